@@ -0,0 +1,32 @@
+use heimdallr::*;
+use std::env;
+use std::time::Instant;
+
+
+// Measures the latency of the daemon barrier, e.g. to compare daemon versions
+// at high rank counts. Takes the number of barriers as argument.
+fn main()
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+
+    let iterations: u32 = match client.cmd_args.first()
+    {
+        Some(arg) => arg.parse().expect("Number of barriers has to be an integer"),
+        None => 10000,
+    };
+
+    client.barrier().unwrap();
+    let now = Instant::now();
+
+    for _ in 0..iterations
+    {
+        client.barrier().unwrap();
+    }
+
+    let secs = now.elapsed().as_secs_f64();
+    if let Some(max) = client.reduce(secs, ReduceOp::Max, 0).unwrap()
+    {
+        println!("{} barriers over {} ranks: total {:.6} s, {:.2} us per barrier", iterations,
+            client.size, max, max / iterations.max(1) as f64 * 1e6);
+    }
+}
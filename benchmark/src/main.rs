@@ -5,7 +5,7 @@ use std::time::Instant;
 
 fn main()
 {
-    let mut client = HeimdallrClient::init(env::args()).unwrap();
+    let mut client = HeimdallrClient::init(env::args()).unwrap().with_stats();
 
     let buff_size: u32 = client.cmd_args[0].parse().unwrap();
     let iterations: u32 = client.cmd_args[1].parse().unwrap();
@@ -37,7 +37,16 @@ fn main()
 
     client.barrier().unwrap();
     let duration = now.elapsed();
-
-    println!("Total runtime: {:.6}", duration.as_secs_f64());
-    
+    client.print_stats().unwrap();
+
+    // One summary over all ranks on rank 0 instead of a runtime per rank
+    let secs = duration.as_secs_f64();
+    let max = client.reduce(secs, ReduceOp::Max, 0).unwrap();
+    let min = client.reduce(secs, ReduceOp::Min, 0).unwrap();
+    let sum = client.reduce(secs, ReduceOp::Sum, 0).unwrap();
+    if let (Some(max), Some(min), Some(sum)) = (max, min, sum)
+    {
+        println!("Total runtime over {} ranks: max {:.6} min {:.6} avg {:.6}", client.size, max, min,
+            sum / client.size as f64);
+    }
 }
@@ -2,7 +2,8 @@
 use std::env;
 use std::time::Instant;
 
-use heimdallr::HeimdallrClient;
+use heimdallr::{HeimdallrClient, ClientArgs, Status, ANY_SOURCE, ANY_TAG, DaemonConfig, ReduceOp, TypeTag, Layout, Window, mpi};
+use heimdallr::erased_serde;
 
 use gethostname::gethostname;
 
@@ -52,21 +53,20 @@ fn _big_vec_send_rec() -> std::io::Result<()>
         0 =>
         {
             let mut buf = Vec::<i64>::new();
-            for i in 0..40000000 as i64
+            for i in 0..40000000_i64
             // for i in 0..100000 as i64
             {
                 buf.push(i);
             }
             println!("Client 0: done creating vec");
             println!("Client 0: starting send");
-            client.send(&mut buf, 1, 0).unwrap();
+            client.send(&buf, 1, 0).unwrap();
             println!("Client 0: done sending");
         },
         1 => 
         {
-            let buf: Vec::<i64>;
             println!("Client 1: start receiving");
-            buf = client.receive(0,0).unwrap();
+            let buf: Vec::<i64> = client.receive(0,0).unwrap();
             println!("Client 1: done receiving");
             println!("{:?}", buf[42]);
         },
@@ -76,6 +76,349 @@ fn _big_vec_send_rec() -> std::io::Result<()>
     Ok(())
 }
 
+// Same as _big_vec_send_rec, but only 1M elements are serialized at a time
+fn _chunked_send_rec_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    match client.id
+    {
+        0 =>
+        {
+            let buf: Vec<i64> = (0..40000000_i64).collect();
+            let mut progress = |sent: usize, total: usize|
+                println!("Client 0: sent {} of {} elements", sent, total);
+            client.send_chunked(&buf, 1, 0, 1 << 20, Some(&mut progress))?;
+        },
+        1 =>
+        {
+            let buf: Vec<i64> = client.receive_chunked(0, 0, None)?;
+            assert_eq!(buf.len(), 40000000);
+            assert_eq!(buf[42], 42);
+            println!("Client 1: received {} elements", buf.len());
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+// Clients exchange chunked messages under the same id with their partner and
+// themselves, the credits of the transfers do not mix with their chunks
+fn _chunked_exchange_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+    let partner = client.id ^ 1;
+    let buf: Vec<u64> = (0..1000).map(|i| i + 1000 * client.id as u64).collect();
+
+    if partner < client.size
+    {
+        let received: Vec<u64> = std::thread::scope(|s|
+        {
+            let sender = s.spawn(|| client.send_chunked(&buf, partner, 5, 10, None));
+            let received = client.receive_chunked(partner, 5, None);
+            sender.join().expect("Error in joining chunked send thread")?;
+            received
+        })?;
+        assert_eq!(received, (0..1000).map(|i| i + 1000 * partner as u64).collect::<Vec<_>>());
+    }
+
+    client.send_chunked(&buf, client.id, 5, 10, None)?;
+    let own: Vec<u64> = client.receive_chunked(client.id, 5, None)?;
+    assert_eq!(own, buf);
+    println!("Client {}: chunked exchange done", client.id);
+
+    Ok(())
+}
+
+// A chunked message with more elements than announced fails its receive,
+// without leaving its remaining chunks behind for the next receive of the id
+fn _chunked_overshoot_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    match client.id
+    {
+        0 =>
+        {
+            client.send(&(2u64, 2u64), 1, 0)?;
+            client.send_slice(&[1i64, 2, 3], 1, 0)?;
+            client.send_slice(&[4i64], 1, 0)?;
+            client.send(&7u32, 1, 0)?;
+        },
+        1 =>
+        {
+            let e = client.receive_chunked::<i64>(0, 0, None).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+            let after: u32 = client.receive(0, 0)?;
+            assert_eq!(after, 7);
+            println!("Client 1: chunked overshoot was rejected");
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+// Same as _big_vec_send_rec, but the vec is sent as raw bytes without bincode
+fn _send_bytes_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    match client.id
+    {
+        0 =>
+        {
+            let buf: Vec<u8> = (0..40000000_i64).flat_map(|i| i.to_ne_bytes()).collect();
+            let start = Instant::now();
+            client.send_bytes(&buf, 1, 0)?;
+            println!("Client 0: sent {} bytes in {:?}", buf.len(), start.elapsed());
+        },
+        1 =>
+        {
+            let bytes = client.receive_bytes(0, 0)?;
+            let buf: Vec<i64> = bytes.chunks_exact(8)
+                .map(|c|
+                {
+                    let mut value = [0u8; 8];
+                    value.copy_from_slice(c);
+                    i64::from_ne_bytes(value)
+                })
+                .collect();
+            println!("Client 1: received {} values", buf.len());
+            assert_eq!(buf.len(), 40000000);
+            assert_eq!(buf[4664], 4664);
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+// Client 0 sends a large redundant buffer compressed and a small message uncompressed,
+// client 1 receives both without enabling compression itself
+fn _compression_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    match client.id
+    {
+        0 =>
+        {
+            let client = client.with_compression(6);
+            let start = Instant::now();
+            client.send(&vec![b'A'; 40000000], 1, 0)?;
+            client.send(&42_u32, 1, 1)?;
+            println!("Client 0: sent in {:?}", start.elapsed());
+        },
+        1 =>
+        {
+            let buf: Vec<u8> = client.receive(0, 0)?;
+            assert_eq!(buf.len(), 40000000);
+            assert!(buf.iter().all(|&b| b == b'A'));
+            assert_eq!(client.receive::<u32>(0, 1)?, 42);
+            println!("Client 1: received compressed buffer");
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+// Small messages with the default nodelay streams and a bulk send with
+// larger socket buffers between client 0 and 1
+fn _socket_options_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap()
+        .with_socket_buffers(Some(4 << 20), Some(4 << 20));
+    assert!(client.socket_options().nodelay);
+
+    match client.id
+    {
+        0 =>
+        {
+            let start = Instant::now();
+            for i in 0..1000_u32
+            {
+                client.send(&i, 1, 0)?;
+                assert_eq!(client.receive::<u32>(1, 0)?, i);
+            }
+            println!("Client 0: 1000 round trips in {:?}", start.elapsed());
+            client.send_bulk(&vec![7_u8; 16000000], 1, 1)?;
+            client.send(&1_u32, 1, 2)?;
+        },
+        1 =>
+        {
+            for _ in 0..1000
+            {
+                let i: u32 = client.receive(0, 0)?;
+                client.send(&i, 0, 0)?;
+            }
+            let buf: Vec<u8> = client.receive(0, 1)?;
+            assert_eq!(buf.len(), 16000000);
+            assert_eq!(client.receive::<u32>(0, 2)?, 1);
+            println!("Client 1: received bulk buffer");
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+// Sends to itself never touch the network and work on a job of size 1
+fn _self_send_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    let x = vec![1_u64, 2, 3];
+    client.send(&x, client.id, 0)?;
+    client.send_slice(&x[1..], client.id, 0)?;
+    assert_eq!(client.receive::<Vec<u64>>(client.id, 0)?, x);
+    assert_eq!(client.receive::<Vec<u64>>(client.id, 0)?, vec![2, 3]);
+
+    let handle = client.send_nb(42_u32, client.id, 1)?;
+    assert_eq!(client.receive::<u32>(client.id, 1)?, 42);
+    handle.data()?;
+    println!("Client {}: received its own messages", client.id);
+
+    Ok(())
+}
+
+// A library communicator created with dup uses the same tag as the application
+// on world without any of them receiving the message of the other
+fn _communicator_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+    let world = client.world();
+    let lib = world.dup()?;
+    let next = (world.rank + 1) % world.size;
+    let prev = (world.rank + world.size - 1) % world.size;
+
+    lib.send(&(world.rank, "library"), next, 0)?;
+    world.send(&(world.rank, "application"), next, 0)?;
+    assert_eq!(world.receive::<(u32, String)>(prev, 0)?, (prev, String::from("application")));
+    assert_eq!(lib.receive::<(u32, String)>(prev, 0)?, (prev, String::from("library")));
+    assert!(lib.send(&0, next, heimdallr::communicator::COMM_TAG_LIMIT).is_err());
+    println!("Client {}: communicators kept their messages apart", world.rank);
+
+    Ok(())
+}
+
+// Splits the clients into even and odd ids in reverse order, the collectives
+// of each half only involve its own members
+fn _split_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+    let world = client.world();
+    let half = world.split(world.rank % 2, world.size - world.rank)?;
+
+    let members = half.allgather(&world.rank)?;
+    assert!(members.iter().all(|r| r % 2 == world.rank % 2));
+    assert!(members.windows(2).all(|w| w[0] > w[1]));
+    assert_eq!(members[half.rank as usize], world.rank);
+
+    let mut root_rank = world.rank;
+    half.broadcast(&mut root_rank, 0)?;
+    assert_eq!(root_rank, members[0]);
+    half.barrier()?;
+    println!("Client {}: rank {} of {} in half {}", world.rank, half.rank, half.size,
+        world.rank % 2);
+
+    Ok(())
+}
+
+// Arranges the clients in a periodic ring and a 2D grid without periodic boundaries,
+// every client passes its id to the next one of the ring
+fn _cart_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+    let world = client.world();
+
+    let ring = world.cart(&[world.size], &[true])?;
+    let (source, dest) = ring.shift(0, 1);
+    ring.comm.send(&world.rank, dest.unwrap(), 0)?;
+    assert_eq!(ring.comm.receive::<u32>(source.unwrap(), 0)?, source.unwrap());
+
+    let dims = heimdallr::communicator::dims_create(world.size, 2);
+    let grid = world.cart(&dims, &[false, false])?;
+    let coords = grid.coords(grid.rank());
+    let (up, down) = grid.shift(0, 1);
+    assert_eq!(up.is_none(), coords[0] == 0);
+    assert_eq!(down.is_none(), coords[0] == dims[0] - 1);
+    println!("Client {}: {:?} in grid {:?}, neighbours {:?} {:?}", world.rank, coords, dims,
+        up, down);
+
+    Ok(())
+}
+
+struct _JsonSerializer;
+
+impl heimdallr::Serializer for _JsonSerializer
+{
+    fn serialize(&self, data: &dyn erased_serde::Serialize) -> std::io::Result<Vec<u8>>
+    {
+        Ok(serde_json::to_vec(data)?)
+    }
+
+    fn deserialize<'de>(&self, data: &'de [u8], _limit: u64,
+        visit: &mut dyn FnMut(&mut dyn erased_serde::Deserializer<'de>)
+            -> Result<(), erased_serde::Error>) -> std::io::Result<()>
+    {
+        let mut de = serde_json::Deserializer::from_slice(data);
+        visit(&mut <dyn erased_serde::Deserializer>::erase(&mut de))
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
+
+// All clients send and receive JSON instead of bincode
+fn _serializer_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+    client.serializer = std::sync::Arc::new(_JsonSerializer);
+
+    match client.id
+    {
+        0 =>
+        {
+            client.send(&vec![1_u32, 2, 3], 1, 0)?;
+            client.send_slice(&[4_u32, 5], 1, 1)?;
+        },
+        1 =>
+        {
+            let raw = client.receive_bytes(0, 0)?;
+            println!("Client 1 received {}", String::from_utf8_lossy(&raw));
+            assert_eq!(raw, b"[1,2,3]");
+            let data: Vec<u32> = client.receive(0, 1)?;
+            assert_eq!(data, vec![4, 5]);
+        },
+        _ => (),
+    }
+
+    // JSON values can not be decoded by bincode, so this also fails if the root
+    // of a collective took its own contribution through another format
+    let value = serde_json::json!({"client": client.id});
+    let expected: Vec<_> = (0..client.size).map(|i| serde_json::json!({"client": i})).collect();
+    if let Some(all) = client.gather(&value, 0)?
+    {
+        assert_eq!(all, expected);
+    }
+    if let Some(all) = client.gatherv(std::slice::from_ref(&value), 0)?
+    {
+        assert_eq!(all, expected);
+    }
+    if let Some(all) = client.gather_tagged(&value, TypeTag::new("json"), 0)?
+    {
+        for (id, _, data) in all
+        {
+            assert_eq!(client.decode_tagged::<serde_json::Value>(&data)?, expected[id as usize]);
+        }
+    }
+    let own: serde_json::Value = client.scatter((client.id == 0).then_some(&expected[..]), 0)?;
+    assert_eq!(own, value);
+
+    Ok(())
+}
+
 fn _nb_test() -> std::io::Result<()>
 {
     let client = HeimdallrClient::init(env::args()).unwrap();
@@ -85,7 +428,7 @@ fn _nb_test() -> std::io::Result<()>
         0 =>
         {
             let mut buf = Vec::<i64>::new();
-            for i in 0..20000000 as i64
+            for i in 0..20000000_i64
             // for i in 0..100000 as i64
             {
                 buf.push(i);
@@ -101,13 +444,12 @@ fn _nb_test() -> std::io::Result<()>
         },
         1 => 
         {
-            let buf: Vec::<i64>;
             println!("receive_nb call");
             let a_recv = client.receive_nb::<Vec::<i64>>(0,0).unwrap();
             println!("receive_nb call done");
             let s: String = client.receive(0,1).unwrap();
             println!("{}",s);
-            buf = a_recv.data()?;
+            let buf: Vec::<i64> = a_recv.data()?;
             println!("got buf data ownership");
             println!("{}", buf[4664]);
         },
@@ -117,6 +459,95 @@ fn _nb_test() -> std::io::Result<()>
     Ok(())
 }
 
+// Thousands of overlapping non-blocking operations run on the worker pool
+// instead of starting a thread each
+fn _nb_pool_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap().with_nb_workers(2);
+    let count = 5000_u32;
+
+    match client.id
+    {
+        0 =>
+        {
+            let handles: Vec<_> = (0..count).map(|i| client.send_nb(i, 1, i))
+                .collect::<std::io::Result<_>>()?;
+            for data in heimdallr::wait_all(handles)
+            {
+                data?;
+            }
+        },
+        1 =>
+        {
+            // Posted in reverse, so most messages arrive before their receive is taken
+            let handles: Vec<_> = (0..count).rev().map(|i| client.receive_nb::<u32>(0, i))
+                .collect::<std::io::Result<_>>()?;
+            for (data, i) in heimdallr::wait_all(handles).into_iter().zip((0..count).rev())
+            {
+                assert_eq!(data?, i);
+            }
+            println!("Client 1 received {} messages", count);
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+// Minimal executor for _future_test, parks the thread until the future wakes it
+struct _ThreadWaker(std::thread::Thread);
+
+impl std::task::Wake for _ThreadWaker
+{
+    fn wake(self: std::sync::Arc<Self>)
+    {
+        self.0.unpark();
+    }
+}
+
+fn _block_on<F: std::future::Future>(future: F) -> F::Output
+{
+    let mut future = std::pin::pin!(future);
+    let waker = std::sync::Arc::new(_ThreadWaker(std::thread::current())).into();
+    let mut cx = std::task::Context::from_waker(&waker);
+    loop
+    {
+        if let std::task::Poll::Ready(data) = future.as_mut().poll(&mut cx)
+        {
+            return data;
+        }
+        std::thread::park();
+    }
+}
+
+// Awaits non-blocking operations instead of joining them with data()
+fn _future_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    match client.id
+    {
+        0 =>
+        {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            let buf = _block_on(client.send_nb(vec![1_u64, 2, 3], 1, 0)?)?;
+            println!("Client 0 got its buffer back: {:?}", buf);
+        },
+        1 =>
+        {
+            let received = _block_on(async
+            {
+                client.receive_nb::<Vec<u64>>(0, 0)?.await
+            })?;
+            println!("Client 1 awaited {:?}", received);
+            assert_eq!(received, vec![1, 2, 3]);
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
 fn _gather_test() -> std::io::Result<()>
 {
     let client = HeimdallrClient::init(env::args()).unwrap();
@@ -166,7 +597,7 @@ fn _mutex_test() -> std::io::Result<()>
 {
     let mut client = HeimdallrClient::init(env::args()).unwrap();
 
-    let mut mutex = client.create_mutex("testmutex", 0 as u64)?;
+    let mut mutex = client.create_mutex("testmutex", 0_u64)?;
 
     {
         let mut m = mutex.lock().unwrap();
@@ -191,7 +622,7 @@ fn _mutex_test() -> std::io::Result<()>
 fn _mutex_test2() -> std::io::Result<()>
 {
     let mut client = HeimdallrClient::init(env::args()).unwrap();
-    let mut mutex = client.create_mutex("testmutex", 0 as u64)?;
+    let mut mutex = client.create_mutex("testmutex", 0_u64)?;
 
     for _ in 0..25000
     {
@@ -205,64 +636,1883 @@ fn _mutex_test2() -> std::io::Result<()>
     Ok(())
 }
 
-fn _receive_any_source_test() -> std::io::Result<()>
+// Same counter as _mutex_test2 with one packet per increment instead of a lock,
+// read and write
+fn _fetch_add_test() -> std::io::Result<()>
 {
-    let client = HeimdallrClient::init(env::args()).unwrap();
-
-    let mut buf: String;
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
 
-    match client.id
+    let start = Instant::now();
+    for _ in 0..25000
     {
-        0 => 
-        {
-            for i in 1..client.size
-            {
-                buf = client.receive_any_source(i).unwrap();
-                println!("{}",buf);
-            }
-        },
-        _ =>
-        {
-            buf = format!("Message from process {}", client.id);
-            client.send(&buf, 0, client.id).unwrap();
-        },
+        client.fetch_add("testcounter", 1)?;
     }
+    client.barrier()?;
 
+    let total = client.fetch_add("testcounter", 0)?;
+    assert_eq!(total, 25000 * client.size as i64);
+    println!("Client {}: counter {} after {:?}", client.id, total, start.elapsed());
 
     Ok(())
 }
 
-
-fn _barrier_test() -> std::io::Result<()>
+// One-shot election: exactly one client swaps the leader from -1 to its own id,
+// then every client increments a spinlock-protected counter built on compare_and_swap
+fn _compare_and_swap_test() -> std::io::Result<()>
 {
     let mut client = HeimdallrClient::init(env::args()).unwrap();
+    let id = client.id as i64;
 
-    match client.id
+    client.compare_and_swap("leader", 0, -1)?;
+    client.barrier()?;
+    let observed = client.compare_and_swap("leader", -1, id)?;
+    client.barrier()?;
+    let leader = client.fetch_add("leader", 0)?;
+    assert_eq!(observed == -1, leader == id);
+
+    for _ in 0..100
     {
-        0 => 
-        {
-            _wait(1);
-            client.barrier().unwrap();
-            println!("BARRIER DONE");
-        },
-        1 =>
-        {
-            _wait(2);
-            client.barrier().unwrap();
-            println!("BARRIER DONE");
-        },
-        2 =>
-        {
-            _wait(4);
-            client.barrier().unwrap();
-            println!("BARRIER DONE");
-        },
+        while client.compare_and_swap("spinlock", 0, 1)? != 0 {}
+        let count = client.fetch_add("count", 0)?;
+        client.compare_and_swap("count", count, count + 1)?;
+        client.compare_and_swap("spinlock", 1, 0)?;
+    }
+    client.barrier()?;
+    assert_eq!(client.fetch_add("count", 0)?, 100 * client.size as i64);
+    println!("Client {}: leader is {}", client.id, leader);
+
+    Ok(())
+}
+
+// Ring exchange with persistent requests, the send buffer is given back by wait
+// and reused for the next iteration
+fn _persistent_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+    let right = (client.id + 1) % client.size;
+    let left = (client.id + client.size - 1) % client.size;
+
+    let mut send = client.send_init::<Vec<u64>>(right, 0)?;
+    let mut receive = client.recv_init::<Vec<u64>>(left, 0)?;
+    let mut buf = vec![client.id as u64; 1000];
+    let start = Instant::now();
+    for i in 0..100
+    {
+        receive.start()?;
+        send.start(buf)?;
+        let halo = receive.wait()?;
+        assert!(halo.iter().all(|&v| v == left as u64 + i));
+        buf = send.wait()?;
+        buf.iter_mut().for_each(|v| *v += 1);
+    }
+    assert!(send.wait().is_err());
+    println!("Client {}: 100 exchanges in {:?}", client.id, start.elapsed());
+
+    Ok(())
+}
+
+// Every client puts its rank into its own section of the windows of all clients,
+// after the fence each window holds the sections of everyone
+fn _window_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+    let size = client.size as usize;
+
+    let window = Window::create(&client, vec![0_u32; 2 * size])?;
+    let rank = window.comm().rank;
+    for target in 0..client.size
+    {
+        window.put(&[rank, rank], target, 2 * rank as usize)?;
+    }
+    window.fence()?;
+
+    let expected: Vec<u32> = (0..2 * size).map(|i| (i / 2) as u32).collect();
+    assert_eq!(*window.local(), expected);
+    let right = (rank + 1) % client.size;
+    assert_eq!(window.get(right, 1, 2)?, expected[1..3].to_vec());
+    assert!(window.get(right, 2 * size, 1).is_err());
+
+    let local = window.into_inner()?;
+    println!("Client {}: window {:?}", client.id, local);
+
+    Ok(())
+}
+
+// Client 0 sends rows to client 1, which receives them into the same buffers
+// every time. A row of the wrong length is rejected.
+fn _receive_into_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    match client.id
+    {
+        0 =>
+        {
+            for i in 0..10
+            {
+                client.send_slice(&vec![i as f64; 100], 1, 0)?;
+                client.send_bytes(&[i as u8; 64], 1, 1)?;
+            }
+            client.send_slice(&[0.0_f64; 99], 1, 0)?;
+        },
+        1 =>
+        {
+            let mut row = vec![0.0_f64; 100];
+            let mut bytes = Vec::with_capacity(64);
+            for i in 0..10
+            {
+                client.receive_slice_into(0, 0, &mut row)?;
+                client.receive_into(0, 1, &mut bytes)?;
+                assert!(row.iter().all(|&v| v == i as f64));
+                assert_eq!(bytes, vec![i as u8; 64]);
+            }
+            let err = client.receive_slice_into(0, 0, &mut row).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            println!("Short row rejected: {}", err);
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+// Every client sends one message of 1000 bytes to its right neighbour and receives
+// one from its left neighbour, then the statistics are printed on client 0
+fn _stats_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap().with_stats();
+    let right = (client.id + 1) % client.size;
+    let left = (client.id + client.size - 1) % client.size;
+
+    client.send_bytes(&[0_u8; 1000], right, 0)?;
+    let msg = client.receive_bytes(left, 0)?;
+    assert_eq!(msg.len(), 1000);
+
+    let stats = client.stats().unwrap();
+    assert_eq!((stats.messages_sent, stats.bytes_sent), (1, 1000));
+    assert_eq!((stats.messages_received, stats.bytes_received), (1, 1000));
+
+    client.print_stats()?;
+    client.barrier()?;
+
+    Ok(())
+}
+
+// Run with the daemon started with --mutex-policy fair and at least 3 clients.
+// Client 0 holds the lock while the others request it in reverse rank order, then
+// re-locks right away. Fair grants in rank order after client 0 and only gives it the
+// lock again once all others had it, FIFO would grant in request order instead.
+fn _fair_mutex_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+    let mut mutex = client.create_mutex("fairmutex", Vec::<u32>::new())?;
+
+    match client.id
+    {
+        0 =>
+        {
+            let mut m = mutex.lock()?;
+            client.barrier()?;
+            std::thread::sleep(std::time::Duration::from_millis(200 * client.size as u64));
+            let mut grants = m.get().clone();
+            grants.push(0);
+            m.set(grants);
+            drop(m);
+            for _ in 0..3
+            {
+                let mut m = mutex.lock()?;
+                let mut grants = m.get().clone();
+                grants.push(0);
+                m.set(grants);
+            }
+        },
+        _ =>
+        {
+            client.barrier()?;
+            std::thread::sleep(std::time::Duration::from_millis(100 * (client.size - client.id) as u64));
+            let mut m = mutex.lock()?;
+            let mut grants = m.get().clone();
+            grants.push(client.id);
+            m.set(grants);
+        },
+    }
+
+    client.barrier()?;
+    if client.id == 0
+    {
+        let m = mutex.lock()?;
+        println!("Grant order: {:?}", m.get());
+        let expected: Vec<u32> = (0..client.size).collect();
+        assert_eq!(&m.get()[..client.size as usize], &expected[..],
+            "Lock was not granted round-robin");
+    }
+
+    Ok(())
+}
+
+// Run with 2 clients, both lock the two mutexes in opposite order.
+// The daemon should fail one of the second locks instead of hanging.
+fn _mutex_deadlock_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+    let mut mutex_a = client.create_mutex("deadlock_a", 0_u64)?;
+    let mut mutex_b = client.create_mutex("deadlock_b", 0_u64)?;
+
+    let (first, second) = match client.id
+    {
+        0 => (&mut mutex_a, &mut mutex_b),
+        _ => (&mut mutex_b, &mut mutex_a),
+    };
+
+    let mut m = first.lock()?;
+    client.barrier()?;
+    let failed = match second.lock()
+    {
+        Ok(mut m2) =>
+        {
+            m2.set(m2.get()+1);
+            println!("Client {} got both locks", client.id);
+            false
+        },
+        Err(e) =>
+        {
+            println!("Client {} got expected error: {}", client.id, e);
+            true
+        },
+    };
+    m.set(m.get()+1);
+    drop(m);
+
+    // The first lock has to be released before, the other client may still wait for it
+    let all = client.allgather(&failed)?;
+    assert_eq!(all.iter().filter(|&&f| f).count(), 1, "Lock results: {:?}", all);
+
+    Ok(())
+}
+
+fn _mutex_owner_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+    let mut mutex = client.create_mutex("ownermutex", 0_u64)?;
+
+    assert_eq!(mutex.current_owner()?, None);
+    client.barrier()?;
+
+    if client.id == 0
+    {
+        let _m = mutex.lock()?;
+        client.barrier()?;
+        client.barrier()?;
+    }
+    else
+    {
+        client.barrier()?;
+        println!("Client {} sees owner {:?}", client.id, mutex.current_owner()?);
+        assert_eq!(mutex.current_owner()?, Some(0));
+        client.barrier()?;
+    }
+
+    Ok(())
+}
+
+// Run with more than 2 clients, at most 2 of them may hold the semaphore at once
+fn _semaphore_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+    let mut semaphore = client.create_semaphore("testsemaphore", 2)?;
+
+    for _ in 0..5
+    {
+        semaphore.acquire()?;
+        let holders = client.fetch_add("semaphore_holders", 1)?;
+        assert!(holders < semaphore.permits() as i64, "{} clients hold the semaphore", holders + 1);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        client.fetch_add("semaphore_holders", -1)?;
+        semaphore.release()?;
+    }
+    println!("Client {} done", client.id);
+
+    Ok(())
+}
+
+// Run with 2 clients. The large non-blocking sends are written by worker threads
+// while the small sends open another stream, so the messages arrive out of order
+// but have to be received in the order of the send calls.
+fn _ordered_delivery_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    match client.id
+    {
+        0 =>
+        {
+            let mut handles = Vec::new();
+            for i in 0..60_u32
+            {
+                match i % 3
+                {
+                    0 => handles.push(client.send_nb(vec![i; 1 << 20], 1, 3)?),
+                    _ => client.send(&vec![i], 1, 3)?,
+                }
+            }
+            for handle in handles
+            {
+                handle.data()?;
+            }
+        },
+        1 =>
+        {
+            for i in 0..60_u32
+            {
+                let v: Vec<u32> = client.receive(0, 3)?;
+                assert_eq!(v[0], i, "Message {} was received out of order", v[0]);
+            }
+            println!("All messages received in order");
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+// A message that fails to send only fails its own receive, later ones still arrive
+fn _lost_message_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+    client.serializer = std::sync::Arc::new(_JsonSerializer);
+
+    match client.id
+    {
+        0 =>
+        {
+            // JSON has no maps with tuple keys
+            let unsendable: std::collections::HashMap<(u32,u32),u32> = [((1, 2), 3)].into();
+            assert!(client.send_nb(unsendable, 1, 4)?.data().is_err());
+            client.send(&7_u32, 1, 4)?;
+        },
+        1 =>
+        {
+            let lost = client.receive::<u32>(0, 4).unwrap_err();
+            assert_eq!(lost.kind(), std::io::ErrorKind::ConnectionAborted);
+            let next: u32 = client.receive(0, 4)?;
+            assert_eq!(next, 7);
+            println!("Lost message failed its receive only");
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+fn _versioned_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+
+    match client.id
+    {
+        0 => client.send_versioned(&42_u64, 1, 1, 0)?,
+        1 =>
+        {
+            match client.receive_versioned::<u64>(0, 0, 2)
+            {
+                Ok(v) => panic!("Expected version mismatch, received {}", v),
+                Err(e) =>
+                {
+                    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+                    assert!(e.to_string().contains("schema version mismatch (sent v1, expected v2)"),
+                        "Unexpected error: {}", e);
+                    println!("Got expected error: {}", e);
+                },
+            }
+        },
+        _ => (),
+    }
+
+    // The version goes through the serializer of the client like the data
+    client.serializer = std::sync::Arc::new(_JsonSerializer);
+    match client.id
+    {
+        0 => client.send_versioned(&vec![1_u64, 2], 2, 1, 0)?,
+        1 => assert_eq!(client.receive_versioned::<Vec<u64>>(0, 0, 2)?, vec![1, 2]),
+        _ => (),
+    }
+
+    Ok(())
+}
+
+fn _receive_any_source_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    let mut buf: String;
+
+    match client.id
+    {
+        0 => 
+        {
+            for i in 1..client.size
+            {
+                buf = client.receive_any_source(i).unwrap();
+                println!("{}",buf);
+            }
+        },
         _ =>
         {
+            buf = format!("Message from process {}", client.id);
+            client.send(&buf, 0, client.id).unwrap();
+        },
+    }
+
+
+    Ok(())
+}
+
+
+// Client 0 receives the messages of client 1 in order regardless of their tags,
+// then one message from any client
+fn _receive_any_tag_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+
+    match client.id
+    {
+        0 =>
+        {
+            for expected in &[7, 3, 5]
+            {
+                let (tag, data): (u32, u32) = client.receive_any_tag(1)?;
+                assert_eq!((tag, data), (*expected, expected * 10));
+            }
+            let (source, tag, data): (u32, u32, String) = client.receive_any(ANY_SOURCE, ANY_TAG)?;
+            println!("Client 0 received {:?} from {} with tag {}", data, source, tag);
+            assert_eq!((source, tag), (1, 9));
+        },
+        1 =>
+        {
+            for tag in &[7, 3, 5]
+            {
+                client.send(&(tag * 10), 0, *tag)?;
+            }
+            client.send(&"last".to_string(), 0, 9)?;
+        },
+        _ => (),
+    }
+    client.barrier()?;
+
+    Ok(())
+}
+
+
+// Client i sends i elements to client 0, which does not know the lengths upfront
+fn _receive_status_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+
+    if client.id == 0
+    {
+        let mut sources = Vec::new();
+        for _ in 1..client.size
+        {
+            let (data, status): (Vec<u64>, Status) = client.receive_status(ANY_SOURCE, 4)?;
+            println!("Client 0 received {} elements from {}", status.count, status.source);
+            assert_eq!(status, Status{source: status.source, tag: 4, count: status.source as usize});
+            assert_eq!(data, vec![status.source as u64; status.count]);
+            sources.push(status.source);
+        }
+        sources.sort_unstable();
+        assert_eq!(sources, (1..client.size).collect::<Vec<u32>>());
+    }
+    else
+    {
+        client.send_slice(&vec![client.id as u64; client.id as usize], 0, 4)?;
+    }
+    client.barrier()?;
+
+    Ok(())
+}
+
+
+fn _barrier_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+
+    match client.id
+    {
+        0 => 
+        {
+            _wait(1);
+            client.barrier().unwrap();
+            println!("BARRIER DONE");
+        },
+        1 =>
+        {
+            _wait(2);
             client.barrier().unwrap();
             println!("BARRIER DONE");
         },
+        2 =>
+        {
+            _wait(4);
+            client.barrier().unwrap();
+            println!("BARRIER DONE");
+        },
+        _ =>
+        {
+            client.barrier().unwrap();
+            println!("BARRIER DONE");
+        },
+    }
+
+    Ok(())
+}
+
+
+fn _barrier_with_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+
+    // Only client 1 wants to stop, so nobody should continue
+    _wait(client.id as u64);
+    let cont = client.barrier_with(client.id != 1, ReduceOp::Min)?;
+    println!("Client {} continue: {}", client.id, cont);
+    assert!(!cont);
+
+    let sum = client.barrier_with(client.id, ReduceOp::Sum)?;
+    assert_eq!(sum, (0..client.size).sum::<u32>());
+
+    let max = client.barrier_allreduce(client.id as f64 * 0.5, ReduceOp::Max)?;
+    assert_eq!(max, (client.size - 1) as f64 * 0.5);
+
+    let done = client.iall_done(client.id != 1)?;
+    assert!(!done.data()?);
+    let min = client.ibarrier_with(client.id + 3, ReduceOp::Min)?;
+    assert_eq!(min.data()?, 3);
+
+    Ok(())
+}
+
+
+// Client i is done after i+1 iterations, so all_done has to
+// return false until the last client got there
+fn _all_done_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+
+    let mut iteration = 0;
+    loop
+    {
+        iteration += 1;
+        let done = client.all_done(iteration > client.id)?;
+        if done
+        {
+            break;
+        }
+        assert!(iteration < client.size);
+    }
+    println!("Client {} done after {} iterations", client.id, iteration);
+    assert_eq!(iteration, client.size);
+
+    Ok(())
+}
+
+
+// Start fewer clients than given with --jobs, the daemon has to abort the
+// job after its registration timeout instead of hanging
+fn _registration_timeout_test() -> std::io::Result<()>
+{
+    match HeimdallrClient::init(env::args())
+    {
+        Ok(_) => panic!("Registration of an incomplete job succeeded"),
+        Err(e) => println!("Got expected error: {}", e),
+    }
+
+    Ok(())
+}
+
+
+// Arguments are validated without a daemon, only complete ones reach init_with
+fn _client_args_test() -> std::io::Result<()>
+{
+    let to_args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<String>>();
+
+    let args = ClientArgs::parse(to_args(&["job", "-p", "part", "-n", "node", "-j", "4",
+        "--args", "a", "b"])).unwrap();
+    assert_eq!(args.size, 4);
+    assert_eq!(args.cmd_args, vec!["a".to_string(), "b".to_string()]);
+
+    assert!(ClientArgs::parse(to_args(&["job", "-p", "part", "-n", "node"])).is_err());
+    assert!(ClientArgs::parse(to_args(&["job", "-p", "part", "-n", "node", "-j", "x"])).is_err());
+    assert!(ClientArgs::parse(to_args(&["job", "-p", "part", "-n", "node", "-j", "4",
+        "--interface", "no-such-interface"])).is_err());
+
+    let client = HeimdallrClient::init_with(ClientArgs::parse(env::args()).unwrap()).unwrap();
+    println!("Client {} initialized from parsed arguments", client.id);
+
+    Ok(())
+}
+
+
+// Large messages are striped over the loopback interface as additional rail,
+// smaller ones sent after them still have to arrive in order
+fn _rails_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap().with_rails(&["lo"])?
+        .with_stripe_threshold(1024);
+    let other = (client.id + 1) % client.size;
+    let from = (client.id + client.size - 1) % client.size;
+
+    let data: Vec<u64> = (0..1_000_000).map(|i| i + client.id as u64).collect();
+    client.send_slice(&data, other, 0)?;
+    client.send(&client.id, other, 0)?;
+
+    let received: Vec<u64> = client.receive(from, 0)?;
+    let expected: Vec<u64> = (0..1_000_000).map(|i| i + from as u64).collect();
+    assert_eq!(received, expected);
+    assert_eq!(client.receive::<u32>(from, 0)?, from);
+    println!("Client {} received {} striped elements", client.id, received.len());
+
+    Ok(())
+}
+
+
+fn _bandwidth_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+
+    let matrix = client.measure_bandwidth_matrix()?;
+    println!("Client {} bandwidth matrix (MB/s): {:?}", client.id, matrix);
+
+    assert_eq!(matrix.len(), client.size as usize);
+    for (i, row) in matrix.iter().enumerate()
+    {
+        assert_eq!(row.len(), client.size as usize);
+        for (j, &bw) in row.iter().enumerate()
+        {
+            assert!((i == j) || (bw > 0.0));
+        }
+    }
+
+    Ok(())
+}
+
+
+// Cyclic shift, every client has to receive the id of its predecessor.
+// Needs an even number of clients, since sends block until they are received.
+fn _send_routed_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+    let size = client.size;
+    let pred = (client.id + size - 1) % size;
+
+    let received = match client.id % 2
+    {
+        0 =>
+        {
+            client.send_routed(&client.id, |r| (r+1) % size, 0)?;
+            client.receive::<u32>(pred, 0)?
+        },
+        _ =>
+        {
+            let r = client.receive::<u32>(pred, 0)?;
+            client.send_routed(&client.id, |r| (r+1) % size, 0)?;
+            r
+        },
+    };
+    println!("Client {} received from {}", client.id, received);
+    assert_eq!(received, pred);
+
+    // Clients outside of the job are rejected by plain sends as well
+    let e = client.send(&client.id, size, 0).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    let e = client.send_routed(&client.id, |r| r + size, 0).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+
+    Ok(())
+}
+
+
+// Run the daemon with --mutex-lease 1.
+// Client 0 never releases its lock, the other clients still get it once the
+// lease expired and see the last released value instead of the one set by client 0.
+fn _mutex_lease_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+    let mut mutex = client.create_mutex("leasemutex", 0_u32)?;
+
+    if client.id == 0
+    {
+        let mut m = mutex.lock()?;
+        m.set(42);
+        client.barrier()?;
+        // Simulate a crashed lock holder that never pushes its data
+        std::mem::forget(m);
+    }
+    else
+    {
+        client.barrier()?;
+        let mut m = mutex.lock()?;
+        println!("Client {} got lock with value {}", client.id, m.get());
+        assert_ne!(*m.get(), 42);
+        m.set(client.id);
+    }
+    client.barrier()?;
+
+    Ok(())
+}
+
+
+// Client 0 waits for three candidates but only the middle one is sent
+fn _receive_first_of_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    match client.id
+    {
+        0 =>
+        {
+            let (i, data) = client.receive_first_of::<String>(&[(1, 10), (1, 11), (1, 12)])?;
+            println!("Received candidate {}: {}", i, data);
+            assert_eq!(i, 1);
+            assert_eq!(data, "middle");
+        },
+        1 => client.send(&"middle".to_string(), 0, 11)?,
+        _ => (),
+    }
+
+    Ok(())
+}
+
+
+fn _mpi_shim_test() -> std::io::Result<()>
+{
+    let mut comm = mpi::mpi_init().unwrap();
+    let rank = mpi::mpi_comm_rank(&comm);
+
+    match rank
+    {
+        0 => mpi::mpi_send(&comm, &vec![1, 2, 3], 1, 5)?,
+        1 =>
+        {
+            let (data, status) = mpi::mpi_recv::<Vec<i32>>(&comm, mpi::ANY_SOURCE, mpi::ANY_TAG)?;
+            println!("Received {:?} with {:?}", data, status);
+            assert_eq!(data, vec![1, 2, 3]);
+            assert_eq!(status, mpi::MpiStatus{source: 0, tag: 5});
+        },
+        _ => (),
+    }
+    mpi::mpi_barrier(&mut comm)?;
+    mpi::mpi_finalize(comm);
+
+    Ok(())
+}
+
+
+// Client 1 dies while client 0 keeps sending large messages, client 0 has to see
+// an error from send instead of being killed by SIGPIPE.
+// Both clients exit without finalizing.
+fn _sigpipe_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    match client.id
+    {
+        0 =>
+        {
+            let data = vec![0_u8; 16 * 1024 * 1024];
+            let err = loop
+            {
+                if let Err(e) = client.send(&data, 1, 0)
+                {
+                    break e;
+                }
+            };
+            println!("Send result: {:?}", err);
+        },
+        1 =>
+        {
+            let start = Instant::now();
+            while start.elapsed() < std::time::Duration::from_secs(1)
+            {
+                client.receive::<Vec<u8>>(0, 0)?;
+            }
+        },
+        _ => (),
+    }
+    std::process::exit(0);
+}
+
+
+// Even clients contribute a u64, odd clients a String
+fn _gather_tagged_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    let res = match client.id % 2
+    {
+        0 => client.gather_tagged(&(client.id as u64), TypeTag::of::<u64>(), 0)?,
+        _ => client.gather_tagged(&format!("client {}", client.id), TypeTag::of::<String>(), 0)?,
+    };
+
+    if let Some(contributions) = res
+    {
+        for (id, tag, data) in contributions
+        {
+            if tag.is::<u64>()
+            {
+                let v: u64 = client.decode_tagged(&data)?;
+                println!("Client {} sent u64 {}", id, v);
+                assert_eq!(v, id as u64);
+            }
+            else if tag.is::<String>()
+            {
+                let v: String = client.decode_tagged(&data)?;
+                println!("Client {} sent String {}", id, v);
+                assert_eq!(v, format!("client {}", id));
+            }
+            else
+            {
+                panic!("Unknown type tag {:?}", tag);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+// Client 1 reads the u64 as the length prefix of a Vec, so the message claims
+// to be huge. The receive has to stop at the size limit instead of reading
+// and allocating memory for all of it.
+fn _message_size_limit_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+    client.max_message_size = 1024 * 1024;
+
+    match client.id
+    {
+        0 => client.send(&(u64::MAX, vec![0_u8; 2 * 1024 * 1024]), 1, 0)?,
+        1 =>
+        {
+            let res = client.receive::<Vec<u8>>(0, 0);
+            println!("Receive result: {:?}", res);
+            let err = res.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains("size limit"));
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+
+// Run the daemon with --unix-socket <path>, the clients on the same node
+// connect through it and have to complete a barrier as usual
+fn _unix_socket_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+
+    client.barrier()?;
+    println!("Client {} passed barrier", client.id);
+
+    Ok(())
+}
+
+
+// Element i of the global array has value i, after moving from a block to a
+// cyclic layout client r has to hold r, r+size, r+2*size, ...
+fn _redistribute_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+    let (block, cyclic) = (Layout::Block(12), Layout::Cyclic(12));
+
+    let local: Vec<u64> = block.indices(client.id, client.size).iter().map(|&i| i as u64).collect();
+    let result = client.redistribute(local, &block, &cyclic)?;
+    println!("Client {} holds {:?}", client.id, result);
+
+    let expected: Vec<u64> = (client.id as u64..12).step_by(client.size as usize).collect();
+    assert_eq!(result, expected);
+
+    Ok(())
+}
+
+
+fn _broadcast_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    // Use the last client as root to not only test root 0
+    let root = client.size - 1;
+    let mut data = match client.id == root
+    {
+        true => vec![1.5, 2.5, 3.5],
+        false => Vec::<f64>::new(),
+    };
+    client.broadcast(&mut data, root)?;
+    println!("Client {} received {:?}", client.id, data);
+    assert_eq!(data, vec![1.5, 2.5, 3.5]);
+
+    // With more than 4 clients the data is forwarded along a tree,
+    // which has a different shape for every root
+    for root in 0..client.size
+    {
+        let mut data = match client.id == root
+        {
+            true => root,
+            false => u32::MAX,
+        };
+        client.broadcast(&mut data, root)?;
+        assert_eq!(data, root);
+    }
+
+    Ok(())
+}
+
+
+// Only client 0 runs the closure, all clients get its result
+fn _broadcast_from_root_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    let config: (String, Vec<String>) = client.broadcast_from_root(||
+    {
+        assert_eq!(client.id, 0);
+        (format!("config of client {}", client.id), client.cmd_args.clone())
+    })?;
+    println!("Client {} received {:?}", client.id, config);
+    assert_eq!(config.0, "config of client 0");
+
+    Ok(())
+}
+
+
+fn _gather_collective_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    let root = client.size - 1;
+    let res = client.gather(&(client.id as u64 * 10), root)?;
+    match client.id == root
+    {
+        true =>
+        {
+            let all = res.unwrap();
+            println!("Root gathered {:?}", all);
+            assert_eq!(all, (0..client.size as u64).map(|i| i * 10).collect::<Vec<u64>>());
+        },
+        false => assert!(res.is_none()),
+    }
+
+    Ok(())
+}
+
+
+fn _scatter_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    let root = client.size - 1;
+    let data: Vec<String> = (0..client.size).map(|i| format!("element {}", i)).collect();
+
+    // A wrong number of elements has to fail on root instead of panicking
+    if client.id == root
+    {
+        assert!(client.scatter(Some(&data[1..]), root).is_err());
+    }
+
+    let element = match client.id == root
+    {
+        true => client.scatter(Some(&data), root)?,
+        false => client.scatter(None, root)?,
+    };
+    println!("Client {} received {}", client.id, element);
+    assert_eq!(element, format!("element {}", client.id));
+
+    Ok(())
+}
+
+
+fn _gatherv_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    // Client i contributes i elements, client 0 none at all
+    let root = client.size - 1;
+    let data: Vec<u32> = vec![client.id; client.id as usize];
+
+    match client.gatherv(&data, root)?
+    {
+        Some(all) =>
+        {
+            println!("Client {} gathered {:?}", client.id, all);
+            let expected: Vec<u32> = (0..client.size).flat_map(|i| vec![i; i as usize]).collect();
+            assert_eq!(all, expected);
+        },
+        None => assert_ne!(client.id, root),
+    }
+
+    // No client contributes anything
+    let empty: Vec<u32> = Vec::new();
+    if let Some(all) = client.gatherv(&empty, root)?
+    {
+        assert!(all.is_empty());
+    }
+
+    Ok(())
+}
+
+
+// Empty payloads are regular messages, every send completes exactly one receive
+fn _empty_message_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+    let other = (client.id + 1) % client.size;
+    let from = (client.id + client.size - 1) % client.size;
+    let empty: [u32; 0] = [];
+
+    client.send_slice(&empty, other, 0)?;
+    let received: Vec<u32> = client.receive(from, 0)?;
+    assert!(received.is_empty());
+
+    client.send_slice(&empty, other, 1)?;
+    let mut out: [u32; 0] = [];
+    client.receive_slice_into(from, 1, &mut out)?;
+
+    // Serializes to zero bytes with bincode
+    client.send(&(), other, 2)?;
+    client.receive::<()>(from, 2)?;
+
+    client.send_bytes(&[], other, 3)?;
+    assert!(client.receive_bytes(from, 3)?.is_empty());
+
+    println!("Client {} received all empty messages", client.id);
+
+    Ok(())
+}
+
+
+fn _alltoall_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+    assert!(client.size >= 4, "alltoall test needs at least 4 clients");
+
+    // Row i of the matrix is held by client i, afterwards every client holds
+    // its column, so received[j] == send[i] of client j
+    let send: Vec<(u32, u32)> = (0..client.size).map(|j| (client.id, j)).collect();
+    let received = client.alltoall(&send)?;
+    println!("Client {} received {:?}", client.id, received);
+    for (source, element) in (0..client.size).zip(&received)
+    {
+        assert_eq!(*element, (source, client.id));
+    }
+
+    assert!(client.alltoall(&send[1..]).is_err());
+
+    Ok(())
+}
+
+
+fn _allreduce_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+
+    let max = client.allreduce(client.id as f64 * 0.5, ReduceOp::Max)?;
+    assert_eq!(max, (client.size - 1) as f64 * 0.5);
+
+    let sum = client.allreduce(client.id as i64 - 1, ReduceOp::Sum)?;
+    assert_eq!(sum, (0..client.size as i64).map(|i| i - 1).sum::<i64>());
+
+    let prod = client.allreduce(2_u64, ReduceOp::Prod)?;
+    assert_eq!(prod, 2_u64.pow(client.size));
+
+    let min = client.allreduce(client.id as f32, ReduceOp::Min)?;
+    assert_eq!(min, 0.0);
+    println!("Client {} got max {} sum {} prod {} min {}", client.id, max, sum, prod, min);
+
+    Ok(())
+}
+
+
+fn _reduce_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    let root = client.size - 1;
+    let sum = client.reduce(0.1 * client.id as f64, ReduceOp::Sum, root)?;
+    let max = client.reduce(client.id as u16, ReduceOp::Max, root)?;
+    match client.id == root
+    {
+        true =>
+        {
+            // Summed up in rank order
+            let expected = (0..client.size).fold(0.0, |acc, i| acc + 0.1 * i as f64);
+            println!("Root got sum {:?} max {:?}", sum, max);
+            assert_eq!(sum, Some(expected));
+            assert_eq!(max, Some(root as u16));
+        },
+        false => assert!(sum.is_none() & max.is_none()),
+    }
+
+    Ok(())
+}
+
+
+// Client i gets the sum of element i of all clients, a wrong length has to fail
+fn _reduce_scatter_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    if client.size > 1
+    {
+        assert!(client.reduce_scatter(&[1_u64], ReduceOp::Sum).is_err());
+    }
+
+    // Element i of client r is r * size + i
+    let input: Vec<u64> = (0..client.size as u64).map(|i| client.id as u64 * client.size as u64 + i)
+        .collect();
+    let result = client.reduce_scatter(&input, ReduceOp::Sum)?;
+    let expected: u64 = (0..client.size as u64).map(|r| r * client.size as u64 + client.id as u64).sum();
+    println!("Client {} got {}", client.id, result);
+    assert_eq!(result, expected);
+
+    let max = client.reduce_scatter(&input, ReduceOp::Max)?;
+    assert_eq!(max, (client.size as u64 - 1) * client.size as u64 + client.id as u64);
+
+    Ok(())
+}
+
+
+// Argmin: reduce (value, rank) tuples to the smallest value and the rank holding it
+fn _allreduce_with_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+
+    // The smallest value is on the middle client
+    let value = (client.id as f64 - (client.size / 2) as f64).abs();
+    let argmin = |a: &(f64, u32), b: &(f64, u32)| match b.0 < a.0
+    {
+        true => *b,
+        false => *a,
+    };
+
+    let (min, rank) = client.allreduce_with((value, client.id), argmin)?;
+    println!("Client {} got min {} on rank {}", client.id, min, rank);
+    assert_eq!((min, rank), (0.0, client.size / 2));
+
+    let res = client.reduce_with((value, client.id), argmin, 0)?;
+    assert_eq!(res.is_some(), client.id == 0);
+
+    Ok(())
+}
+
+
+// Every client has to see the same rank ordered result, run with at least 3 clients
+fn _allgather_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    let all = client.allgather(&format!("client {}", client.id))?;
+    println!("Client {} got {:?}", client.id, all);
+
+    let expected: Vec<String> = (0..client.size).map(|i| format!("client {}", i)).collect();
+    assert_eq!(all, expected);
+
+    // Compare with the result of client 0 as well
+    let mut first = all.clone();
+    client.broadcast(&mut first, 0)?;
+    assert_eq!(all, first);
+
+    Ok(())
+}
+
+
+fn _sendrecv_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+    let next = (client.id + 1) % client.size;
+    let prev = (client.id + client.size - 1) % client.size;
+
+    // Cyclic shift, all clients send and receive at the same time
+    let r: u32 = client.sendrecv(&client.id, next, 0, prev, 0)?;
+    println!("Client {} got {} from {}", client.id, r, prev);
+    assert_eq!(r, prev);
+
+    // Pairwise exchange with the same partner
+    let partner = client.id ^ 1;
+    if partner < client.size
+    {
+        let r: String = client.sendrecv(&format!("from {}", client.id), partner, 1, partner, 1)?;
+        println!("Client {} got {:?}", client.id, r);
+        assert_eq!(r, format!("from {}", partner));
+    }
+
+    Ok(())
+}
+
+
+fn _ibarrier_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+
+    // The last client arrives late, the others keep communicating in the meantime
+    if client.id == client.size - 1
+    {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+    }
+
+    let start = Instant::now();
+    let handle = client.ibarrier()?;
+    println!("Client {} returned from ibarrier after {:?}", client.id, start.elapsed());
+
+    let next = (client.id + 1) % client.size;
+    let prev = (client.id + client.size - 1) % client.size;
+    let r: u32 = client.sendrecv(&client.id, next, 0, prev, 0)?;
+    assert_eq!(r, prev);
+
+    handle.data()?;
+    println!("Client {} completed ibarrier after {:?}", client.id, start.elapsed());
+
+    client.barrier()?;
+
+    Ok(())
+}
+
+
+// Several non-blocking collectives run at the same time as point-to-point messages
+fn _nb_collectives_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+    let root = client.size - 1;
+
+    let data = match client.id == root
+    {
+        true => vec![1.5, 2.5],
+        false => Vec::<f64>::new(),
+    };
+    let bcast = client.ibcast(data, root)?;
+    let gather = client.igather(client.id, 0)?;
+    let reduce = client.ireduce(client.id as u64, ReduceOp::Sum, root)?;
+    let allreduce = client.iallreduce(client.id, ReduceOp::Max)?;
+
+    let next = (client.id + 1) % client.size;
+    let prev = (client.id + client.size - 1) % client.size;
+    let r: u32 = client.sendrecv(&client.id, next, 0, prev, 0)?;
+    assert_eq!(r, prev);
+
+    assert_eq!(bcast.data()?, vec![1.5, 2.5]);
+    if let Some(all) = gather.data()?
+    {
+        assert_eq!(all, (0..client.size).collect::<Vec<u32>>());
+    }
+    if let Some(sum) = reduce.data()?
+    {
+        assert_eq!(sum, (0..client.size as u64).sum::<u64>());
+    }
+    assert_eq!(allreduce.data()?, client.size - 1);
+    println!("Client {} completed all non-blocking collectives", client.id);
+
+    Ok(())
+}
+
+
+// Run partdiff with --report-json <path> first, then this test with the same path
+// after --args. Checks that the report has exactly the documented keys.
+fn _report_json_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    if client.id == 0
+    {
+        let file = std::fs::File::open(&client.cmd_args[0])?;
+        let report: serde_json::Value = serde_json::from_reader(std::io::BufReader::new(file))?;
+        let report = report.as_object().expect("Report is not a JSON object");
+
+        let mut keys: Vec<&str> = report.keys().map(|k| k.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, ["inf_func", "interlines", "iterations", "method", "precision",
+            "rank_times", "ranks", "termination", "total_time"]);
+
+        let rank_times: Vec<f64> = report["rank_times"].as_array().unwrap().iter()
+            .map(|t| t.as_f64().unwrap()).collect();
+        assert_eq!(rank_times.len() as u64, report["ranks"].as_u64().unwrap());
+        assert_eq!(rank_times.iter().cloned().fold(0.0, f64::max),
+            report["total_time"].as_f64().unwrap());
+        println!("Report of {} ranks is complete", rank_times.len());
+    }
+
+    Ok(())
+}
+
+
+// Start the daemon with --port 0 and pass partition and node name again
+// after --args, the client has to connect to the port the OS picked
+fn _daemon_port_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+
+    let home = env::var("HOME").expect("HOME environment variable is not set");
+    let path = format!("{}/.config/heimdallr/{}/{}", home, client.cmd_args[0], client.cmd_args[1]);
+    let file = std::fs::File::open(&path)?;
+    let config: DaemonConfig = serde_json::from_reader(std::io::BufReader::new(file))?;
+
+    println!("Client {} read daemon address {}", client.id, config.client_addr);
+    assert_ne!(config.client_addr.port(), 0);
+    assert_eq!(config.client_addr, config.daemon_addr);
+
+    // Only works if the client reached the daemon at that address
+    client.barrier()?;
+
+    Ok(())
+}
+
+
+// Start the clients with --daemon-addr <ip:port> of the daemon instead of
+// --partition and --node, they then connect without reading the daemon config file
+fn _daemon_addr_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+
+    // Rank to rank communication uses the addresses provided by the daemon
+    let next = (client.id + 1) % client.size;
+    let before = (client.id + client.size - 1) % client.size;
+    client.send(&client.id, next, 0)?;
+    let value: u32 = client.receive(before, 0)?;
+    println!("Client {} received {} without daemon config file", client.id, value);
+    assert_eq!(value, before);
+
+    client.barrier()?;
+
+    Ok(())
+}
+
+
+// Stop the daemon while the clients sleep, dropping the clients afterwards
+// must only print a warning instead of panicking
+fn _drop_without_daemon_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+    client.barrier()?;
+
+    println!("Client {} waiting for the daemon to be stopped", client.id);
+    std::thread::sleep(std::time::Duration::from_secs(5));
+
+    drop(client);
+    println!("Dropped client without daemon");
+
+    Ok(())
+}
+
+
+// The last client exits without finalizing, the daemon then aborts the job and
+// the barrier and lock calls of the other clients fail instead of hanging
+fn _disconnect_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+    assert!(client.size >= 3);
+    let mut mutex = client.create_mutex("disconnect_mutex", 0_u64)?;
+    client.barrier()?;
+
+    match client.id
+    {
+        0 =>
+        {
+            let _m = mutex.lock()?;
+            std::thread::sleep(std::time::Duration::from_millis(500));
+        },
+        1 =>
+        {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            let res = mutex.lock().map(|_| ());
+            println!("Client 1 lock after disconnect: {:?}", res);
+            assert!(res.is_err());
+        },
+        id if id == client.size - 1 =>
+        {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            std::process::exit(1);
+        },
+        _ => (),
+    }
+
+    let res = client.barrier();
+    println!("Client {} barrier after disconnect: {:?}", client.id, res);
+    assert!(res.is_err());
+
+    Ok(())
+}
+
+
+// The last client aborts the job, the receive of client 0 waiting for a message
+// from it and the barrier of the others then fail instead of hanging
+fn _abort_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+    assert!(client.size >= 2);
+    client.barrier()?;
+
+    if client.id == client.size - 1
+    {
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        client.abort(3);
+    }
+
+    let res = match client.id
+    {
+        0 => client.receive::<u64>(client.size - 1, 0).map(|_| ()),
+        _ => client.barrier(),
+    };
+    println!("Client {} after abort: {:?}", client.id, res);
+    assert!(res.is_err());
+
+    Ok(())
+}
+
+
+// Start the daemon with --interface ::1 and the clients with --interface ::1
+// or --interface lo, clients then have to communicate over IPv6
+fn _ipv6_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+    assert!(client.listener.local_addr()?.is_ipv6());
+
+    match client.id
+    {
+        0 => client.send(&"over ipv6".to_string(), 1, 0)?,
+        1 =>
+        {
+            let msg: String = client.receive(0, 0)?;
+            println!("Client 1 received {:?} on {}", msg, client.listener.local_addr()?);
+            assert_eq!(msg, "over ipv6");
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+
+// Repeated messages between two clients reuse the same connection
+fn _pingpong_latency_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+    let iterations = 1000;
+
+    let start = Instant::now();
+    for i in 0..iterations
+    {
+        match client.id
+        {
+            0 =>
+            {
+                client.send(&i, 1, 0)?;
+                let r: u32 = client.receive(1, 0)?;
+                assert_eq!(r, i);
+            },
+            1 =>
+            {
+                let r: u32 = client.receive(0, 0)?;
+                client.send(&r, 0, 0)?;
+            },
+            _ => (),
+        }
+    }
+    println!("Client {}: {} round trips in {:?}", client.id, iterations, start.elapsed());
+
+    Ok(())
+}
+
+
+fn _receive_timeout_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+    let timeout = std::time::Duration::from_millis(200);
+
+    match client.id
+    {
+        0 => client.send(&42_u64, 1, 0)?,
+        1 =>
+        {
+            let r = client.receive_timeout::<u64>(0, 0, timeout)?;
+            println!("Client 1 received {:?}", r);
+            assert_eq!(r, Some(42));
+
+            // Nobody sends this message
+            let start = Instant::now();
+            let r = client.receive_timeout::<u64>(0, 1, timeout)?;
+            println!("Client 1 gave up after {:?}", start.elapsed());
+            assert_eq!(r, None);
+            assert!(start.elapsed() >= timeout);
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+
+// Messages with the same id are received in the order they were sent
+fn _same_id_order_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    match client.id
+    {
+        0 =>
+        {
+            client.send(&"first".to_string(), 1, 0)?;
+            client.send(&"other id".to_string(), 1, 1)?;
+            client.send(&"second".to_string(), 1, 0)?;
+        },
+        1 =>
+        {
+            // Wait until all messages arrived
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            let first: String = client.receive(0, 0)?;
+            let second: String = client.receive(0, 0)?;
+            let other: String = client.receive(0, 1)?;
+            println!("Client 1 received {:?} {:?} {:?}", first, second, other);
+            assert_eq!((first.as_str(), second.as_str()), ("first", "second"));
+            assert_eq!(other, "other id");
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+
+fn _try_lock_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+    let mut mutex = client.create_mutex("trylockmutex", 0_u32)?;
+
+    if client.id == 0
+    {
+        let mut m = mutex.lock()?;
+        m.set(1);
+        client.barrier()?;
+        // The others try to lock while client 0 holds the lock
+        client.barrier()?;
+    }
+    else
+    {
+        client.barrier()?;
+        let busy = mutex.try_lock()?.is_none();
+        println!("Client {} found the mutex busy: {}", client.id, busy);
+        assert!(busy);
+        client.barrier()?;
+    }
+    client.barrier()?;
+
+    // Only one client can get the free lock at a time with try_lock, all of them
+    // eventually succeed
+    loop
+    {
+        if let Some(mut m) = mutex.try_lock()?
+        {
+            let value = *m.get();
+            m.set(value + 1);
+            break;
+        }
+    }
+    client.barrier()?;
+
+    if client.id == 0
+    {
+        let m = mutex.lock()?;
+        println!("Mutex value after try_lock: {}", m.get());
+        assert_eq!(*m.get(), 1 + client.size);
+    }
+
+    Ok(())
+}
+
+fn _lock_timeout_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+    let mut mutex = client.create_mutex("locktimeoutmutex", 0_u32)?;
+
+    if client.id == 0
+    {
+        let mut m = mutex.lock()?;
+        m.set(1);
+        client.barrier()?;
+        // The others time out while client 0 holds the lock
+        client.barrier()?;
+    }
+    else
+    {
+        client.barrier()?;
+        let start = Instant::now();
+        let timed_out = mutex.lock_timeout(std::time::Duration::from_millis(200))?.is_none();
+        println!("Client {} timed out after {:?}: {}", client.id, start.elapsed(), timed_out);
+        assert!(timed_out);
+        client.barrier()?;
+    }
+    client.barrier()?;
+
+    // Cancelled requests must not block the queue, all clients get the lock again
+    let mut m = mutex.lock_timeout(std::time::Duration::from_secs(10))?
+        .expect("Could not get the free lock within the timeout");
+    let value = *m.get();
+    m.set(value + 1);
+    drop(m);
+    client.barrier()?;
+
+    if client.id == 0
+    {
+        let m = mutex.lock()?;
+        println!("Mutex value after lock_timeout: {}", m.get());
+        assert_eq!(*m.get(), 1 + client.size);
+    }
+
+    Ok(())
+}
+
+fn _rwlock_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+    let mut rwlock = client.create_rwlock("rwlock", 0_u32)?;
+
+    // All clients hold read access at the same time, an exclusive lock would
+    // deadlock in the barrier
+    {
+        let r = rwlock.read()?;
+        assert_eq!(*r.get(), 0);
+        client.barrier()?;
+    }
+    client.barrier()?;
+
+    {
+        let mut w = rwlock.write()?;
+        let value = *w.get();
+        w.set(value + 1);
+    }
+    client.barrier()?;
+
+    let r = rwlock.read()?;
+    println!("Client {} read rwlock value: {}", client.id, r.get());
+    assert_eq!(*r.get(), client.size);
+
+    Ok(())
+}
+
+fn _try_data_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    match client.id
+    {
+        0 =>
+        {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            client.send(&42_u64, 1, 0)?;
+        },
+        1 =>
+        {
+            let mut handle = client.receive_nb::<u64>(0, 0)?;
+            let mut polls = 0;
+            let value = loop
+            {
+                handle = match handle.try_data()
+                {
+                    Ok(value) => break value?,
+                    Err(h) => h,
+                };
+                polls += 1;
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            };
+            println!("Client 1 received {} after {} polls", value, polls);
+            assert_eq!(value, 42);
+            assert!(polls > 0);
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+fn _wait_any_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    if client.id == 0
+    {
+        // Higher ranks send earlier, so wait_any completes them in reverse order
+        let mut handles = Vec::new();
+        for source in 1..client.size
+        {
+            handles.push(client.receive_nb::<u32>(source, 0)?);
+        }
+
+        let mut order = Vec::new();
+        while !handles.is_empty()
+        {
+            let (_, value) = heimdallr::wait_any(&mut handles);
+            order.push(value?);
+        }
+        println!("Client 0 completed receives in order: {:?}", order);
+        let expected: Vec<u32> = (1..client.size).rev().collect();
+        assert_eq!(order, expected);
+
+        let handles: Vec<_> = (1..client.size).map(|dest| client.send_nb(dest, dest, 1))
+            .collect::<std::io::Result<_>>()?;
+        for res in heimdallr::wait_all(handles)
+        {
+            res?;
+        }
+    }
+    else
+    {
+        std::thread::sleep(std::time::Duration::from_millis(100 * (client.size - client.id) as u64));
+        client.send(&client.id, 0, 0)?;
+        let value: u32 = client.receive(0, 1)?;
+        assert_eq!(value, client.id);
+    }
+
+    Ok(())
+}
+
+fn _processor_name_test() -> std::io::Result<()>
+{
+    let client = HeimdallrClient::init(env::args()).unwrap();
+
+    let hostname = gethostname().to_string_lossy().into_owned();
+    assert_eq!(client.self_processor_name(), hostname);
+    assert!(client.processor_name(client.size).is_none());
+
+    for rank in 0..client.size
+    {
+        println!("Client {} sees rank {} on host: {}", client.id, rank,
+            client.processor_name(rank).unwrap());
+    }
+
+    Ok(())
+}
+
+fn _wtime_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+
+    let start = client.wtime();
+    println!("Client {} wtime after init: {}", client.id, start);
+    assert!(start >= 0.0);
+
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    client.barrier()?;
+
+    // All clients left the barrier at about the same time
+    let after = client.wtime();
+    let min = client.allreduce(after, ReduceOp::Min)?;
+    let max = client.allreduce(after, ReduceOp::Max)?;
+    println!("Client {} wtime after barrier: {}, spread {}", client.id, after, max - min);
+    assert!(after - start >= 0.1);
+    assert!(max - min < 0.05);
+
+    Ok(())
+}
+
+fn _probe_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+
+    if client.id == 0
+    {
+        assert!(!client.probe(1, 0));
+        assert_eq!(client.probe_any_source(0), None);
+    }
+    client.barrier()?;
+
+    if client.id > 0
+    {
+        client.send(&client.id, 0, 0)?;
+    }
+    client.barrier()?;
+
+    if client.id == 0
+    {
+        // Messages can still be on their way after the barrier
+        while !(1..client.size).all(|source| client.probe(source, 0))
+        {
+            std::thread::yield_now();
+        }
+        // Probing does not consume the message
+        assert!(client.probe(client.size - 1, 0));
+
+        for _ in 1..client.size
+        {
+            let source = client.probe_any_source(0).unwrap();
+            let value: u32 = client.receive(source, 0)?;
+            println!("Client 0 probed and received {} from {}", value, source);
+            assert_eq!(value, source);
+        }
+        assert_eq!(client.probe_any_source(0), None);
+    }
+
+    Ok(())
+}
+
+fn _named_barrier_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+    assert!(client.size >= 3, "Named barrier test needs at least 3 clients");
+
+    // "low" is used by all clients but the last, "high" by all but the first,
+    // so the clients in between take part in both
+    let in_low = client.id < client.size - 1;
+    let in_high = client.id > 0;
+
+    // The last client joins late, "low" has to complete without it
+    let start = Instant::now();
+    if in_low
+    {
+        client.named_barrier("low", client.size - 1)?;
+    }
+    else
+    {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+    }
+    if client.id == 0
+    {
+        println!("Client 0 passed low barrier after {:?}", start.elapsed());
+        assert!(start.elapsed() < std::time::Duration::from_millis(500));
+    }
+
+    // Both barriers reset independently when used alternately
+    for _ in 0..10
+    {
+        if in_low
+        {
+            client.named_barrier("low", client.size - 1)?;
+        }
+        if in_high
+        {
+            client.named_barrier("high", client.size - 1)?;
+        }
+    }
+    client.barrier()?;
+
+    Ok(())
+}
+
+
+// Client 1 pretends to be client 0, the daemon has to report
+// the duplicate instead of leaving the barrier hanging
+fn _duplicate_id_test() -> std::io::Result<()>
+{
+    let mut client = HeimdallrClient::init(env::args()).unwrap();
+
+    let id = client.id;
+    if id == 1
+    {
+        client.id = 0;
+    }
+    match client.barrier()
+    {
+        Ok(()) => panic!("Barrier with duplicate client id succeeded"),
+        Err(e) => println!("Client {} got expected error: {}", id, e),
     }
+    client.id = id;
 
     Ok(())
 }
@@ -289,9 +2539,9 @@ fn _send_slice() -> std::io::Result<()>
     let client = HeimdallrClient::init(env::args()).unwrap();
 
     let mut sendbuf = vec![0.0;20];
-    for i in 0..sendbuf.len()
+    for (i, v) in sendbuf.iter_mut().enumerate()
     {
-        sendbuf[i] = i as f64;
+        *v = i as f64;
     }
     let mut recvbuf = vec![0.0;20];
 
@@ -331,7 +2581,7 @@ fn _barrier_benchmark() -> std::io::Result<()>
 fn _mutex_benchmark() -> std::io::Result<()>
 {
     let mut client = HeimdallrClient::init(env::args()).unwrap();
-    let mut mutex = client.create_mutex("testmutex", 0 as u64)?;
+    let mut mutex = client.create_mutex("testmutex", 0_u64)?;
 
     let now = Instant::now();
     for i in 0..25000
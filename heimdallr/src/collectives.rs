@@ -0,0 +1,1003 @@
+use std::time::Instant;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::Ordering;
+
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+
+use crate::{HeimdallrClient, CommStats, NbDataHandle, ByteSender, MessageQueues};
+use crate::{serialize_with, deserialize_with, wait_for_message};
+use crate::networking::*;
+
+
+// Message ids from RESERVED_ID_START on are used internally by collectives
+// and should not be used by applications
+pub const RESERVED_ID_START: u32 = 0xffff_0000;
+
+const BANDWIDTH_DATA_ID: u32 = RESERVED_ID_START;
+const BANDWIDTH_ACK_ID: u32 = RESERVED_ID_START + 1;
+const BANDWIDTH_RESULT_ID: u32 = RESERVED_ID_START + 2;
+const GATHER_ID: u32 = RESERVED_ID_START + 3;
+const REDISTRIBUTE_ID: u32 = RESERVED_ID_START + 4;
+const BROADCAST_ID: u32 = RESERVED_ID_START + 5;
+const SCATTER_ID: u32 = RESERVED_ID_START + 6;
+const GATHERV_ID: u32 = RESERVED_ID_START + 7;
+const ALLTOALL_ID: u32 = RESERVED_ID_START + 8;
+// Sent by the daemon to every client of an aborted job, the payload is the reason
+pub const ABORT_ID: u32 = RESERVED_ID_START + 9;
+const REDUCE_SCATTER_ID: u32 = RESERVED_ID_START + 10;
+// Credits of receive_chunked, the payload is the id of the chunked message
+pub const CHUNK_CREDIT_ID: u32 = RESERVED_ID_START + 11;
+// Every non-blocking collective gets the next of these ids, so collectives that run
+// at the same time do not take each others messages
+const NB_COLLECTIVE_ID_START: u32 = RESERVED_ID_START + 0x100;
+const NB_COLLECTIVE_IDS: u32 = 0x100;
+
+// Size of the message used to measure the bandwidth between two clients
+const BANDWIDTH_MSG_SIZE: usize = 4 * 1024 * 1024;
+
+// Up to this job size root sends a broadcast to every client itself,
+// larger jobs forward it along a binomial tree
+const LINEAR_BROADCAST_SIZE: u32 = 4;
+
+
+// The supported reduction operators
+// For bool values Max and Sum act as logical or, Min and Prod as logical and
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ReduceOp
+{
+    Sum,
+    Prod,
+    Max,
+    Min,
+}
+
+
+// Type independent representation of a reducible value.
+// Used to let the daemon apply reductions without knowing the client side types.
+// Integers are widened to 64 bit with wrapping arithmetic, so narrowing the
+// result back gives the same value as reducing in the original type.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ReduceValue
+{
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Bool(bool),
+}
+
+impl ReduceValue
+{
+    pub fn reduce(self, other: ReduceValue, op: ReduceOp) -> Option<ReduceValue>
+    {
+        match (self, other)
+        {
+            (ReduceValue::I64(a), ReduceValue::I64(b)) => Some(ReduceValue::I64(a.reduce(b, op))),
+            (ReduceValue::U64(a), ReduceValue::U64(b)) => Some(ReduceValue::U64(a.reduce(b, op))),
+            (ReduceValue::F64(a), ReduceValue::F64(b)) => Some(ReduceValue::F64(a.reduce(b, op))),
+            (ReduceValue::Bool(a), ReduceValue::Bool(b)) => Some(ReduceValue::Bool(a.reduce(b, op))),
+            _ => None,
+        }
+    }
+}
+
+
+// Types that can be used with the built-in reduction operators
+pub trait Reducible: Copy
+{
+    fn reduce(self, other: Self, op: ReduceOp) -> Self;
+    fn to_value(self) -> ReduceValue;
+    fn from_value(value: ReduceValue) -> Option<Self>;
+}
+
+macro_rules! impl_reducible_int
+{
+    ($variant:ident, $wide:ty, $($t:ty),*) =>
+    {
+        $(
+            impl Reducible for $t
+            {
+                fn reduce(self, other: Self, op: ReduceOp) -> Self
+                {
+                    match op
+                    {
+                        ReduceOp::Sum => self.wrapping_add(other),
+                        ReduceOp::Prod => self.wrapping_mul(other),
+                        ReduceOp::Max => self.max(other),
+                        ReduceOp::Min => self.min(other),
+                    }
+                }
+
+                fn to_value(self) -> ReduceValue
+                {
+                    ReduceValue::$variant(self as $wide)
+                }
+
+                fn from_value(value: ReduceValue) -> Option<Self>
+                {
+                    match value
+                    {
+                        ReduceValue::$variant(v) => Some(v as $t),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_reducible_int!(I64, i64, i8, i16, i32, i64, isize);
+impl_reducible_int!(U64, u64, u8, u16, u32, u64, usize);
+
+macro_rules! impl_reducible_float
+{
+    ($($t:ty),*) =>
+    {
+        $(
+            impl Reducible for $t
+            {
+                fn reduce(self, other: Self, op: ReduceOp) -> Self
+                {
+                    match op
+                    {
+                        ReduceOp::Sum => self + other,
+                        ReduceOp::Prod => self * other,
+                        ReduceOp::Max => self.max(other),
+                        ReduceOp::Min => self.min(other),
+                    }
+                }
+
+                fn to_value(self) -> ReduceValue
+                {
+                    ReduceValue::F64(self as f64)
+                }
+
+                fn from_value(value: ReduceValue) -> Option<Self>
+                {
+                    match value
+                    {
+                        ReduceValue::F64(v) => Some(v as $t),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_reducible_float!(f32, f64);
+
+impl Reducible for bool
+{
+    fn reduce(self, other: Self, op: ReduceOp) -> Self
+    {
+        match op
+        {
+            ReduceOp::Sum | ReduceOp::Max => self | other,
+            ReduceOp::Prod | ReduceOp::Min => self & other,
+        }
+    }
+
+    fn to_value(self) -> ReduceValue
+    {
+        ReduceValue::Bool(self)
+    }
+
+    fn from_value(value: ReduceValue) -> Option<Self>
+    {
+        match value
+        {
+            ReduceValue::Bool(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+
+// Identifies the type of a contribution to gather_tagged
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TypeTag(pub String);
+
+impl TypeTag
+{
+    pub fn new(name: &str) -> TypeTag
+    {
+        TypeTag(name.to_string())
+    }
+
+    // Tag from the name of the type. Type names are only guaranteed to match
+    // between clients built with the same compiler, use new otherwise.
+    pub fn of<T: ?Sized>() -> TypeTag
+    {
+        TypeTag(std::any::type_name::<T>().to_string())
+    }
+
+    pub fn is<T: ?Sized>(&self) -> bool
+    {
+        *self == TypeTag::of::<T>()
+    }
+}
+
+// Contribution of one client to gather_tagged: (client id, type tag, serialized data)
+pub type TaggedData = (u32, TypeTag, Vec<u8>);
+
+// Deserializes a raw contribution returned by gather_tagged of clients that use the
+// default serializer, otherwise use decode_tagged of the client
+pub fn decode<T>(data: &[u8]) -> std::io::Result<T>
+    where T: DeserializeOwned,
+{
+    deserialize_from(data, DEFAULT_MAX_MESSAGE_SIZE)
+}
+
+
+// Distribution of a global array with the given number of elements over the clients
+// Block: every client holds one contiguous part, the first clients get one element
+//        more if the elements can not be divided evenly
+// Cyclic: element i is held by client i % size
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout
+{
+    Block(usize),
+    Cyclic(usize),
+}
+
+impl Layout
+{
+    // Number of elements of the global array
+    pub fn total(&self) -> usize
+    {
+        match *self
+        {
+            Layout::Block(n) | Layout::Cyclic(n) => n,
+        }
+    }
+
+    // First global index and number of elements of rank in a block layout
+    fn block_range(n: usize, rank: u32, size: u32) -> (usize, usize)
+    {
+        let (base, rem) = (n / size as usize, n % size as usize);
+        let rank = rank as usize;
+        (rank * base + rank.min(rem), base + (rank < rem) as usize)
+    }
+
+    // Client holding the element with the global index
+    pub fn owner(&self, index: usize, size: u32) -> u32
+    {
+        match *self
+        {
+            Layout::Block(n) =>
+            {
+                let (base, rem) = (n / size as usize, n % size as usize);
+                match index < rem * (base+1)
+                {
+                    true => (index / (base+1)) as u32,
+                    false => (rem + (index - rem * (base+1)) / base) as u32,
+                }
+            },
+            Layout::Cyclic(_) => index as u32 % size,
+        }
+    }
+
+    // Position of the element with the global index in the local part of its owner
+    pub fn local_index(&self, index: usize, size: u32) -> usize
+    {
+        match *self
+        {
+            Layout::Block(n) => index - Layout::block_range(n, self.owner(index, size), size).0,
+            Layout::Cyclic(_) => index / size as usize,
+        }
+    }
+
+    // Global indices of the elements held by rank in local order
+    pub fn indices(&self, rank: u32, size: u32) -> Vec<usize>
+    {
+        match *self
+        {
+            Layout::Block(n) =>
+            {
+                let (start, count) = Layout::block_range(n, rank, size);
+                (start..start+count).collect()
+            },
+            Layout::Cyclic(n) => (rank as usize..n).step_by(size as usize).collect(),
+        }
+    }
+}
+
+
+// Combines the values of all clients in the given order, None without values
+fn fold_values<T, I>(values: I, op: ReduceOp, collective: &str) -> std::io::Result<Option<T>>
+    where T: Reducible, I: IntoIterator<Item = ReduceValue>,
+{
+    let mut result: Option<T> = None;
+    for v in values
+    {
+        let v = T::from_value(v).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData,
+            format!("Clients used different types in {}", collective)))?;
+        result = Some(match result
+        {
+            Some(acc) => acc.reduce(v, op),
+            None => v,
+        });
+    }
+    Ok(result)
+}
+
+
+impl HeimdallrClient
+{
+    fn check_root(&self, root: u32, collective: &str) -> std::io::Result<()>
+    {
+        match root < self.size
+        {
+            true => Ok(()),
+            false => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("Root {} of {} is outside of job with size {}", root, collective, self.size))),
+        }
+    }
+
+    // Barrier that additionally reduces one value per client.
+    // The daemon folds the values in rank order once all clients arrived and
+    // returns the result to everyone, so synchronization and agreement on a
+    // small piece of control data only take a single round trip.
+    pub fn barrier_with<T>(&mut self, value: T, op: ReduceOp) -> std::io::Result<T>
+        where T: Reducible,
+    {
+        let pkt = BarrierPkt::with_value(GLOBAL_BARRIER, self.id, self.size, &self.job, op,
+            value.to_value());
+        pkt.send(&mut self.daemon_stream)?;
+
+        let reply = BarrierReplyPkt::receive(&mut self.daemon_stream).ok_or_else(||
+            std::io::Error::other("Could not receive BarrierReplyPkt"))?;
+
+        reply.value.and_then(T::from_value).ok_or_else(||
+            std::io::Error::new(std::io::ErrorKind::InvalidData,
+                "Daemon did not return a valid reduced value for barrier_with"))
+    }
+
+    // Same as barrier_with, e.g. for a termination check that needs the
+    // maximum residuum of all clients in every iteration
+    pub fn barrier_allreduce<T>(&mut self, value: T, op: ReduceOp) -> std::io::Result<T>
+        where T: Reducible,
+    {
+        self.barrier_with(value, op)
+    }
+
+    // Combines value of all clients with op and returns the result on every client.
+    // The daemon applies op in rank order, so all clients get exactly the same result,
+    // also for floating point values.
+    pub fn allreduce<T>(&mut self, value: T, op: ReduceOp) -> std::io::Result<T>
+        where T: Reducible,
+    {
+        self.barrier_with(value, op)
+    }
+
+    // Combines value of all clients with op on root, all other clients get None.
+    // The values are combined in ascending rank order, so floating point results
+    // are reproducible. Has to be called by all clients.
+    pub fn reduce<T>(&self, value: T, op: ReduceOp, root: u32) -> std::io::Result<Option<T>>
+        where T: Reducible,
+    {
+        match self.gather(&value.to_value(), root)?
+        {
+            Some(values) => fold_values(values, op, "reduce"),
+            None => Ok(None),
+        }
+    }
+
+    // Combines element i of input of all clients with op and returns the result on
+    // client i, like MPI_Reduce_scatter with one element per client. Every client
+    // sends element i directly to client i, which combines them in ascending rank
+    // order, so no single root has to reduce all elements. Has to be called by all clients.
+    // Uses the reserved message id RESERVED_ID_START + 10.
+    pub fn reduce_scatter<T>(&self, input: &[T], op: ReduceOp) -> std::io::Result<T>
+        where T: Reducible,
+    {
+        if input.len() != self.size as usize
+        {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("reduce_scatter got {} elements for a job with size {}", input.len(), self.size)));
+        }
+
+        let mut values: Vec<Option<ReduceValue>> = vec![None; self.size as usize];
+        values[self.id as usize] = Some(input[self.id as usize].to_value());
+
+        for step in 1..self.size
+        {
+            let dest = (self.id + step) % self.size;
+            let source = (self.id + self.size - step) % self.size;
+            self.send(&input[dest as usize].to_value(), dest, REDUCE_SCATTER_ID)?;
+            values[source as usize] = Some(self.receive(source, REDUCE_SCATTER_ID)?);
+        }
+
+        Ok(fold_values(values.into_iter().flatten(), op, "reduce_scatter")?
+            .expect("Every job has at least one client"))
+    }
+
+    // Like reduce but with a user defined operation, e.g. for reductions on tuples
+    // or structs. f is applied pairwise in ascending rank order on root.
+    pub fn reduce_with<T, F>(&self, value: T, f: F, root: u32) -> std::io::Result<Option<T>>
+        where T: Serialize + DeserializeOwned, F: Fn(&T, &T) -> T,
+    {
+        let values = self.gather(&value, root)?;
+        Ok(values.and_then(|v| v.into_iter().reduce(|acc, x| f(&acc, &x))))
+    }
+
+    // Like allreduce but with a user defined operation, see reduce_with
+    pub fn allreduce_with<T, F>(&mut self, value: T, f: F) -> std::io::Result<T>
+        where T: Serialize + DeserializeOwned, F: Fn(&T, &T) -> T,
+    {
+        // Non root clients pass their own value to broadcast, which overwrites it
+        let mut result = match self.gather(&value, 0)?
+        {
+            Some(values) => values.into_iter().reduce(|acc, x| f(&acc, &x)).unwrap(),
+            None => value,
+        };
+        self.broadcast(&mut result, 0)?;
+        Ok(result)
+    }
+
+    // Returns true only once every client reports local_done.
+    // Meant for terminating iterative algorithms consistently on all clients.
+    pub fn all_done(&mut self, local_done: bool) -> std::io::Result<bool>
+    {
+        self.barrier_with(local_done, ReduceOp::Min)
+    }
+
+    // Non-blocking barrier_with. Like for ibarrier, the daemon stream must not be used
+    // for anything else until the result was taken from the handle.
+    pub fn ibarrier_with<T>(&mut self, value: T, op: ReduceOp)
+        -> std::io::Result<NbDataHandle<std::io::Result<T>>>
+        where T: Reducible + std::marker::Send + 'static,
+    {
+        let pkt = BarrierPkt::with_value(GLOBAL_BARRIER, self.id, self.size, &self.job, op,
+            value.to_value());
+        pkt.send(&mut self.daemon_stream)?;
+
+        let mut stream = self.daemon_stream.try_clone_stream()?;
+        Ok(self.run_nb(move ||
+        {
+            let reply = BarrierReplyPkt::receive(&mut stream).ok_or_else(||
+                std::io::Error::other("Could not receive BarrierReplyPkt"))?;
+            reply.value.and_then(T::from_value).ok_or_else(||
+                std::io::Error::new(std::io::ErrorKind::InvalidData,
+                    "Daemon did not return a valid reduced value for ibarrier_with"))
+        }))
+    }
+
+    // Non-blocking all_done, lets e.g. an iterative solver check the previous
+    // iteration while computing the next one
+    pub fn iall_done(&mut self, local_done: bool)
+        -> std::io::Result<NbDataHandle<std::io::Result<bool>>>
+    {
+        self.ibarrier_with(local_done, ReduceOp::Min)
+    }
+
+    // Measures the bandwidth in MB/s from every client to every other client.
+    // Has to be called by all clients. Entry [i][j] holds the bandwidth measured
+    // for sending from client i to client j, the diagonal is 0.
+    // The pairs are measured one after another in the same order on every client,
+    // a client only takes part in the measurements it is sending or receiving in.
+    pub fn measure_bandwidth_matrix(&mut self) -> std::io::Result<Vec<Vec<f64>>>
+    {
+        let size = self.size as usize;
+        let mut row = vec![0.0; size];
+        let msg = vec![0_u8; BANDWIDTH_MSG_SIZE];
+
+        for src in 0..self.size
+        {
+            for dest in (0..self.size).filter(|&d| d != src)
+            {
+                if self.id == src
+                {
+                    // Wait for an acknowledgement, so the time covers the full transfer
+                    let start = Instant::now();
+                    self.send(&msg, dest, BANDWIDTH_DATA_ID)?;
+                    self.receive::<()>(dest, BANDWIDTH_ACK_ID)?;
+                    let secs = start.elapsed().as_secs_f64();
+                    row[dest as usize] = BANDWIDTH_MSG_SIZE as f64 / 1e6 / secs;
+                }
+                else if self.id == dest
+                {
+                    self.receive::<Vec<u8>>(src, BANDWIDTH_DATA_ID)?;
+                    self.send(&(), src, BANDWIDTH_ACK_ID)?;
+                }
+            }
+        }
+
+        // Collect all rows at client 0 and distribute the full matrix from there
+        if self.id == 0
+        {
+            let mut matrix = vec![row];
+            for src in 1..self.size
+            {
+                matrix.push(self.receive::<Vec<f64>>(src, BANDWIDTH_RESULT_ID)?);
+            }
+            for dest in 1..self.size
+            {
+                self.send(&matrix, dest, BANDWIDTH_RESULT_ID)?;
+            }
+            Ok(matrix)
+        }
+        else
+        {
+            self.send(&row, 0, BANDWIDTH_RESULT_ID)?;
+            self.receive::<Vec<Vec<f64>>>(0, BANDWIDTH_RESULT_ID)
+        }
+    }
+
+    // Collects the CommStats of all clients on client 0, which prints them as a table
+    // with one row per client and their totals. Has to be called by all clients.
+    // Clients without with_stats report zeros.
+    pub fn print_stats(&self) -> std::io::Result<()>
+    {
+        // Taken before gathering, so the gather itself is not counted
+        let local = self.stats().unwrap_or_default();
+        let all = match self.gather(&local, 0)?
+        {
+            Some(all) => all,
+            None => return Ok(()),
+        };
+
+        println!("{:>6} {:>12} {:>14} {:>12} {:>14} {:>12}", "rank", "msgs sent", "bytes sent",
+            "msgs recv", "bytes recv", "recv wait s");
+        let mut total = CommStats::default();
+        for (rank, stats) in all.iter().enumerate()
+        {
+            println!("{:>6} {:>12} {:>14} {:>12} {:>14} {:>12.6}", rank, stats.messages_sent,
+                stats.bytes_sent, stats.messages_received, stats.bytes_received,
+                stats.receive_wait.as_secs_f64());
+            total.messages_sent += stats.messages_sent;
+            total.bytes_sent += stats.bytes_sent;
+            total.messages_received += stats.messages_received;
+            total.bytes_received += stats.bytes_received;
+            total.receive_wait += stats.receive_wait;
+        }
+        println!("{:>6} {:>12} {:>14} {:>12} {:>14} {:>12.6}", "total", total.messages_sent,
+            total.bytes_sent, total.messages_received, total.bytes_received,
+            total.receive_wait.as_secs_f64());
+        Ok(())
+    }
+
+    // Collects data of all clients on root, indexed by client id.
+    // Returns None on all other clients. Has to be called by all clients.
+    // Uses the reserved message id RESERVED_ID_START + 3.
+    pub fn gather<T>(&self, data: &T, root: u32) -> std::io::Result<Option<Vec<T>>>
+        where T: Serialize + DeserializeOwned,
+    {
+        self.check_root(root, "gather")?;
+
+        if self.id != root
+        {
+            self.send(data, root, GATHER_ID)?;
+            return Ok(None);
+        }
+
+        let mut result = Vec::with_capacity(self.size as usize);
+        for src in 0..self.size
+        {
+            match src == root
+            {
+                true => result.push(self.copy_serialized(data)?),
+                false => result.push(self.receive(src, GATHER_ID)?),
+            }
+        }
+        Ok(Some(result))
+    }
+
+    // Collects data of all clients on every client, indexed by client id.
+    // Has to be called by all clients.
+    pub fn allgather<T>(&self, data: &T) -> std::io::Result<Vec<T>>
+        where T: Serialize + DeserializeOwned,
+    {
+        let mut all = self.gather(data, 0)?.unwrap_or_default();
+        self.broadcast(&mut all, 0)?;
+        Ok(all)
+    }
+
+    // Concatenates slices of different lengths of all clients on root in rank order.
+    // Every slice is sent with its length, so the root does not need to know them.
+    // Returns None on all other clients. Has to be called by all clients.
+    // Uses the reserved message id RESERVED_ID_START + 7.
+    pub fn gatherv<T>(&self, data: &[T], root: u32) -> std::io::Result<Option<Vec<T>>>
+        where T: Serialize + DeserializeOwned,
+    {
+        self.check_root(root, "gatherv")?;
+
+        if self.id != root
+        {
+            self.send_slice(data, root, GATHERV_ID)?;
+            return Ok(None);
+        }
+
+        let mut result = Vec::new();
+        for src in 0..self.size
+        {
+            let part: Vec<T> = match src == root
+            {
+                true => self.copy_serialized(data)?,
+                false => self.receive(src, GATHERV_ID)?,
+            };
+            result.extend(part);
+        }
+        Ok(Some(result))
+    }
+
+    // Gather for contributions of different types. Every client sends its
+    // serialized data together with a tag describing its type. The root gets
+    // the contributions of all clients in rank order and can decode each
+    // contribution according to its tag, all other clients get None.
+    pub fn gather_tagged<T>(&self, data: &T, tag: TypeTag, root: u32)
+        -> std::io::Result<Option<Vec<TaggedData>>>
+        where T: Serialize,
+    {
+        let bytes = serialize_with(&*self.serializer, data)?;
+        let gathered = self.gather(&(tag, bytes), root)?;
+
+        Ok(gathered.map(|g| g.into_iter().zip(0..)
+            .map(|((tag, bytes), src)| (src, tag, bytes)).collect()))
+    }
+
+    // Deserializes a contribution returned by gather_tagged with the serializer of
+    // this client, which has to be the one of the contributing client as well
+    pub fn decode_tagged<T>(&self, data: &[u8]) -> std::io::Result<T>
+        where T: DeserializeOwned,
+    {
+        self.deserialize(data)
+    }
+
+    // Copies data through the serializer of the client, so collectives can keep the
+    // own contribution of a client without requiring Clone and decode it the same
+    // way as the contributions of the others
+    fn copy_serialized<T, U>(&self, data: &T) -> std::io::Result<U>
+        where T: Serialize + ?Sized, U: DeserializeOwned,
+    {
+        self.deserialize(&serialize_with(&*self.serializer, data)?)
+    }
+
+    // Moves the elements of a distributed array from old_layout to new_layout.
+    // local holds the elements of this client under old_layout, the elements of
+    // this client under new_layout are returned. Has to be called by all clients.
+    // The clients exchange their data pair by pair in the same order everywhere,
+    // so the blocking sends can not deadlock.
+    pub fn redistribute<T>(&self, local: Vec<T>, old_layout: &Layout, new_layout: &Layout)
+        -> std::io::Result<Vec<T>>
+        where T: Serialize + DeserializeOwned,
+    {
+        let old_indices = old_layout.indices(self.id, self.size);
+        if (old_layout.total() != new_layout.total()) | (local.len() != old_indices.len())
+        {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("Client {} can not redistribute {} elements from {:?} to {:?}",
+                    self.id, local.len(), old_layout, new_layout)));
+        }
+
+        // Sort the local elements by destination, together with their global index
+        let mut outgoing: Vec<Vec<(usize, T)>> = (0..self.size).map(|_| Vec::new()).collect();
+        for (index, element) in old_indices.into_iter().zip(local)
+        {
+            outgoing[new_layout.owner(index, self.size) as usize].push((index, element));
+        }
+
+        let mut result: Vec<Option<T>> = (0..new_layout.indices(self.id, self.size).len())
+            .map(|_| None).collect();
+        let mut place = |elements: Vec<(usize, T)>|
+        {
+            for (index, element) in elements
+            {
+                result[new_layout.local_index(index, self.size)] = Some(element);
+            }
+        };
+        place(std::mem::take(&mut outgoing[self.id as usize]));
+
+        for src in 0..self.size
+        {
+            for dest in (0..self.size).filter(|&d| d != src)
+            {
+                if self.id == src
+                {
+                    self.send(&outgoing[dest as usize], dest, REDISTRIBUTE_ID)?;
+                }
+                else if self.id == dest
+                {
+                    place(self.receive::<Vec<(usize, T)>>(src, REDISTRIBUTE_ID)?);
+                }
+            }
+        }
+
+        result.into_iter().collect::<Option<Vec<T>>>().ok_or_else(||
+            std::io::Error::new(std::io::ErrorKind::InvalidData,
+                "Not all elements were received in redistribute"))
+    }
+
+    // Distributes the value of data on root to all clients, overwriting their data.
+    // Has to be called by all clients.
+    pub fn broadcast<T>(&self, data: &mut T, root: u32) -> std::io::Result<()>
+        where T: Serialize + DeserializeOwned,
+    {
+        self.check_root(root, "broadcast")?;
+
+        if self.id == root
+        {
+            let msg = serialize_with(&*self.serializer, data)?;
+            self.broadcast_bytes(Some(msg), root)?;
+        }
+        else
+        {
+            let msg = self.broadcast_bytes(None, root)?;
+            *data = self.deserialize(&msg)?;
+        }
+        Ok(())
+    }
+
+    // Distributes the serialized message of root, which is the only client passing it.
+    // With ranks counted relative to root, a client gets the message from the client
+    // that differs in its lowest set bit and forwards it to the clients that differ in
+    // one of the lower bits, largest subtree first. So all clients have the message after
+    // log2(size) rounds and every client forwards the bytes without decoding them.
+    fn broadcast_bytes(&self, msg: Option<Vec<u8>>, root: u32) -> std::io::Result<Vec<u8>>
+    {
+        if self.size <= LINEAR_BROADCAST_SIZE
+        {
+            return match msg
+            {
+                Some(msg) =>
+                {
+                    for dest in (0..self.size).filter(|&d| d != root)
+                    {
+                        self.send_bytes(&msg, dest, BROADCAST_ID)?;
+                    }
+                    Ok(msg)
+                },
+                None => self.receive_bytes(root, BROADCAST_ID),
+            };
+        }
+
+        let relative = (self.id + self.size - root) % self.size;
+        let mut msg = msg;
+        let mut mask = 1_u32;
+        while mask < self.size
+        {
+            if relative & mask != 0
+            {
+                let parent = (relative - mask + root) % self.size;
+                msg = Some(self.receive_bytes(parent, BROADCAST_ID)?);
+                break;
+            }
+            mask <<= 1;
+        }
+
+        let msg = msg.expect("Only root starts a broadcast without receiving");
+        mask >>= 1;
+        while mask > 0
+        {
+            if relative + mask < self.size
+            {
+                self.send_bytes(&msg, (relative + mask + root) % self.size, BROADCAST_ID)?;
+            }
+            mask >>= 1;
+        }
+        Ok(msg)
+    }
+
+    // Client 0 runs producer, e.g. to read a config file once, and all clients return
+    // its result. Has to be called by all clients.
+    pub fn broadcast_from_root<T, F>(&self, producer: F) -> std::io::Result<T>
+        where T: Serialize + DeserializeOwned, F: FnOnce() -> T,
+    {
+        if self.id != 0
+        {
+            let msg = self.broadcast_bytes(None, 0)?;
+            return self.deserialize(&msg);
+        }
+
+        let data = producer();
+        self.broadcast_bytes(Some(serialize_with(&*self.serializer, &data)?), 0)?;
+        Ok(data)
+    }
+
+    // Sends element i of data on root to client i, every client returns its element.
+    // Only root has to pass the data, which needs exactly one element per client.
+    // Has to be called by all clients.
+    pub fn scatter<T>(&self, data: Option<&[T]>, root: u32) -> std::io::Result<T>
+        where T: Serialize + DeserializeOwned,
+    {
+        self.check_root(root, "scatter")?;
+
+        if self.id != root
+        {
+            return self.receive(root, SCATTER_ID);
+        }
+
+        let data = match data
+        {
+            Some(d) if d.len() == self.size as usize => d,
+            Some(d) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("Root of scatter has {} elements for a job with size {}", d.len(), self.size))),
+            None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "Root of scatter did not provide any data")),
+        };
+
+        for (dest, element) in (0..self.size).zip(data).filter(|(d, _)| *d != root)
+        {
+            self.send(element, dest, SCATTER_ID)?;
+        }
+        self.copy_serialized(&data[root as usize])
+    }
+
+    // Every client sends send[j] to client j and gets a Vec indexed by source.
+    // In step s a client sends to id+s and receives from id-s, so every client
+    // waits for a different source in each step. Has to be called by all clients.
+    // Uses the reserved message id RESERVED_ID_START + 8.
+    pub fn alltoall<T>(&self, send: &[T]) -> std::io::Result<Vec<T>>
+        where T: Serialize + DeserializeOwned + Clone,
+    {
+        if send.len() != self.size as usize
+        {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("alltoall got {} elements for a job with size {}", send.len(), self.size)));
+        }
+
+        let mut result: Vec<Option<T>> = vec![None; self.size as usize];
+        result[self.id as usize] = Some(send[self.id as usize].clone());
+
+        for step in 1..self.size
+        {
+            let dest = (self.id + step) % self.size;
+            let source = (self.id + self.size - step) % self.size;
+            self.send(&send[dest as usize], dest, ALLTOALL_ID)?;
+            result[source as usize] = Some(self.receive(source, ALLTOALL_ID)?);
+        }
+
+        Ok(result.into_iter().map(|r| r.unwrap()).collect())
+    }
+}
+
+
+// Everything a collective running on a worker thread needs of its client
+struct NbCollective
+{
+    sender: ByteSender,
+    readers: Arc<(Mutex<MessageQueues>, Condvar)>,
+    serializer: Arc<dyn Serializer>,
+    max_message_size: u64,
+    stats: Option<Arc<Mutex<CommStats>>>,
+    id: u32,
+    size: u32,
+    // Message id of all messages of this collective
+    tag: u32,
+}
+
+impl NbCollective
+{
+    fn send<T>(&self, data: &T, dest: u32) -> std::io::Result<()>
+        where T: Serialize,
+    {
+        let msg = serialize_with(&*self.serializer, data)?;
+        self.sender.send(&msg, dest, self.tag)
+    }
+
+    fn receive<T>(&self, source: u32) -> std::io::Result<T>
+        where T: DeserializeOwned,
+    {
+        let start = Instant::now();
+        let msg = wait_for_message(&self.readers, None, |r| r.pop(&(source, self.tag)))?
+            .expect("Waiting for a message without deadline can not time out")?;
+        CommStats::record_receive(&self.stats, msg.len(), start.elapsed());
+        deserialize_with(&*self.serializer, &msg, self.max_message_size)
+    }
+
+    fn broadcast<T>(&self, data: T, root: u32) -> std::io::Result<T>
+        where T: Serialize + DeserializeOwned,
+    {
+        if self.id != root
+        {
+            return self.receive(root);
+        }
+        for dest in (0..self.size).filter(|&d| d != root)
+        {
+            self.send(&data, dest)?;
+        }
+        Ok(data)
+    }
+
+    fn gather<T>(&self, data: T, root: u32) -> std::io::Result<Option<Vec<T>>>
+        where T: Serialize + DeserializeOwned,
+    {
+        if self.id != root
+        {
+            self.send(&data, root)?;
+            return Ok(None);
+        }
+
+        let mut own = Some(data);
+        let mut all = Vec::with_capacity(self.size as usize);
+        for src in 0..self.size
+        {
+            all.push(match src == root
+            {
+                true => own.take().unwrap(),
+                false => self.receive(src)?,
+            });
+        }
+        Ok(Some(all))
+    }
+
+    fn reduce<T>(&self, value: T, op: ReduceOp, root: u32) -> std::io::Result<Option<T>>
+        where T: Reducible,
+    {
+        match self.gather(value.to_value(), root)?
+        {
+            Some(values) => fold_values(values, op, "ireduce"),
+            None => Ok(None),
+        }
+    }
+}
+
+// Non-blocking variants of the collectives. They run on the worker threads of the
+// client, see with_nb_workers, and data() of the returned handle gives their result.
+// Like the blocking collectives they have to be called by all clients in the same order.
+impl HeimdallrClient
+{
+    // Context for the next non-blocking collective with its own message id
+    fn nb_collective(&self) -> NbCollective
+    {
+        let n = self.nb_collectives.fetch_add(1, Ordering::Relaxed);
+        NbCollective{sender: self.byte_sender(), readers: Arc::clone(&self.readers),
+            serializer: Arc::clone(&self.serializer), max_message_size: self.max_message_size,
+            stats: self.stats.clone(), id: self.id, size: self.size,
+            tag: NB_COLLECTIVE_ID_START + n % NB_COLLECTIVE_IDS}
+    }
+
+    fn run_nb<T, F>(&self, f: F) -> NbDataHandle<T>
+        where T: std::marker::Send + 'static, F: FnOnce() -> T + std::marker::Send + 'static,
+    {
+        let (handle, completion) = NbDataHandle::new();
+        self.pool.execute(move || completion.complete(f()));
+        handle
+    }
+
+    // Non-blocking broadcast, every client gets the data of root
+    pub fn ibcast<T>(&self, data: T, root: u32)
+        -> std::io::Result<NbDataHandle<std::io::Result<T>>>
+        where T: Serialize + DeserializeOwned + std::marker::Send + 'static,
+    {
+        self.check_root(root, "ibcast")?;
+        let nb = self.nb_collective();
+        Ok(self.run_nb(move || nb.broadcast(data, root)))
+    }
+
+    // Non-blocking gather, root gets the data of all clients in rank order, the others None
+    pub fn igather<T>(&self, data: T, root: u32)
+        -> std::io::Result<NbDataHandle<std::io::Result<Option<Vec<T>>>>>
+        where T: Serialize + DeserializeOwned + std::marker::Send + 'static,
+    {
+        self.check_root(root, "igather")?;
+        let nb = self.nb_collective();
+        Ok(self.run_nb(move || nb.gather(data, root)))
+    }
+
+    // Non-blocking reduce, combines the values in ascending rank order on root
+    pub fn ireduce<T>(&self, value: T, op: ReduceOp, root: u32)
+        -> std::io::Result<NbDataHandle<std::io::Result<Option<T>>>>
+        where T: Reducible + std::marker::Send + 'static,
+    {
+        self.check_root(root, "ireduce")?;
+        let nb = self.nb_collective();
+        Ok(self.run_nb(move || nb.reduce(value, op, root)))
+    }
+
+    // Non-blocking allreduce. Reduces on client 0 and broadcasts the result instead of
+    // using the daemon like allreduce, so the daemon stream stays free in the meantime.
+    pub fn iallreduce<T>(&self, value: T, op: ReduceOp)
+        -> std::io::Result<NbDataHandle<std::io::Result<T>>>
+        where T: Reducible + std::marker::Send + 'static,
+    {
+        let nb = self.nb_collective();
+        Ok(self.run_nb(move ||
+        {
+            let result = nb.reduce(value, op, 0)?.unwrap_or(value);
+            T::from_value(nb.broadcast(result.to_value(), 0)?).ok_or_else(|| std::io::Error::new(
+                std::io::ErrorKind::InvalidData, "Clients used different types in iallreduce"))
+        }))
+    }
+}
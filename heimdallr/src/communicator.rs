@@ -0,0 +1,394 @@
+// Groups of clients with their own ranks and message ids, similar to MPI_Comm.
+// A communicator borrows the client and maps its tags to message ids of the client,
+// so library code can communicate without colliding with the ids of the application.
+
+use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::{HeimdallrClient, NbDataHandle};
+use crate::collectives::RESERVED_ID_START;
+
+
+// Ids from here on belong to communicators created with dup and split, the tags
+// of world are the message ids of the client and have to stay below it
+pub const COMM_ID_START: u32 = 0x8000_0000;
+// Every communicator other than world gets a block of ids, of which the last
+// COMM_INTERNAL_TAGS are used by its collectives
+pub const COMM_BLOCK_SIZE: u32 = 1 << 16;
+pub const COMM_INTERNAL_TAGS: u32 = 16;
+pub const COMM_TAG_LIMIT: u32 = COMM_BLOCK_SIZE - COMM_INTERNAL_TAGS;
+pub const MAX_COMMUNICATORS: u32 = (RESERVED_ID_START - COMM_ID_START) / COMM_BLOCK_SIZE;
+
+// Collectives of world use these reserved ids instead
+const WORLD_INTERNAL_ID_START: u32 = RESERVED_ID_START + 16;
+
+// Internal tags of the collectives
+const GATHER_TAG: u32 = 0;
+const BROADCAST_TAG: u32 = 1;
+
+
+#[derive(Clone)]
+pub struct Communicator<'a>
+{
+    client: &'a HeimdallrClient,
+    pub rank: u32,
+    pub size: u32,
+    // 0 for world, the others get a context that none of their members used before
+    pub context: u32,
+    // Client ids of the members by their rank in this communicator
+    members: Arc<Vec<u32>>,
+}
+
+impl HeimdallrClient
+{
+    // Communicator of all clients of the job, ranks and tags are the same as for
+    // the methods of the client itself
+    pub fn world(&self) -> Communicator<'_>
+    {
+        Communicator{client: self, rank: self.id, size: self.size, context: 0,
+            members: Arc::new((0..self.size).collect())}
+    }
+}
+
+impl<'a> Communicator<'a>
+{
+    pub fn client(&self) -> &'a HeimdallrClient
+    {
+        self.client
+    }
+
+    // Client id of the member with rank in this communicator
+    pub fn client_id(&self, rank: u32) -> std::io::Result<u32>
+    {
+        self.members.get(rank as usize).copied().ok_or_else(||
+            std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("Rank {} is outside of communicator {} with size {}",
+                    rank, self.context, self.size)))
+    }
+
+    // Same group of clients with a new set of tags.
+    // Has to be called by all members.
+    pub fn dup(&self) -> std::io::Result<Communicator<'a>>
+    {
+        let mut context = self.client.communicators.load(Ordering::Relaxed);
+        context = self.allgather(&context)?.into_iter().max().unwrap_or(context) + 1;
+        let context = self.claim_context(context)?;
+
+        Ok(Communicator{context, ..self.clone()})
+    }
+
+    // Partitions the members into one communicator per color, like MPI_Comm_split.
+    // The members of each new communicator are ordered by key and then by their rank
+    // in this one. Has to be called by all members.
+    pub fn split(&self, color: u32, key: u32) -> std::io::Result<Communicator<'a>>
+    {
+        let next_context = self.client.communicators.load(Ordering::Relaxed);
+        let all = self.allgather(&(color, key, next_context))?;
+
+        // All new communicators get the same context, they have no members in common
+        let context = all.iter().map(|&(_, _, c)| c).max().unwrap_or(next_context) + 1;
+        let context = self.claim_context(context)?;
+
+        let mut ranks: Vec<(u32, u32)> = all.iter().enumerate()
+            .filter(|(_, &(c, _, _))| c == color)
+            .map(|(rank, &(_, k, _))| (k, rank as u32))
+            .collect();
+        ranks.sort_unstable();
+
+        let members: Vec<u32> = ranks.iter().map(|&(_, rank)| self.members[rank as usize])
+            .collect();
+        let rank = ranks.iter().position(|&(_, rank)| rank == self.rank)
+            .expect("Own rank is missing in split communicator") as u32;
+
+        Ok(Communicator{client: self.client, rank, size: members.len() as u32, context,
+            members: Arc::new(members)})
+    }
+
+    // Marks all contexts up to context as used by this client
+    fn claim_context(&self, context: u32) -> std::io::Result<u32>
+    {
+        if context > MAX_COMMUNICATORS
+        {
+            return Err(std::io::Error::other(format!(
+                "No more than {} communicators can be created", MAX_COMMUNICATORS)));
+        }
+        self.client.communicators.fetch_max(context, Ordering::Relaxed);
+        Ok(context)
+    }
+
+    fn block_start(&self) -> u32
+    {
+        COMM_ID_START + (self.context - 1) * COMM_BLOCK_SIZE
+    }
+
+    // Message id of the internal tag of a collective
+    fn internal_id(&self, tag: u32) -> u32
+    {
+        match self.context
+        {
+            0 => WORLD_INTERNAL_ID_START + tag,
+            _ => self.block_start() + COMM_TAG_LIMIT + tag,
+        }
+    }
+
+    // Message id of the client that tag of this communicator is sent with
+    pub fn id(&self, tag: u32) -> std::io::Result<u32>
+    {
+        let limit = match self.context
+        {
+            0 => COMM_ID_START,
+            _ => COMM_TAG_LIMIT,
+        };
+        if tag >= limit
+        {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("Tag {} is outside of the {} tags of communicator {}",
+                    tag, limit, self.context)));
+        }
+
+        match self.context
+        {
+            0 => Ok(tag),
+            _ => Ok(self.block_start() + tag),
+        }
+    }
+
+    pub fn send<T>(&self, data: &T, dest: u32, tag: u32) -> std::io::Result<()>
+        where T: Serialize,
+    {
+        self.client.send(data, self.client_id(dest)?, self.id(tag)?)
+    }
+
+    pub fn send_slice<T>(&self, data: &[T], dest: u32, tag: u32) -> std::io::Result<()>
+        where T: Serialize,
+    {
+        self.client.send_slice(data, self.client_id(dest)?, self.id(tag)?)
+    }
+
+    pub fn receive<T>(&self, source: u32, tag: u32) -> std::io::Result<T>
+        where T: DeserializeOwned,
+    {
+        self.client.receive(self.client_id(source)?, self.id(tag)?)
+    }
+
+    pub fn receive_timeout<T>(&self, source: u32, tag: u32, timeout: Duration)
+        -> std::io::Result<Option<T>>
+        where T: DeserializeOwned,
+    {
+        self.client.receive_timeout(self.client_id(source)?, self.id(tag)?, timeout)
+    }
+
+    // Only takes messages of members of this communicator
+    pub fn receive_any_source<T>(&self, tag: u32) -> std::io::Result<T>
+        where T: DeserializeOwned,
+    {
+        let id = self.id(tag)?;
+        let (_, msg) = self.client.take_matching_message(|k| k.1 == id
+            && self.members.contains(&k.0))?;
+        self.client.deserialize(&msg)
+    }
+
+    pub fn probe(&self, source: u32, tag: u32) -> std::io::Result<bool>
+    {
+        Ok(self.client.probe(self.client_id(source)?, self.id(tag)?))
+    }
+
+    pub fn send_nb<T>(&self, data: T, dest: u32, tag: u32)
+        -> std::io::Result<NbDataHandle<std::io::Result<T>>>
+        where T: Serialize + Send + 'static,
+    {
+        self.client.send_nb(data, self.client_id(dest)?, self.id(tag)?)
+    }
+
+    pub fn receive_nb<T>(&self, source: u32, tag: u32)
+        -> std::io::Result<NbDataHandle<std::io::Result<T>>>
+        where T: DeserializeOwned + Send + 'static,
+    {
+        self.client.receive_nb(self.client_id(source)?, self.id(tag)?)
+    }
+
+    // Collects data of all members on root, indexed by rank.
+    // Returns None on all other members. Has to be called by all members.
+    pub fn gather<T>(&self, data: &T, root: u32) -> std::io::Result<Option<Vec<T>>>
+        where T: Serialize + DeserializeOwned,
+    {
+        let root_id = self.client_id(root)?;
+        let id = self.internal_id(GATHER_TAG);
+        self.client.send(data, root_id, id)?;
+        if self.rank != root
+        {
+            return Ok(None);
+        }
+
+        self.members.iter().map(|&src| self.client.receive(src, id)).collect::<Result<_, _>>()
+            .map(Some)
+    }
+
+    // Collects data of all members on every member, indexed by rank.
+    // Has to be called by all members.
+    pub fn allgather<T>(&self, data: &T) -> std::io::Result<Vec<T>>
+        where T: Serialize + DeserializeOwned,
+    {
+        let mut all = self.gather(data, 0)?.unwrap_or_default();
+        self.broadcast(&mut all, 0)?;
+        Ok(all)
+    }
+
+    // Distributes the value of data on root to all members, overwriting their data.
+    // Has to be called by all members.
+    pub fn broadcast<T>(&self, data: &mut T, root: u32) -> std::io::Result<()>
+        where T: Serialize + DeserializeOwned,
+    {
+        let root_id = self.client_id(root)?;
+        let id = self.internal_id(BROADCAST_TAG);
+        if self.rank == root
+        {
+            for &dest in self.members.iter().filter(|&&d| d != root_id)
+            {
+                self.client.send(data, dest, id)?;
+            }
+        }
+        else
+        {
+            *data = self.client.receive(root_id, id)?;
+        }
+        Ok(())
+    }
+
+    // Waits until all members reached the barrier. Only involves the members,
+    // unlike the barrier of the client this goes through messages instead of the daemon.
+    pub fn barrier(&self) -> std::io::Result<()>
+    {
+        self.gather(&(), 0)?;
+        self.broadcast(&mut (), 0)
+    }
+}
+
+
+// Communicator whose members are arranged in a grid, like MPI_Cart_create.
+// Ranks are assigned in row-major order, the last dimension varies fastest.
+#[derive(Clone)]
+pub struct CartComm<'a>
+{
+    pub comm: Communicator<'a>,
+    pub dims: Vec<u32>,
+    // Whether each dimension wraps around at its ends
+    pub periods: Vec<bool>,
+}
+
+impl<'a> Communicator<'a>
+{
+    // Grid with the extents of dims, which have to multiply to the size of the communicator
+    pub fn cart(&self, dims: &[u32], periods: &[bool]) -> std::io::Result<CartComm<'a>>
+    {
+        if dims.len() != periods.len()
+        {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("Grid with {} dimensions needs {} periods, not {}",
+                    dims.len(), dims.len(), periods.len())));
+        }
+        if dims.iter().map(|&d| d as u64).product::<u64>() != self.size as u64
+        {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("Grid {:?} does not match communicator size {}", dims, self.size)));
+        }
+
+        Ok(CartComm{comm: self.clone(), dims: dims.to_vec(), periods: periods.to_vec()})
+    }
+}
+
+impl<'a> CartComm<'a>
+{
+    pub fn rank(&self) -> u32
+    {
+        self.comm.rank
+    }
+
+    // Position of rank in the grid
+    pub fn coords(&self, rank: u32) -> Vec<u32>
+    {
+        let mut rest = rank;
+        let mut coords = vec![0; self.dims.len()];
+        for (c, &d) in coords.iter_mut().zip(&self.dims).rev()
+        {
+            *c = rest % d;
+            rest /= d;
+        }
+        coords
+    }
+
+    // Rank at coords, coordinates outside of the grid wrap around in periodic
+    // dimensions and give None otherwise
+    pub fn rank_of(&self, coords: &[i64]) -> Option<u32>
+    {
+        let mut rank = 0;
+        for ((&c, &d), &periodic) in coords.iter().zip(&self.dims).zip(&self.periods)
+        {
+            let d = d as i64;
+            let c = match periodic
+            {
+                true => c.rem_euclid(d),
+                false if (0..d).contains(&c) => c,
+                false => return None,
+            };
+            rank = rank * d + c;
+        }
+        Some(rank as u32)
+    }
+
+    // Neighbours disp steps back and forth along dim, like MPI_Cart_shift.
+    // Returns (source, dest): this rank receives from source and sends to dest
+    // when shifting data by disp. None at the boundary of a non-periodic dimension.
+    pub fn shift(&self, dim: usize, disp: i64) -> (Option<u32>, Option<u32>)
+    {
+        let coords: Vec<i64> = self.coords(self.rank()).into_iter().map(|c| c as i64).collect();
+        let neighbour = |d: i64|
+        {
+            let mut c = coords.clone();
+            c[dim] += d;
+            self.rank_of(&c)
+        };
+        (neighbour(-disp), neighbour(disp))
+    }
+}
+
+// Splits size ranks into a grid of ndims dimensions that are as close to each
+// other as possible, largest first, like MPI_Dims_create
+pub fn dims_create(size: u32, ndims: usize) -> Vec<u32>
+{
+    let mut dims = vec![1; ndims];
+    if ndims == 0
+    {
+        return dims;
+    }
+
+    // Assigns the prime factors from the largest on to the currently smallest dimension
+    let mut factors = Vec::new();
+    let mut rest = size;
+    let mut p = 2;
+    while p * p <= rest
+    {
+        while rest.is_multiple_of(p)
+        {
+            factors.push(p);
+            rest /= p;
+        }
+        p += 1;
+    }
+    if rest > 1
+    {
+        factors.push(rest);
+    }
+
+    for f in factors.into_iter().rev()
+    {
+        let smallest = dims.iter_mut().min().unwrap();
+        *smallest *= f;
+    }
+    dims.sort_unstable_by(|a, b| b.cmp(a));
+    dims
+}
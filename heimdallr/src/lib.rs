@@ -1,38 +1,606 @@
 pub mod networking;
-
-use std::process;
-use std::net::{SocketAddr, IpAddr,TcpListener, TcpStream};
-use std::io::{Write, BufReader};
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
+pub mod collectives;
+pub mod mpi;
+pub mod communicator;
+pub mod window;
+
+use std::net::{SocketAddr, IpAddr, TcpListener, TcpStream};
+use std::io::{Read, Write, BufReader, BufWriter};
+use std::sync::{Arc, Weak, Mutex, MutexGuard, Condvar, mpsc};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::{fmt, env, thread};
 use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
 use std::str::FromStr;
 
 use serde::{Serialize, Deserialize};
-use local_ipaddress;
-use pnet::datalink;
 
 use crate::networking::*;
 
+pub use crate::collectives::{ReduceOp, Reducible, TypeTag, Layout};
+pub use crate::networking::{Serializer, BincodeSerializer};
+pub use crate::communicator::{Communicator, CartComm};
+pub use crate::window::Window;
+// Used in the signatures of Serializer
+pub use erased_serde;
 
-pub struct HeimdallrClient
+
+#[derive(Debug)]
+pub enum HeimdallrError
+{
+    MissingArgs,
+    InvalidArg(&'static str),
+    HomeNotSet,
+    ConfigNotFound(PathBuf),
+    ConfigParse(serde_json::Error),
+    DaemonConnect(std::io::Error),
+    RegistrationRejected,
+    Io(std::io::Error),
+}
+
+impl fmt::Display for HeimdallrError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            HeimdallrError::MissingArgs => write!(f,
+                "Client did not provide all necessary arguments (--partition and --node or --daemon-addr, and --jobs)"),
+            HeimdallrError::InvalidArg(msg) => write!(f, "{}", msg),
+            HeimdallrError::HomeNotSet => write!(f, "HOME environment variable is not set"),
+            HeimdallrError::ConfigNotFound(path) => write!(f,
+                "Could not open node file for this job at {}", path.display()),
+            HeimdallrError::ConfigParse(e) => write!(f, "Could not parse DaemonConfig file: {}", e),
+            HeimdallrError::DaemonConnect(e) => write!(f, "Could not connect to daemon: {}", e),
+            HeimdallrError::RegistrationRejected => write!(f,
+                "Client registration was rejected by the daemon"),
+            HeimdallrError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for HeimdallrError
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)>
+    {
+        match self
+        {
+            HeimdallrError::ConfigParse(e) => Some(e),
+            HeimdallrError::DaemonConnect(e) | HeimdallrError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for HeimdallrError
+{
+    fn from(e: std::io::Error) -> Self
+    {
+        HeimdallrError::Io(e)
+    }
+}
+
+
+// Wildcards for receive_any, like MPI_ANY_SOURCE and MPI_ANY_TAG. Both are reserved
+// ids, which are never used as source or id of a message themselves.
+pub const ANY_SOURCE: u32 = u32::MAX;
+pub const ANY_TAG: u32 = u32::MAX;
+
+// Where a message received with receive_status came from and how many elements
+// it had, like MPI_Status together with MPI_Get_count
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status
+{
+    pub source: u32,
+    pub tag: u32,
+    pub count: usize,
+}
+
+
+// Payload of a received message, or an error for a message that was lost on the way,
+// which fails the receive taking it
+type Payload = std::io::Result<Vec<u8>>;
+
+// Payloads of one (source, id) in the order they arrived, each with its arrival number
+type MessageQueue = VecDeque<(u64, Payload)>;
+
+// Called with the message of a receive_nb once it arrived, or with an error if it was
+// lost or the job was aborted before
+type PendingReceive = Box<dyn FnOnce(Payload) + std::marker::Send>;
+
+// Called by the reader thread with the source and payload of every message with its id,
+// instead of queueing the message. Used for requests no receive is posted for, like
+// the puts and gets of a Window.
+type MessageHandler = Arc<dyn Fn(u32, Vec<u8>) + std::marker::Send + Sync>;
+
+// Received messages by (source, id) that were not taken by a receive yet
+#[derive(Default)]
+struct MessageQueues
+{
+    queues: HashMap<(u32,u32), MessageQueue>,
+    // Count of all received messages, orders messages with different keys
+    received: u64,
+    // Reason sent by the daemon once the job was aborted
+    aborted: Option<String>,
+    // Posted receive_nb calls by (source, id) in the order they were posted. They get
+    // messages before blocking receives, so they do not need a thread waiting for them.
+    pending: HashMap<(u32,u32), VecDeque<PendingReceive>>,
+    handlers: HashMap<u32, MessageHandler>,
+    // Striped messages by (source, seq) that are still missing fragments,
+    // with the number of bytes received so far
+    stripes: HashMap<(u32,u64), (Vec<u8>, u64)>,
+    // Striped messages the sender failed to send, their remaining fragments are dropped
+    cancelled: HashSet<(u32,u64)>,
+    // Sequence number of the next message by (source, id), messages that arrive
+    // before it over another stream are kept in early until it is there,
+    // together with the time they started waiting for it
+    next_seqs: HashMap<(u32,u32), u64>,
+    early: HashMap<(u32,u32), (Instant, BTreeMap<u64, Payload>)>,
+    // Messages by (source, id, seq) whose header arrived, but not their whole payload yet
+    in_flight: HashSet<(u32,u32,u64)>,
+}
+
+// Time later messages wait for a message that did not even start to arrive,
+// before it is considered lost and its receive fails
+pub const GAP_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl MessageQueues
+{
+    fn push(&mut self, key: (u32,u32), msg: Payload)
+    {
+        if let Some(pending) = self.pending.get_mut(&key)
+        {
+            let receive = pending.pop_front().unwrap();
+            if pending.is_empty()
+            {
+                self.pending.remove(&key);
+            }
+            receive(msg);
+            return;
+        }
+
+        self.queues.entry(key).or_default().push_back((self.received, msg));
+        self.received += 1;
+    }
+
+    // Returns msg together with the early messages following it if it is the next
+    // message of key in send order, otherwise keeps it until the ones before arrived
+    fn in_order(&mut self, key: (u32,u32), seq: u64, msg: Payload) -> Vec<Payload>
+    {
+        self.in_flight.remove(&(key.0, key.1, seq));
+        let next = self.next_seqs.entry(key).or_insert(0);
+        // Already delivered, e.g. a message whose sender saw its write fail too late
+        if seq < *next
+        {
+            return Vec::new();
+        }
+        if seq != *next
+        {
+            self.early.entry(key).or_insert_with(|| (Instant::now(), BTreeMap::new()))
+                .1.insert(seq, msg);
+            return Vec::new();
+        }
+
+        let mut ready = vec![msg];
+        *next += 1;
+        if let Some((since, early)) = self.early.get_mut(&key)
+        {
+            while let Some(m) = early.remove(next)
+            {
+                ready.push(m);
+                *next += 1;
+            }
+            if early.is_empty()
+            {
+                self.early.remove(&key);
+            }
+            else
+            {
+                *since = Instant::now();
+            }
+        }
+        ready
+    }
+
+    // Gives up on the next message of every (source, id) that later messages waited
+    // for longer than GAP_TIMEOUT without it starting to arrive, e.g. because it was
+    // lost with a broken connection. Returns the messages released by that, which wait
+    // behind an error for the receive of the lost one.
+    fn expire_gaps(&mut self, now: Instant) -> Vec<((u32,u32), Vec<Payload>)>
+    {
+        let expired: Vec<_> = self.early.iter()
+            .filter(|(k, (since, _))| now.duration_since(*since) >= GAP_TIMEOUT
+                && !self.in_flight.contains(&(k.0, k.1, self.next_seqs[k])))
+            .map(|(k, _)| *k).collect();
+
+        expired.into_iter().map(|key|
+        {
+            let seq = self.next_seqs[&key];
+            let lost = Err(std::io::Error::new(std::io::ErrorKind::TimedOut,
+                format!("Message {} with id {} from client {} did not arrive within {} seconds \
+                    of later ones", seq, key.1, key.0, GAP_TIMEOUT.as_secs())));
+            (key, self.in_order(key, seq, lost))
+        }).collect()
+    }
+
+    // Completes receive with a message that already arrived or keeps it until one does
+    fn post(&mut self, key: (u32,u32), receive: PendingReceive)
+    {
+        if let Some(reason) = &self.aborted
+        {
+            receive(Err(std::io::Error::other(reason.clone())));
+            return;
+        }
+
+        match self.pop(&key)
+        {
+            Some(msg) => receive(msg),
+            None => self.pending.entry(key).or_default().push_back(receive),
+        }
+    }
+
+    fn abort(&mut self, reason: String)
+    {
+        for (_, pending) in self.pending.drain()
+        {
+            for receive in pending
+            {
+                receive(Err(std::io::Error::other(reason.clone())));
+            }
+        }
+        self.aborted = Some(reason);
+    }
+
+    // Takes the oldest message of key, keys without messages are removed
+    fn pop(&mut self, key: &(u32,u32)) -> Option<Payload>
+    {
+        let queue = self.queues.get_mut(key)?;
+        let msg = queue.pop_front().map(|(_, m)| m);
+        if queue.is_empty()
+        {
+            self.queues.remove(key);
+        }
+        msg
+    }
+
+    // Takes the oldest credit of key for the chunked message id, or an error that
+    // arrived instead of one
+    fn take_credit(&mut self, key: (u32,u32), id: u32) -> Option<Payload>
+    {
+        let queue = self.queues.get_mut(&key)?;
+        let i = queue.iter().position(|(_, m)|
+            m.as_ref().map_or(true, |c| c[..] == id.to_le_bytes()))?;
+        let msg = queue.remove(i).map(|(_, m)| m);
+        if queue.is_empty()
+        {
+            self.queues.remove(&key);
+        }
+        msg
+    }
+
+    // Key of the message that arrived first among all keys satisfying matches
+    fn oldest_matching<F>(&self, matches: F) -> Option<(u32,u32)>
+        where F: Fn(&(u32,u32)) -> bool,
+    {
+        self.queues.iter().filter(|(k, _)| matches(k))
+            .min_by_key(|(_, q)| q[0].0).map(|(k, _)| *k)
+    }
+}
+
+
+// Sleeps until take finds a message in readers or deadline passed.
+// Fails once the job was aborted, as the message might never be sent then.
+fn wait_for_message<R, F>(readers: &(Mutex<MessageQueues>, Condvar), deadline: Option<Instant>,
+    mut take: F) -> std::io::Result<Option<R>>
+    where F: FnMut(&mut MessageQueues) -> Option<R>,
+{
+    let (lock, cvar) = readers;
+    let mut r = lock.lock().expect("Could not lock 'readers' Mutex");
+    loop
+    {
+        if let Some(reason) = &r.aborted
+        {
+            return Err(std::io::Error::other(reason.clone()));
+        }
+        if let Some(msg) = take(&mut r)
+        {
+            return Ok(Some(msg));
+        }
+
+        r = match deadline
+        {
+            None => cvar.wait(r).expect("Could not lock 'readers' Mutex"),
+            Some(d) =>
+            {
+                let now = Instant::now();
+                if now >= d
+                {
+                    return Ok(None);
+                }
+                cvar.wait_timeout(r, d - now).expect("Could not lock 'readers' Mutex").0
+            },
+        };
+    }
+}
+
+
+// Encodes data with serializer, usable from threads that do not own the client
+fn serialize_with<T>(serializer: &dyn Serializer, data: &T) -> std::io::Result<Vec<u8>>
+    where T: Serialize + ?Sized,
+{
+    serializer.serialize(&data)
+}
+
+fn deserialize_with<T>(serializer: &dyn Serializer, data: &[u8], limit: u64)
+    -> std::io::Result<T>
+    where T: serde::de::DeserializeOwned,
+{
+    let mut value = None;
+    serializer.deserialize(data, limit, &mut |de|
+    {
+        value = Some(erased_serde::deserialize::<T>(de)?);
+        Ok(())
+    })?;
+    value.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData,
+        "Serializer did not decode any data"))
+}
+
+// Same as deserialize_with for a sequence, but decodes its elements into out
+// instead of a new Vec. Fails if the sequence does not have out.len() elements.
+fn deserialize_into_with<T>(serializer: &dyn Serializer, data: &[u8], limit: u64, out: &mut [T])
+    -> std::io::Result<()>
+    where T: serde::de::DeserializeOwned,
+{
+    serializer.deserialize(data, limit, &mut |de|
+        serde::de::DeserializeSeed::deserialize(SliceSeed(&mut *out), de))
+}
+
+// Decodes the (version, data) pair of send_versioned, but stops after the version
+// if it is not the expected one, as data might have an incompatible layout then
+struct VersionedSeed<'a, T>
+{
+    version: u32,
+    sent_version: &'a mut Option<u32>,
+    value: &'a mut Option<T>,
+}
+
+impl<'de, 'a, T> serde::de::DeserializeSeed<'de> for VersionedSeed<'a, T>
+    where T: serde::de::DeserializeOwned,
+{
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error>
+        where D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_tuple(2, self)
+    }
+}
+
+impl<'de, 'a, T> serde::de::Visitor<'de> for VersionedSeed<'a, T>
+    where T: serde::de::DeserializeOwned,
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "a schema version followed by data")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<(), A::Error>
+        where A: serde::de::SeqAccess<'de>,
+    {
+        let sent: u32 = seq.next_element()?.ok_or_else(||
+            serde::de::Error::invalid_length(0, &self))?;
+        *self.sent_version = Some(sent);
+        if sent != self.version
+        {
+            return Err(serde::de::Error::custom("schema version mismatch"));
+        }
+        *self.value = Some(seq.next_element()?.ok_or_else(||
+            serde::de::Error::invalid_length(1, &self))?);
+        Ok(())
+    }
+}
+
+// Fills a slice with the elements of a serialized sequence
+struct SliceSeed<'a, T>(&'a mut [T]);
+
+impl<'de, 'a, T> serde::de::DeserializeSeed<'de> for SliceSeed<'a, T>
+    where T: serde::de::DeserializeOwned,
+{
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error>
+        where D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a, T> serde::de::Visitor<'de> for SliceSeed<'a, T>
+    where T: serde::de::DeserializeOwned,
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "a sequence of {} elements", self.0.len())
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<(), A::Error>
+        where A: serde::de::SeqAccess<'de>,
+    {
+        let len = self.0.len();
+        let expected = format!("a sequence of {} elements", len);
+        for (i, slot) in self.0.iter_mut().enumerate()
+        {
+            *slot = seq.next_element()?.ok_or_else(||
+                serde::de::Error::invalid_length(i, &expected.as_str()))?;
+        }
+        if seq.next_element::<serde::de::IgnoredAny>()?.is_some()
+        {
+            return Err(serde::de::Error::invalid_length(len + 1, &expected.as_str()));
+        }
+        Ok(())
+    }
+}
+
+
+// Settings of a single send_message call
+#[derive(Clone, Copy)]
+struct SendOptions
+{
+    compression: Option<Compression>,
+    socket: SocketOptions,
+    // Messages of at least this size are striped if there are rails
+    stripe_threshold: u64,
+}
+
+// Messages of at least this many bytes are striped over the rails of a client,
+// unless set with with_stripe_threshold
+pub const DEFAULT_STRIPE_THRESHOLD: u64 = 1 << 20;
+
+// send_chunked only sends this many chunks ahead of the ones receive_chunked took,
+// so neither side holds more than that in memory
+pub const CHUNK_WINDOW: u64 = 4;
+
+// Additional listeners of all clients on further network interfaces, created by
+// with_rails. Large messages are split into fragments that are sent in parallel
+// over the regular stream and one stream to each rail of the destination.
+struct Rails
+{
+    // Addresses of the rail listeners by client id
+    listeners: Vec<Vec<SocketAddr>>,
+    // Open streams by (client id, rail), reused like send_streams
+    streams: Mutex<HashMap<(u32, usize), TcpStream>>,
+    // Numbers the striped messages of this client
+    next_seq: AtomicU64,
+}
+
+// Everything needed to send messages of a client from other threads
+struct ByteSender
+{
+    send_streams: Arc<Mutex<HashMap<u32,TcpStream>>>,
+    client_listeners: Arc<Vec<SocketAddr>>,
+    readers: Arc<(Mutex<MessageQueues>, Condvar)>,
+    id: u32,
+    options: SendOptions,
+    stats: Option<Arc<Mutex<CommStats>>>,
+    rails: Option<Arc<Rails>>,
+    send_seqs: Arc<Mutex<HashMap<(u32,u32),u64>>>,
+}
+
+impl ByteSender
+{
+    fn send(&self, msg: &[u8], dest: u32, id: u32) -> std::io::Result<()>
+    {
+        self.send_seq(msg, dest, id, self.next_seq(dest, id))
+    }
+
+    // Takes the place of the next message to dest with id in the receive order, so
+    // a message sent later from a worker thread is still received in call order
+    fn next_seq(&self, dest: u32, id: u32) -> u64
+    {
+        HeimdallrClient::next_seq(&self.send_seqs, dest, id)
+    }
+
+    fn send_seq(&self, msg: &[u8], dest: u32, id: u32, seq: u64) -> std::io::Result<()>
+    {
+        CommStats::record_send(&self.stats, msg.len());
+        if dest == self.id
+        {
+            HeimdallrClient::deliver_local(&self.readers, self.id, id, seq, Ok(msg.to_vec()));
+            return Ok(());
+        }
+        let dest_addr = self.client_listeners.get(dest as usize).ok_or_else(||
+            std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("Client {} is not part of this job", dest)))?;
+        HeimdallrClient::send_message(&self.send_streams, self.rails.as_deref(), dest_addr,
+            self.id, dest, id, seq, msg, self.options)
+    }
+
+    // Gives seq back if the message taking it could not even be serialized
+    fn skip_seq(&self, dest: u32, id: u32, seq: u64)
+    {
+        if dest == self.id
+        {
+            HeimdallrClient::deliver_local(&self.readers, self.id, id, seq,
+                Err(HeimdallrClient::lost_message(self.id, id, seq)));
+        }
+        else if let Some(dest_addr) = self.client_listeners.get(dest as usize)
+        {
+            HeimdallrClient::report_lost(&self.send_streams, dest_addr, dest,
+                ClientOperationPkt::new(self.id, id, 0, false).with_seq(seq), &self.options.socket);
+        }
+    }
+}
+
+// Messages and payload bytes a client sent and received since with_stats,
+// including the messages of collectives. Bytes are counted before compression.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct CommStats
+{
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    // Time spent blocked in receives waiting for messages to arrive
+    pub receive_wait: Duration,
+}
+
+impl CommStats
+{
+    fn record_send(stats: &Option<Arc<Mutex<CommStats>>>, bytes: usize)
+    {
+        if let Some(stats) = stats
+        {
+            let mut stats = stats.lock().expect("Error in locking 'stats' Mutex");
+            stats.messages_sent += 1;
+            stats.bytes_sent += bytes as u64;
+        }
+    }
+
+    fn record_receive(stats: &Option<Arc<Mutex<CommStats>>>, bytes: usize, waited: Duration)
+    {
+        if let Some(stats) = stats
+        {
+            let mut stats = stats.lock().expect("Error in locking 'stats' Mutex");
+            stats.messages_received += 1;
+            stats.bytes_received += bytes as u64;
+            stats.receive_wait += waited;
+        }
+    }
+}
+
+// Command line arguments of a client. Parsing them only checks that they are complete
+// and well-formed, the daemon is not contacted yet, so launchers can validate them upfront.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientArgs
 {
     pub job: String,
+    pub partition: String,
     pub size: u32,
-    pub id: u32,
-    pub listener: TcpListener,
-    pub client_listeners: Vec<SocketAddr>,
-    readers: Arc<Mutex<HashMap<(u32,u32),SocketAddr>>>,
+    pub node: String,
     pub cmd_args: Vec<String>,
-    daemon_stream: TcpStream,
+    pub interface: String,
+    pub max_message_size: u64,
+    pub daemon_addr: Option<SocketAddr>,
+    pub token: Option<String>,
+    pub connect_timeout: Duration,
 }
 
-impl HeimdallrClient
+impl ClientArgs
 {
-    pub fn init(mut args: std::env::Args) -> Result<HeimdallrClient, &'static str>
+    // The first argument is the job name, like the program name of std::env::args
+    pub fn parse<I>(args: I) -> Result<ClientArgs, HeimdallrError>
+        where I: IntoIterator<Item = String>,
     {
-
+        let mut args = args.into_iter();
         let mut job = match args.next()
         {
             Some(arg) => arg,
@@ -43,7 +611,11 @@ impl HeimdallrClient
         let mut size: u32 = 0;
         let mut node = "".to_string();
         let mut cmd_args = Vec::<String>::new();
-        let mut interface = "".to_string();
+        let mut interface = env::var(INTERFACE_ENV).unwrap_or_default();
+        let mut max_message_size = DEFAULT_MAX_MESSAGE_SIZE;
+        let mut daemon_addr: Option<SocketAddr> = None;
+        let mut token = env::var(TOKEN_ENV).ok();
+        let mut connect_timeout = networking::DEFAULT_CONNECT_TIMEOUT;
 
         while let Some(arg) = args.next()
         {
@@ -54,15 +626,15 @@ impl HeimdallrClient
                     partition = match args.next()
                     {
                         Some(p) => p,
-                        None => return Err("Error in partition argument."),
+                        None => return Err(HeimdallrError::InvalidArg("Error in partition argument.")),
                     };
                 },
                 "-j" | "--jobs" => 
                 {
-                    size = match args.next()
+                    size = match args.next().map(|s| s.parse())
                     {
-                        Some(s) => s.parse().unwrap(),
-                        None => return Err("Error in setting job count."),
+                        Some(Ok(s)) => s,
+                        _ => return Err(HeimdallrError::InvalidArg("Error in setting job count.")),
                     };
                 },
                 "-n" | "--node" => 
@@ -70,7 +642,7 @@ impl HeimdallrClient
                     node = match args.next()
                     {
                         Some(n) => n,
-                        None => return Err("Error in setting node."),
+                        None => return Err(HeimdallrError::InvalidArg("Error in setting node.")),
                     };
                 },
                 "--job-name" =>
@@ -78,7 +650,7 @@ impl HeimdallrClient
                     job = match args.next()
                     {
                         Some(jn) => jn,
-                        None => return Err("Error in setting job-name."),
+                        None => return Err(HeimdallrError::InvalidArg("Error in setting job-name.")),
                     };
                 },
                 "--interface" =>
@@ -86,12 +658,45 @@ impl HeimdallrClient
                     interface = match args.next()
                     {
                         Some(i) => i,
-                        None => return Err("No valid network interface name given."),
+                        None => return Err(HeimdallrError::InvalidArg("No valid network interface name given.")),
+                    }
+                },
+                "--daemon-addr" =>
+                {
+                    daemon_addr = match args.next().map(|a| SocketAddr::from_str(&a))
+                    {
+                        Some(Ok(a)) => Some(a),
+                        _ => return Err(HeimdallrError::InvalidArg("No valid daemon address given.")),
+                    }
+                },
+                "--token" =>
+                {
+                    token = match args.next()
+                    {
+                        Some(t) => Some(t),
+                        None => return Err(HeimdallrError::InvalidArg("No valid token given.")),
+                    }
+                },
+                "--connect-timeout" =>
+                {
+                    // Seconds a daemon or client that is not listening yet is waited for
+                    connect_timeout = match args.next().map(|t| t.parse())
+                    {
+                        Some(Ok(t)) => Duration::from_secs(t),
+                        _ => return Err(HeimdallrError::InvalidArg("No valid connect timeout in seconds given.")),
+                    }
+                },
+                "--max-message-size" =>
+                {
+                    max_message_size = match args.next().map(|m| m.parse())
+                    {
+                        Some(Ok(m)) => m,
+                        _ => return Err(HeimdallrError::InvalidArg("No valid maximum message size in bytes given.")),
                     }
                 },
                 "--args" =>
                 {
-                    while let Some(a) = args.next()
+                    for a in args.by_ref()
                     {
                         cmd_args.push(a);
                     }
@@ -101,72 +706,306 @@ impl HeimdallrClient
             };
         }
 
-        if partition.is_empty() | node.is_empty() | (size == 0)
+        if daemon_addr.is_none()
         {
-            eprintln!("Error: client did not provide all necessary arguments.\n  partition: {}\n  node: {}\n  jobs: {}\nShutting down.", &partition, &node, size);
-            process::exit(1);
+            if let Ok(a) = env::var("HEIMDALLR_DAEMON_ADDR")
+            {
+                daemon_addr = Some(SocketAddr::from_str(&a).map_err(|_|
+                    HeimdallrError::InvalidArg("HEIMDALLR_DAEMON_ADDR is not a valid daemon address."))?);
+            }
         }
 
-        // Find daemon address from daemon config file
-        let home = env::var("HOME").expect("HOME environment variable is not set");
-        let path = format!("{}/.config/heimdallr/{}/{}",home, &partition, &node);
-        let file = File::open(&path)
-            .expect(&format!("Could not open node file for this job at {}", path));
-        let reader = BufReader::new(file);
-        let daemon_config: DaemonConfig = serde_json::from_reader(reader)
-            .expect("Could not parse DaemonConfig file");
-
-        let mut stream = networking::connect(&daemon_config.client_addr)
-            .expect(&format!("Could not connect to daemon at: {}", daemon_config.client_addr));
-
-        // Get IP of this node
-        let mut ip = match local_ipaddress::get()
+        if (daemon_addr.is_none() & (partition.is_empty() | node.is_empty())) | (size == 0)
         {
-            Some(i) => IpAddr::from_str(&i).expect("Error in setting local ip address"),
-            None => IpAddr::from_str("0.0.0.0").expect("Error in setting local ip address"),
-        };
+            return Err(HeimdallrError::MissingArgs);
+        }
 
-        // If specified use the given network interface
-        if !interface.is_empty()
+        // Names the interface is looked up by later, it needs an address to listen on
+        if !interface.is_empty() && IpAddr::from_str(&interface).is_err()
+            && networking::interface_ip(&interface, false).is_none()
         {
-            let interfaces = datalink::interfaces();
-            for i in interfaces
-            {
-                if i.name == interface
-                {
-                    println!("Using specified network interface {} with ip {}",
-                        i.name, i.ips[0]);
-                    ip = i.ips[0].ip();
-                }
-            }
+            return Err(HeimdallrError::InvalidArg("No network interface with the given name and an address found."));
         }
 
-        let listener = networking::bind_listener(&format!("{}:0", ip))
-            .expect("Could not create listener for this client");
-        
-        let client_reg = ClientRegistrationPkt::new(&job, size, listener.local_addr().unwrap());
-        client_reg.send(&mut stream).expect("Could not send ClientRegistrationPkt");
-
-        let reply = ClientRegistrationReplyPkt::receive(&stream)
-            .expect("Error in receiving daemon reply");
-
-        let readers = Arc::new(Mutex::new(HashMap::<(u32,u32),SocketAddr>::new()));
-        
-        let client = HeimdallrClient {job, size, id:reply.id,
-            listener, client_listeners: reply.client_listeners,
-            readers, cmd_args, daemon_stream: stream};
+        Ok(ClientArgs{job, partition, size, node, cmd_args, interface, max_message_size, daemon_addr,
+            token, connect_timeout})
+    }
+}
 
-        // Start listener handler thread that handles incoming connections from other clients
+pub struct HeimdallrClient
+{
+    pub job: String,
+    pub size: u32,
+    pub id: u32,
+    pub listener: TcpListener,
+    pub client_listeners: Vec<SocketAddr>,
+    // Hostnames of all clients by their id
+    pub hostnames: Vec<String>,
+    // Reader threads notify the Condvar after storing a message
+    readers: Arc<(Mutex<MessageQueues>, Condvar)>,
+    // Open streams to other clients by their id, reused for all sends to them
+    send_streams: Arc<Mutex<HashMap<u32,TcpStream>>>,
+    // Messages sent so far by (dest, id), numbers the next message of each pair
+    send_seqs: Arc<Mutex<HashMap<(u32,u32),u64>>>,
+    pub cmd_args: Vec<String>,
+    daemon_stream: Box<dyn Stream>,
+    // Receiving a larger message fails instead of allocating memory for it
+    pub max_message_size: u64,
+    // Wire format of the data sent with send and received with receive,
+    // all clients of a job have to use the same one
+    pub serializer: Arc<dyn Serializer>,
+    // Compression of large outgoing messages, off by default
+    pub compression: Option<Compression>,
+    // Runs the non-blocking operations
+    pool: Arc<WorkerPool>,
+    // Applied to every stream to and from other clients, shared with the listener thread
+    socket_options: Arc<Mutex<SocketOptions>>,
+    // Highest context of the communicators this client is a member of
+    communicators: AtomicU32,
+    // Communication statistics, only collected after with_stats
+    stats: Option<Arc<Mutex<CommStats>>>,
+    // Streams over further network interfaces, only used after with_rails
+    rails: Option<Arc<Rails>>,
+    stripe_threshold: u64,
+    // Non-blocking collectives started so far, each gets its own message id
+    nb_collectives: AtomicU32,
+    // Local time of init and how many seconds after the job start of rank 0 it was
+    wtime_origin: Instant,
+    wtime_offset: f64,
+}
+
+impl HeimdallrClient
+{
+    pub fn init(args: std::env::Args) -> Result<HeimdallrClient, HeimdallrError>
+    {
+        HeimdallrClient::init_with(ClientArgs::parse(args)?)
+    }
+
+    // Same as init with arguments that were parsed before
+    pub fn init_with(args: ClientArgs) -> Result<HeimdallrClient, HeimdallrError>
+    {
+        networking::ignore_sigpipe();
+
+        let ClientArgs{job, partition, size, node, cmd_args, interface, max_message_size,
+            daemon_addr, token, connect_timeout} = args;
+
+        // A given daemon address is used directly, otherwise it is read from
+        // the daemon config file. A daemon that is still starting up has not
+        // written it yet, so it is waited for like the connection itself.
+        let started = Instant::now();
+        let (client_socket, client_addr) = match daemon_addr
+        {
+            Some(a) => (None, a),
+            None =>
+            {
+                let home = env::var("HOME").map_err(|_| HeimdallrError::HomeNotSet)?;
+                let path = Path::new(&home).join(".config/heimdallr").join(&partition).join(&node);
+                let file = networking::retry_connect(connect_timeout, || File::open(&path))
+                    .map_err(|_| HeimdallrError::ConfigNotFound(path.clone()))?;
+                let reader = BufReader::new(file);
+                let daemon_config: DaemonConfig = serde_json::from_reader(reader)
+                    .map_err(HeimdallrError::ConfigParse)?;
+                (daemon_config.client_socket, daemon_config.client_addr)
+            },
+        };
+
+        let mut stream = networking::retry_connect(connect_timeout.saturating_sub(started.elapsed()),
+            || networking::connect_daemon(client_socket.as_deref(), &client_addr))
+            .map_err(HeimdallrError::DaemonConnect)?;
+
+        // Other clients have to reach this one with the same address family as the daemon
+        let ip = networking::resolve_ip(Some(&interface), Some(client_addr.is_ipv6()))?;
+
+        let listener = networking::bind_listener(&SocketAddr::new(ip, 0))?;
+        
+        let hostname = gethostname::gethostname().to_string_lossy().into_owned();
+        let client_reg = ClientRegistrationPkt::new(&job, size, listener.local_addr()?, &hostname,
+            token.as_deref());
+        client_reg.send(&mut stream)?;
+
+        let reply = match ClientRegistrationReplyPkt::receive(&mut stream)
+        {
+            Some(r) => r,
+            None => return Err(HeimdallrError::RegistrationRejected),
+        };
+
+        let readers = Arc::new((Mutex::new(MessageQueues::default()), Condvar::new()));
+        let send_streams = Arc::new(Mutex::new(HashMap::<u32,TcpStream>::new()));
+        
+        let mut client = HeimdallrClient {job, size, id:reply.id,
+            listener, client_listeners: reply.client_listeners, hostnames: reply.hostnames,
+            readers, send_streams, send_seqs: Arc::new(Mutex::new(HashMap::new())),
+            cmd_args, daemon_stream: stream, max_message_size,
+            serializer: Arc::new(BincodeSerializer), compression: None,
+            pool: Arc::new(WorkerPool::new(DEFAULT_NB_WORKERS)),
+            socket_options: Arc::new(Mutex::new(SocketOptions{connect_timeout,
+                ..SocketOptions::default()})),
+            communicators: AtomicU32::new(0), stats: None,
+            rails: None, stripe_threshold: DEFAULT_STRIPE_THRESHOLD, nb_collectives: AtomicU32::new(0),
+            wtime_origin: Instant::now(), wtime_offset: 0.0};
+
+        // Start listener handler thread that handles incoming connections from other clients
         client.listener_handler();
+        HeimdallrClient::watch_gaps(Arc::downgrade(&client.readers));
+
+        client.sync_wtime()?;
 
         Ok(client)
     }
 
+    // Compresses outgoing messages of at least DEFAULT_COMPRESSION_THRESHOLD bytes with
+    // zlib at level 0 to 9. Receivers decompress them without any setting of their own.
+    pub fn with_compression(mut self, level: u32) -> Self
+    {
+        let threshold = self.compression.map_or(DEFAULT_COMPRESSION_THRESHOLD, |c| c.threshold);
+        self.compression = Some(Compression{level, threshold});
+        self
+    }
+
+    // Number of threads running send_nb and ibarrier, DEFAULT_NB_WORKERS otherwise.
+    // Operations beyond that wait for a free thread.
+    pub fn with_nb_workers(mut self, workers: usize) -> Self
+    {
+        self.pool = Arc::new(WorkerPool::new(workers));
+        self
+    }
+
+    // Enables or disables Nagle's algorithm on the streams to other clients, enabled by
+    // default for low latency. send_bulk disables it for a single message either way.
+    pub fn with_nodelay(self, nodelay: bool) -> Self
+    {
+        self.lock_socket_options().nodelay = nodelay;
+        self
+    }
+
+    // Sets SO_SNDBUF and SO_RCVBUF of the streams to other clients, None keeps the OS default.
+    // Only affects streams that are opened afterwards.
+    pub fn with_socket_buffers(self, send_buffer: Option<usize>, recv_buffer: Option<usize>)
+        -> Self
+    {
+        {
+            let mut options = self.lock_socket_options();
+            options.send_buffer = send_buffer;
+            options.recv_buffer = recv_buffer;
+        }
+        self
+    }
+
+    // How long sends retry connecting to a client that is not listening yet
+    pub fn with_connect_timeout(self, timeout: Duration) -> Self
+    {
+        self.lock_socket_options().connect_timeout = timeout;
+        self
+    }
+
+    pub fn socket_options(&self) -> SocketOptions
+    {
+        *self.lock_socket_options()
+    }
+
+    fn lock_socket_options(&self) -> std::sync::MutexGuard<'_, SocketOptions>
+    {
+        self.socket_options.lock().expect("Error in locking 'socket_options' Mutex")
+    }
+
+    // Starts counting the messages and bytes this client sends and receives,
+    // see stats and collectives::print_stats
+    pub fn with_stats(mut self) -> Self
+    {
+        self.stats = Some(Arc::new(Mutex::new(CommStats::default())));
+        self
+    }
+
+    // The statistics collected so far, None without with_stats
+    pub fn stats(&self) -> Option<CommStats>
+    {
+        self.stats.as_ref().map(|s| *s.lock().expect("Error in locking 'stats' Mutex"))
+    }
+
+    // Changes the size from which on messages are compressed, needs with_compression
+    pub fn with_compression_threshold(mut self, threshold: u64) -> Self
+    {
+        if let Some(c) = self.compression.as_mut()
+        {
+            c.threshold = threshold;
+        }
+        self
+    }
+
+    // Stripes messages of at least the stripe threshold over additional streams,
+    // for more bandwidth on nodes with several network interfaces. The client listens
+    // on an address of every given interface and senders split large messages over
+    // the regular stream and these rails. Has to be called by all clients, they can
+    // give different numbers of interfaces. Smaller messages only use the regular stream.
+    pub fn with_rails(mut self, interfaces: &[&str]) -> std::io::Result<Self>
+    {
+        let ipv6 = self.listener.local_addr()?.is_ipv6();
+        let mut addrs = Vec::new();
+        for interface in interfaces
+        {
+            let ip = networking::resolve_ip(Some(interface), Some(ipv6))?;
+            let listener = networking::bind_listener(&SocketAddr::new(ip, 0))?;
+            addrs.push(listener.local_addr()?);
+            HeimdallrClient::accept_connections(listener, Arc::clone(&self.readers),
+                Arc::clone(&self.socket_options));
+        }
+
+        let listeners = self.allgather(&addrs)?;
+        self.rails = Some(Arc::new(Rails{listeners, streams: Mutex::new(HashMap::new()),
+            next_seq: AtomicU64::new(0)}));
+        Ok(self)
+    }
+
+    // Changes the size from which on messages are striped, DEFAULT_STRIPE_THRESHOLD otherwise
+    pub fn with_stripe_threshold(mut self, threshold: u64) -> Self
+    {
+        self.stripe_threshold = threshold;
+        self
+    }
+
+    // Rank 0 broadcasts its wall-clock time as the job start, every client keeps
+    // how far its own origin is from it. Only as exact as the clocks of the nodes agree.
+    fn sync_wtime(&mut self) -> std::io::Result<()>
+    {
+        let unix_secs = || SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64()).unwrap_or(0.0);
+
+        let mut job_start = unix_secs();
+        self.broadcast(&mut job_start, 0)?;
+
+        self.wtime_origin = Instant::now();
+        self.wtime_offset = unix_secs() - job_start;
+        Ok(())
+    }
+
+    // Seconds since the start of the job, with the same origin on all clients
+    pub fn wtime(&self) -> f64
+    {
+        self.wtime_origin.elapsed().as_secs_f64() + self.wtime_offset
+    }
+
+    // Name of the host that client rank runs on
+    pub fn processor_name(&self, rank: u32) -> Option<&str>
+    {
+        self.hostnames.get(rank as usize).map(|h| h.as_str())
+    }
+
+    pub fn self_processor_name(&self) -> &str
+    {
+        &self.hostnames[self.id as usize]
+    }
+
     pub fn listener_handler(&self)
     {
         let listener = self.listener.try_clone().unwrap();
-        let readers = Arc::clone(&self.readers);
+        HeimdallrClient::accept_connections(listener, Arc::clone(&self.readers),
+            Arc::clone(&self.socket_options));
+    }
 
+    // Starts a thread that reads the messages of every connection to listener
+    fn accept_connections(listener: TcpListener, readers: Arc<(Mutex<MessageQueues>, Condvar)>,
+        socket_options: Arc<Mutex<SocketOptions>>)
+    {
         thread::spawn(move || 
         {
             for stream in listener.incoming()
@@ -175,11 +1014,14 @@ impl HeimdallrClient
                 {
                     Ok(stream) =>
                     {
-                        let op_pkt = ClientOperationPkt::receive(&stream);
-                        let mut r = readers.lock().expect("Error in locking 'readers' Mutex");
-                        // TODO check that no such entry already exists and handle
-                        // that case
-                        r.insert((op_pkt.client_id, op_pkt.op_id), op_pkt.addr);
+                        let options = *socket_options.lock()
+                            .expect("Error in locking 'socket_options' Mutex");
+                        if let Err(e) = options.apply(&stream)
+                        {
+                            eprintln!("Error in setting socket options of incoming connection: {}", e);
+                        }
+                        let readers = Arc::clone(&readers);
+                        thread::spawn(move || HeimdallrClient::read_messages(stream, readers));
                     },
                     Err(e) =>
                     {
@@ -190,223 +1032,1286 @@ impl HeimdallrClient
         });
     }
 
+    // Reads all messages another client sends over stream until it is closed
+    fn read_messages(stream: TcpStream, readers: Arc<(Mutex<MessageQueues>, Condvar)>)
+    {
+        // One reader for the whole stream, a new one per message could read ahead
+        // into the next message and lose it
+        let mut reader = BufReader::new(stream);
+        while let Ok(op_pkt) = ClientOperationPkt::receive(&mut reader)
+        {
+            let key = (op_pkt.client_id, op_pkt.op_id);
+            if op_pkt.lost
+            {
+                if let Some(stripe) = op_pkt.stripe
+                {
+                    HeimdallrClient::cancel_stripe(&readers, key.0, stripe.seq);
+                }
+                HeimdallrClient::deliver(&readers, key, op_pkt.seq,
+                    Err(HeimdallrClient::lost_message(key.0, key.1, op_pkt.seq)));
+                continue;
+            }
+            // Later messages keep waiting for this one as long as it takes to arrive
+            if op_pkt.stripe.is_none_or(|s| s.offset == 0)
+            {
+                readers.0.lock().expect("Error in locking 'readers' Mutex")
+                    .in_flight.insert((key.0, key.1, op_pkt.seq));
+            }
+
+            // Grows with the data that actually arrives instead of trusting len upfront
+            let mut msg = Vec::new();
+            match reader.by_ref().take(op_pkt.len).read_to_end(&mut msg)
+            {
+                Ok(n) if n as u64 == op_pkt.len => (),
+                _ =>
+                {
+                    eprintln!("Error in receiving message {} from client {}: connection closed",
+                        op_pkt.op_id, op_pkt.client_id);
+                    match op_pkt.stripe
+                    {
+                        // The reader of the first fragment fails the message
+                        Some(stripe) if stripe.offset != 0 =>
+                            HeimdallrClient::cancel_stripe(&readers, key.0, stripe.seq),
+                        _ => HeimdallrClient::deliver(&readers, key, op_pkt.seq,
+                            Err(HeimdallrClient::lost_message(key.0, key.1, op_pkt.seq))),
+                    }
+                    return;
+                },
+            }
+            if let Some(stripe) = op_pkt.stripe
+            {
+                msg = match HeimdallrClient::assemble(&readers, op_pkt.client_id, stripe, msg)
+                {
+                    Some(Ok(m)) => m,
+                    Some(Err(e)) =>
+                    {
+                        HeimdallrClient::deliver(&readers, key, op_pkt.seq, Err(e));
+                        continue;
+                    },
+                    None => continue,
+                };
+            }
+            if op_pkt.compressed
+            {
+                msg = match networking::decompress(&msg)
+                {
+                    Ok(m) => m,
+                    Err(e) =>
+                    {
+                        eprintln!("Error in decompressing message {} from client {}: {}",
+                            op_pkt.op_id, op_pkt.client_id, e);
+                        HeimdallrClient::deliver(&readers, key, op_pkt.seq, Err(e));
+                        continue;
+                    },
+                };
+            }
+
+            if op_pkt.op_id == collectives::ABORT_ID
+            {
+                let (lock, cvar) = &*readers;
+                lock.lock().expect("Error in locking 'readers' Mutex")
+                    .abort(String::from_utf8_lossy(&msg).into_owned());
+                cvar.notify_all();
+                continue;
+            }
+            HeimdallrClient::deliver(&readers, key, op_pkt.seq, Ok(msg));
+        }
+    }
+
+    // Error in place of a message the sender failed to send or that broke off on the way
+    fn lost_message(src: u32, id: u32, seq: u64) -> std::io::Error
+    {
+        std::io::Error::new(std::io::ErrorKind::ConnectionAborted,
+            format!("Message {} with id {} from client {} was lost on the way", seq, id, src))
+    }
+
+    // Messages with the same (source, id) are received in the order they were sent,
+    // even if they arrive over different streams. So msg is only queued or passed to
+    // the handler of its id once all messages sent before it are.
+    fn deliver(readers: &(Mutex<MessageQueues>, Condvar), key: (u32,u32), seq: u64, msg: Payload)
+    {
+        let mut queues = readers.0.lock().expect("Error in locking 'readers' Mutex");
+        let ready = queues.in_order(key, seq, msg);
+        HeimdallrClient::dispatch(readers, queues, vec![(key, ready)]);
+    }
+
+    // Queues the ready messages of each key or passes them to the handler of its id
+    fn dispatch(readers: &(Mutex<MessageQueues>, Condvar), mut queues: MutexGuard<MessageQueues>,
+        ready: Vec<((u32,u32), Vec<Payload>)>)
+    {
+        let mut handled = Vec::new();
+        for (key, msgs) in ready
+        {
+            match queues.handlers.get(&key.1).cloned()
+            {
+                Some(handler) => handled.push((key, handler, msgs)),
+                None => msgs.into_iter().for_each(|msg| queues.push(key, msg)),
+            }
+        }
+        readers.1.notify_all();
+        drop(queues);
+
+        // Handlers run without the lock, so they can send and receive themselves
+        for (key, handler, msgs) in handled
+        {
+            for msg in msgs
+            {
+                match msg
+                {
+                    Ok(m) => handler(key.0, m),
+                    Err(e) => eprintln!("Error in receiving message with id {} from client {}: {}",
+                        key.1, key.0, e),
+                }
+            }
+        }
+    }
+
+    // Starts a thread that regularly gives up on messages later ones waited too long for,
+    // until the client is dropped
+    fn watch_gaps(readers: Weak<(Mutex<MessageQueues>, Condvar)>)
+    {
+        thread::spawn(move || loop
+        {
+            thread::sleep(Duration::from_secs(1));
+            let Some(readers) = readers.upgrade() else { return };
+            let mut queues = readers.0.lock().expect("Error in locking 'readers' Mutex");
+            let ready = queues.expire_gaps(Instant::now());
+            HeimdallrClient::dispatch(&readers, queues, ready);
+        });
+    }
+
+    // Stores a fragment of a striped message. The reader of the first fragment waits
+    // until all fragments arrived and returns the whole message, so it is queued in the
+    // order it was sent on the regular stream. Returns None for all other fragments.
+    // The first one fails instead if the sender cancelled the message or no fragment
+    // arrived for GAP_TIMEOUT, so its stream is not blocked forever.
+    fn assemble(readers: &(Mutex<MessageQueues>, Condvar), src: u32, stripe: Stripe,
+        fragment: Vec<u8>) -> Option<Payload>
+    {
+        let (lock, cvar) = readers;
+        let mut queues = lock.lock().expect("Error in locking 'readers' Mutex");
+        let key = (src, stripe.seq);
+        if queues.cancelled.contains(&key)
+        {
+            if stripe.offset != 0
+            {
+                return None;
+            }
+            queues.cancelled.remove(&key);
+            queues.stripes.remove(&key);
+            return Some(Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted,
+                format!("Striped message {} from client {} was cancelled", stripe.seq, src))));
+        }
+
+        // Grows with the fragments that arrive instead of trusting total upfront
+        let (data, received) = queues.stripes.entry(key).or_default();
+        let start = stripe.offset as usize;
+        if data.len() < start + fragment.len()
+        {
+            data.resize(start + fragment.len(), 0);
+        }
+        data[start..start + fragment.len()].copy_from_slice(&fragment);
+        *received += fragment.len() as u64;
+
+        if stripe.offset != 0
+        {
+            cvar.notify_all();
+            return None;
+        }
+        let mut progress = (0, Instant::now());
+        loop
+        {
+            let received = queues.stripes[&key].1;
+            let failure = if received >= stripe.total
+            {
+                return queues.stripes.remove(&key).map(|(data, _)| Ok(data));
+            }
+            else if let Some(reason) = &queues.aborted
+            {
+                std::io::Error::other(reason.clone())
+            }
+            else if queues.cancelled.remove(&key)
+            {
+                std::io::Error::new(std::io::ErrorKind::ConnectionAborted,
+                    format!("Striped message {} from client {} was cancelled", stripe.seq, src))
+            }
+            else
+            {
+                if received != progress.0
+                {
+                    progress = (received, Instant::now());
+                }
+                let left = GAP_TIMEOUT.saturating_sub(progress.1.elapsed());
+                if !left.is_zero()
+                {
+                    queues = cvar.wait_timeout(queues, left)
+                        .expect("Error in locking 'readers' Mutex").0;
+                    continue;
+                }
+                std::io::Error::new(std::io::ErrorKind::TimedOut,
+                    format!("Fragments of striped message {} from client {} did not arrive \
+                        within {} seconds", stripe.seq, src, GAP_TIMEOUT.as_secs()))
+            };
+            queues.stripes.remove(&key);
+            return Some(Err(failure));
+        }
+    }
+
+    // Drops the fragments of a striped message the sender failed to send,
+    // and the ones that still arrive later
+    fn cancel_stripe(readers: &(Mutex<MessageQueues>, Condvar), src: u32, seq: u64)
+    {
+        let mut queues = readers.0.lock().expect("Error in locking 'readers' Mutex");
+        if let Some((data, _)) = queues.stripes.get_mut(&(src, seq))
+        {
+            *data = Vec::new();
+        }
+        queues.cancelled.insert((src, seq));
+        readers.1.notify_all();
+    }
+
+    // Stores a message this client sends to itself directly in its own queues,
+    // without a stream to its own listener
+    fn deliver_local(readers: &(Mutex<MessageQueues>, Condvar), src: u32, id: u32, seq: u64,
+        msg: Payload)
+    {
+        HeimdallrClient::deliver(readers, (src, id), seq, msg);
+    }
+
+    // Sequence number of the next message to dest with id
+    fn next_seq(send_seqs: &Mutex<HashMap<(u32,u32),u64>>, dest: u32, id: u32) -> u64
+    {
+        let mut seqs = send_seqs.lock().expect("Error in locking 'send_seqs' Mutex");
+        let next = seqs.entry((dest, id)).or_insert(0);
+        *next += 1;
+        *next - 1
+    }
+
+    // Passes all messages with id to handler from now on, until remove_handler
+    fn set_handler(&self, id: u32, handler: MessageHandler)
+    {
+        self.readers.0.lock().expect("Error in locking 'readers' Mutex")
+            .handlers.insert(id, handler);
+    }
+
+    fn remove_handler(&self, id: u32)
+    {
+        self.readers.0.lock().expect("Error in locking 'readers' Mutex")
+            .handlers.remove(&id);
+    }
+
+    // Sends with the current settings of this client without borrowing it,
+    // e.g. from non-blocking operations and message handlers
+    fn byte_sender(&self) -> ByteSender
+    {
+        ByteSender{send_streams: Arc::clone(&self.send_streams),
+            client_listeners: Arc::new(self.client_listeners.clone()),
+            readers: Arc::clone(&self.readers), id: self.id,
+            options: SendOptions{compression: self.compression, socket: self.socket_options(),
+                stripe_threshold: self.stripe_threshold},
+            stats: self.stats.clone(), rails: self.rails.clone(),
+            send_seqs: Arc::clone(&self.send_seqs)}
+    }
+
+    // Writes msg with a ClientOperationPkt header to the stream to dest_addr, or stripes
+    // it over the rails of dest if it is large enough.
+    #[allow(clippy::too_many_arguments)]
+    fn send_message(send_streams: &Mutex<HashMap<u32,TcpStream>>, rails: Option<&Rails>,
+        dest_addr: &SocketAddr, src: u32, dest: u32, id: u32, seq: u64, msg: &[u8],
+        options: SendOptions) -> std::io::Result<()>
+    {
+        let mut header = ClientOperationPkt::new(src, id, 0, false).with_seq(seq);
+        let mut send = ||
+        {
+            let compressed = match options.compression
+            {
+                Some(c) if msg.len() as u64 >= c.threshold => Some(networking::compress(msg, c.level)?),
+                _ => None,
+            };
+            let msg = compressed.as_deref().unwrap_or(msg);
+            header.len = msg.len() as u64;
+            header.compressed = compressed.is_some();
+
+            match rails
+            {
+                Some(r) if !msg.is_empty() && (msg.len() as u64 >= options.stripe_threshold)
+                    && r.listeners.get(dest as usize).is_some_and(|l| !l.is_empty()) =>
+                {
+                    header.stripe = Some(Stripe{seq: r.next_seq.fetch_add(1, Ordering::Relaxed),
+                        offset: 0, total: header.len});
+                    HeimdallrClient::send_striped(send_streams, r, dest_addr, dest, header, msg,
+                        &options.socket)
+                },
+                _ => HeimdallrClient::write_message(send_streams, dest, dest_addr, header, msg,
+                    &options.socket),
+            }
+        };
+
+        let result = send();
+        if result.is_err()
+        {
+            HeimdallrClient::report_lost(send_streams, dest_addr, dest, header, &options.socket);
+        }
+        result
+    }
+
+    // Tells dest that the message with header failed, so it does not hold back the later
+    // messages with its id until it arrives, and drops the fragments of a striped one.
+    // Goes over a new stream, as the failed one was dropped.
+    fn report_lost(send_streams: &Mutex<HashMap<u32,TcpStream>>, dest_addr: &SocketAddr,
+        dest: u32, failed: ClientOperationPkt, socket: &SocketOptions)
+    {
+        let notice = ClientOperationPkt{len: 0, compressed: false, ..failed}.lost();
+        if let Err(e) = HeimdallrClient::write_message(send_streams, dest, dest_addr, notice, &[],
+            socket)
+        {
+            eprintln!("Error in reporting lost message {} with id {} to client {}: {}",
+                failed.seq, failed.op_id, dest, e);
+        }
+    }
+
+    // Splits msg into one fragment per stream to dest and writes them in parallel.
+    // The first fragment goes over the regular stream, the others over the rails.
+    // If any of them fails, the regular stream is dropped as well, as the receiver may
+    // still wait for the message on it.
+    fn send_striped(send_streams: &Mutex<HashMap<u32,TcpStream>>, rails: &Rails,
+        dest_addr: &SocketAddr, dest: u32, header: ClientOperationPkt, msg: &[u8],
+        socket: &SocketOptions) -> std::io::Result<()>
+    {
+        let rail_addrs = &rails.listeners[dest as usize];
+        let chunk = (msg.len() + rail_addrs.len()) / (rail_addrs.len() + 1);
+        let stripe = header.stripe.expect("Striped messages have a stripe");
+        let fragment = |i: usize, data: &[u8]| ClientOperationPkt{len: data.len() as u64,
+            stripe: Some(Stripe{offset: (i * chunk) as u64, ..stripe}), ..header};
+
+        let mut fragments = msg.chunks(chunk).enumerate();
+        let (_, first) = fragments.next().expect("Striped messages are not empty");
+        thread::scope(|scope|
+        {
+            let handles: Vec<_> = fragments.map(|(i, data)| scope.spawn(move ||
+                HeimdallrClient::write_message(&rails.streams, (dest, i - 1), &rail_addrs[i - 1],
+                    fragment(i, data), data, socket))).collect();
+
+            let mut result = HeimdallrClient::write_message(send_streams, dest, dest_addr,
+                fragment(0, first), first, socket);
+            for handle in handles
+            {
+                let sent = handle.join().unwrap_or_else(|_|
+                    Err(std::io::Error::other("Thread sending a fragment panicked")));
+                result = result.and(sent);
+            }
+            if result.is_err()
+            {
+                send_streams.lock().expect("Error in locking 'send_streams' Mutex").remove(&dest);
+            }
+            result
+        })
+    }
+
+    // Writes payload with header to the stream of key in streams, which is opened to
+    // addr on the first send and kept for later ones.
+    fn write_message<K>(streams: &Mutex<HashMap<K,TcpStream>>, key: K, addr: &SocketAddr,
+        header: ClientOperationPkt, payload: &[u8], socket: &SocketOptions) -> std::io::Result<()>
+        where K: std::hash::Hash + Eq + Copy,
+    {
+        // Taken out of the map while writing, so concurrent messages to dest
+        // can not interleave. These simply open another stream.
+        let cached = streams.lock().expect("Error in locking 'send_streams' Mutex")
+            .remove(&key);
+        // Cached streams may still have the nodelay setting of a previous send_bulk
+        let mut stream = match cached
+        {
+            Some(s) =>
+            {
+                s.set_nodelay(socket.nodelay)?;
+                s
+            },
+            None => networking::connect_with(addr, socket)?,
+        };
+
+        // Header and small payloads go out in a single write
+        let mut writer = BufWriter::new(&mut stream);
+        header.send(&mut writer)?;
+        writer.write_all(payload)?;
+        writer.flush()?;
+        drop(writer);
+
+        // Streams with a failed write are dropped and reopened on the next send
+        streams.lock().expect("Error in locking 'send_streams' Mutex")
+            .entry(key).or_insert(stream);
+        Ok(())
+    }
+
+    // Waits for the first message whose (source, id) satisfies matches and
+    // returns its key together with its payload
+    fn take_matching_message<F>(&self, matches: F) -> std::io::Result<((u32, u32), Vec<u8>)>
+        where F: Fn(&(u32, u32)) -> bool,
+    {
+        let start = Instant::now();
+        let (key, msg) = wait_for_message(&self.readers, None, |r|
+            r.oldest_matching(&matches).map(|k| (k, r.pop(&k).unwrap())))?
+            .expect("Waiting for a message without deadline can not time out");
+        let msg = msg?;
+        CommStats::record_receive(&self.stats, msg.len(), start.elapsed());
+        Ok((key, msg))
+    }
+
+    // Waits for the message with id from source and returns its payload
+    fn take_message(&self, source: u32, id: u32) -> std::io::Result<Vec<u8>>
+    {
+        Ok(self.take_message_until(source, id, None)?
+            .expect("Waiting for a message without deadline can not time out"))
+    }
+
+    // Same as take_message, but gives up and returns None once deadline passed
+    fn take_message_until(&self, source: u32, id: u32, deadline: Option<Instant>)
+        -> std::io::Result<Option<Vec<u8>>>
+    {
+        let start = Instant::now();
+        let msg = wait_for_message(&self.readers, deadline, |r| r.pop(&(source,id)))?
+            .transpose()?;
+        if let Some(m) = &msg
+        {
+            CommStats::record_receive(&self.stats, m.len(), start.elapsed());
+        }
+        Ok(msg)
+    }
+
     pub fn send<T>(&self, data: &T, dest: u32, id: u32) -> std::io::Result<()>
         where T: Serialize,
     {
-        let mut stream = networking::connect(self.client_listeners.get(dest as usize).unwrap())?;
+        let msg = serialize_with(&*self.serializer, data)?;
+        self.send_bytes(&msg, dest, id)
+    }
+
+    // Sends data as it is, without serializing it. The typed sends use this for their
+    // serialized data, so e.g. already serialized buffers can skip another copy.
+    // Empty data is still framed as a message, so it completes one receive.
+    pub fn send_bytes(&self, data: &[u8], dest: u32, id: u32) -> std::io::Result<()>
+    {
+        self.send_bytes_with(data, dest, id, self.socket_options())
+    }
+
+    // Sends a large message with Nagle's algorithm disabled on the stream, so its data
+    // fills full segments. The next regular send restores the nodelay setting.
+    pub fn send_bulk<T>(&self, data: &T, dest: u32, id: u32) -> std::io::Result<()>
+        where T: Serialize,
+    {
+        let msg = serialize_with(&*self.serializer, data)?;
+        let options = SocketOptions{nodelay: false, ..self.socket_options()};
+        self.send_bytes_with(&msg, dest, id, options)
+    }
+
+    fn send_bytes_with(&self, data: &[u8], dest: u32, id: u32, socket: SocketOptions)
+        -> std::io::Result<()>
+    {
+        // Checked before taking a sequence number, which would wait for a message then
+        let dest_addr = self.client_listeners.get(dest as usize).ok_or_else(||
+            std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("Client {} is not part of this job", dest)))?;
+
+        CommStats::record_send(&self.stats, data.len());
+        let seq = HeimdallrClient::next_seq(&self.send_seqs, dest, id);
+        if dest == self.id
+        {
+            HeimdallrClient::deliver_local(&self.readers, self.id, id, seq, Ok(data.to_vec()));
+            return Ok(());
+        }
+
+        HeimdallrClient::send_message(&self.send_streams, self.rails.as_deref(), dest_addr,
+            self.id, dest, id, seq, data, SendOptions{compression: self.compression, socket,
+                stripe_threshold: self.stripe_threshold})
+    }
+
+    // Sends data to the client computed by route from the id of this client,
+    // e.g. |r| (r+1) % size for a cyclic shift
+    pub fn send_routed<T, F>(&self, data: &T, route: F, id: u32) -> std::io::Result<()>
+        where T: Serialize, F: Fn(u32) -> u32,
+    {
+        let dest = route(self.id);
+        if dest >= self.size
+        {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("Route of client {} leads to client {} outside of job with size {}",
+                    self.id, dest, self.size)));
+        }
+
+        self.send(data, dest, id)
+    }
+
+    // An empty slice is sent as a message like any other and received as an empty Vec
+    pub fn send_slice<T>(&self, data: &[T], dest: u32, id: u32) -> std::io::Result<()>
+        where T: Serialize,
+    {
+        let msg = serialize_with(&*self.serializer, data)?;
+        self.send_bytes(&msg, dest, id)
+    }
+
+    // Sends data prefixed with a user defined schema version.
+    // Has to be received with receive_versioned.
+    pub fn send_versioned<T>(&self, data: &T, version: u32, dest: u32, id: u32)
+        -> std::io::Result<()>
+        where T: Serialize,
+    {
+        let msg = serialize_with(&*self.serializer, &(version, data))?;
+        self.send_bytes(&msg, dest, id)
+    }
+
+    // Decodes a received message with the serializer of this client
+    fn deserialize<T>(&self, msg: &[u8]) -> std::io::Result<T>
+        where T: serde::de::DeserializeOwned,
+    {
+        deserialize_with(&*self.serializer, msg, self.max_message_size)
+    }
+
+    // Receives the payload of a message without deserializing it, counterpart of send_bytes
+    pub fn receive_bytes(&self, source: u32, id: u32) -> std::io::Result<Vec<u8>>
+    {
+        self.take_message(source, id)
+    }
+
+    // Same as receive_bytes, but copies the payload into buf, so a buffer that is
+    // reused for every message keeps its capacity
+    pub fn receive_into(&self, source: u32, id: u32, buf: &mut Vec<u8>) -> std::io::Result<()>
+    {
+        let msg = self.receive_bytes(source, id)?;
+        buf.clear();
+        buf.extend_from_slice(&msg);
+        Ok(())
+    }
+
+    pub fn receive<T>(&self, source: u32, id: u32) -> std::io::Result<T>
+        where T: serde::de::DeserializeOwned,
+    {
+        let msg = self.receive_bytes(source, id)?;
+        self.deserialize(&msg)
+    }
+
+    // Receives data sent with send_slice (or a Vec sent with send) directly into out,
+    // without allocating a Vec for it. Fails with an InvalidData error if the sent
+    // sequence does not have exactly out.len() elements.
+    pub fn receive_slice_into<T>(&self, source: u32, id: u32, out: &mut [T])
+        -> std::io::Result<()>
+        where T: serde::de::DeserializeOwned,
+    {
+        let msg = self.receive_bytes(source, id)?;
+        deserialize_into_with(&*self.serializer, &msg, self.max_message_size, out)
+    }
+
+    // Sends data as a series of messages with up to chunk_len elements each, so only one
+    // chunk is serialized at a time instead of the whole slice. The first message holds
+    // the total number of elements and chunks. progress is called after every chunk with
+    // the number of elements sent so far and in total. Has to be received with
+    // receive_chunked, the chunks are received in order as they share id. At most
+    // CHUNK_WINDOW chunks are sent before the receiver took them, every further chunk
+    // waits for a credit the receiver sends back with CHUNK_CREDIT_ID. Two clients
+    // sending chunked to each other therefore have to receive at the same time, e.g.
+    // in another thread. Chunks sent to this client itself need no credits.
+    pub fn send_chunked<T>(&self, data: &[T], dest: u32, id: u32, chunk_len: usize,
+        mut progress: Option<&mut dyn FnMut(usize, usize)>) -> std::io::Result<()>
+        where T: Serialize,
+    {
+        if chunk_len == 0
+        {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "send_chunked needs at least one element per chunk"));
+        }
+
+        let chunks = data.len().div_ceil(chunk_len);
+        self.send(&(data.len() as u64, chunks as u64), dest, id)?;
+
+        let mut sent = 0;
+        for (i, chunk) in data.chunks(chunk_len).enumerate()
+        {
+            if (i as u64 >= CHUNK_WINDOW) & (dest != self.id)
+            {
+                wait_for_message(&self.readers, None,
+                    |r| r.take_credit((dest, collectives::CHUNK_CREDIT_ID), id))?
+                    .expect("Waiting for a message without deadline can not time out")?;
+            }
+            self.send_slice(chunk, dest, id)?;
+            sent += chunk.len();
+            if let Some(p) = progress.as_mut()
+            {
+                p(sent, data.len());
+            }
+        }
+        Ok(())
+    }
+
+    // Receives data sent with send_chunked, decoding every chunk as it is taken.
+    // progress is called after every chunk with the number of elements received so far
+    // and in total. Every taken chunk gives the sender a credit for the next one.
+    // If the chunks have more elements than announced, the remaining chunks are still
+    // taken and dropped before failing, so none are left behind under id.
+    pub fn receive_chunked<T>(&self, source: u32, id: u32,
+        mut progress: Option<&mut dyn FnMut(usize, usize)>) -> std::io::Result<Vec<T>>
+        where T: serde::de::DeserializeOwned,
+    {
+        let (total, chunks): (u64, u64) = self.receive(source, id)?;
+        let total = total as usize;
+
+        // The announced total is not trusted for more than max_message_size upfront
+        let limit = self.max_message_size as usize / std::mem::size_of::<T>().max(1);
+        let mut data = Vec::with_capacity(total.min(limit));
+        let mut received = 0;
+        for i in 0..chunks
+        {
+            let chunk: Vec<T> = self.receive(source, id)?;
+            if (i + CHUNK_WINDOW < chunks) & (source != self.id)
+            {
+                self.send_bytes(&id.to_le_bytes(), source, collectives::CHUNK_CREDIT_ID)?;
+            }
+
+            received += chunk.len();
+            if received > total
+            {
+                data = Vec::new();
+                continue;
+            }
+            data.extend(chunk);
+            if let Some(p) = progress.as_mut()
+            {
+                p(data.len(), total);
+            }
+        }
+
+        match received == total
+        {
+            true => Ok(data),
+            false => Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                format!("Chunked message {} from client {} announced {} elements, but had {}",
+                    id, source, total, received))),
+        }
+    }
+
+    // Same as receive, but returns None if the message did not arrive within timeout
+    pub fn receive_timeout<T>(&self, source: u32, id: u32, timeout: Duration)
+        -> std::io::Result<Option<T>>
+        where T: serde::de::DeserializeOwned,
+    {
+        match self.take_message_until(source, id, Some(Instant::now() + timeout))?
+        {
+            Some(msg) => self.deserialize(&msg).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Receives data sent with send_versioned.
+    // Fails with an InvalidData error if the sender used a different schema version
+    // instead of trying to deserialize data of an incompatible layout.
+    pub fn receive_versioned<T>(&self, source: u32, id: u32, version: u32)
+        -> std::io::Result<T>
+        where T: serde::de::DeserializeOwned,
+    {
+        let msg = self.receive_bytes(source, id)?;
+        let mut sent_version = None;
+        let mut value = None;
+        let decoded = self.serializer.deserialize(&msg, self.max_message_size, &mut |de|
+            serde::de::DeserializeSeed::deserialize(VersionedSeed{version,
+                sent_version: &mut sent_version, value: &mut value}, de));
+
+        match sent_version
+        {
+            Some(sent) if sent != version =>
+                Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                    format!("schema version mismatch (sent v{}, expected v{})", sent, version))),
+            _ =>
+            {
+                decoded?;
+                value.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData,
+                    "Serializer did not decode any data"))
+            },
+        }
+    }
+
+    // Sends data to dest and receives from source,
+    // so e.g. two neighbours can swap data without ordering their send and receive calls.
+    // The send does not wait for a matching receive, as the reader threads of the
+    // destination take every message off its connection.
+    pub fn sendrecv<S, R>(&self, send_data: &S, dest: u32, send_id: u32,
+        source: u32, recv_id: u32) -> std::io::Result<R>
+        where S: Serialize + ?Sized, R: serde::de::DeserializeOwned,
+    {
+        let msg = serialize_with(&*self.serializer, send_data)?;
+        self.send_bytes(&msg, dest, send_id)?;
+        self.receive(source, recv_id)
+    }
+
+    // Waits until a message for any of the (source, id) candidates arrived and
+    // returns the index of the matching candidate together with the data.
+    // If several candidates are pending, the one with the lowest index is taken.
+    pub fn receive_first_of<T>(&self, candidates: &[(u32, u32)]) -> std::io::Result<(usize, T)>
+        where T: serde::de::DeserializeOwned,
+    {
+        if candidates.is_empty()
+        {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "receive_first_of needs at least one candidate"));
+        }
+
+        let start = Instant::now();
+        let (i, msg) = wait_for_message(&self.readers, None, |r|
+            candidates.iter().enumerate().find_map(|(i, c)| r.pop(c).map(|m| (i, m))))?
+            .expect("Waiting for a message without deadline can not time out");
+        let msg = msg?;
+        CommStats::record_receive(&self.stats, msg.len(), start.elapsed());
+
+        let data: T = self.deserialize(&msg)?;
+        Ok((i, data))
+    }
+
+    pub fn receive_any_source<T>(&self, id: u32) -> std::io::Result<T>
+        where T: serde::de::DeserializeOwned,
+    {
+        let (_, msg) = self.take_matching_message(|k| k.1 == id)?;
+        self.deserialize(&msg)
+    }
+
+    // Receives the next message from source with any id and returns its id with the data
+    pub fn receive_any_tag<T>(&self, source: u32) -> std::io::Result<(u32, T)>
+        where T: serde::de::DeserializeOwned,
+    {
+        let (_, id, data) = self.receive_any(source, ANY_TAG)?;
+        Ok((id, data))
+    }
+
+    // Receives the oldest message matching source and id, either of which can be
+    // ANY_SOURCE or ANY_TAG, and returns its source and id together with the data.
+    // ANY_TAG only matches the ids of the application below COMM_ID_START, so
+    // messages of collectives and communicators are left for them.
+    pub fn receive_any<T>(&self, source: u32, id: u32) -> std::io::Result<(u32, u32, T)>
+        where T: serde::de::DeserializeOwned,
+    {
+        let ((source, id), msg) = self.take_any_message(source, id)?;
+        Ok((source, id, self.deserialize(&msg)?))
+    }
+
+    // Receives a sequence sent with send_slice (or a Vec sent with send) of a length the
+    // receiver does not know upfront. source and id can be wildcards like for receive_any,
+    // the Status tells which message was matched and how many elements it had.
+    pub fn receive_status<T>(&self, source: u32, id: u32) -> std::io::Result<(Vec<T>, Status)>
+        where T: serde::de::DeserializeOwned,
+    {
+        let ((source, tag), msg) = self.take_any_message(source, id)?;
+        let data: Vec<T> = self.deserialize(&msg)?;
+        let status = Status{source, tag, count: data.len()};
+        Ok((data, status))
+    }
+
+    fn take_any_message(&self, source: u32, id: u32) -> std::io::Result<((u32, u32), Vec<u8>)>
+    {
+        self.take_matching_message(|k| ((source == ANY_SOURCE) | (k.0 == source))
+            && (((id == ANY_TAG) & (k.1 < communicator::COMM_ID_START)) | (k.1 == id)))
+    }
+
+    // Checks without blocking whether a message with id from source has arrived.
+    // The message stays queued for the next receive.
+    pub fn probe(&self, source: u32, id: u32) -> bool
+    {
+        let r = self.readers.0.lock().expect("Could not lock 'readers' Mutex");
+        r.queues.contains_key(&(source, id))
+    }
+
+    // Same as probe for messages with id from any source, returns the source of
+    // the message that receive_any_source would take next
+    pub fn probe_any_source(&self, id: u32) -> Option<u32>
+    {
+        let r = self.readers.0.lock().expect("Could not lock 'readers' Mutex");
+        r.oldest_matching(|k| k.1 == id).map(|(source, _)| source)
+    }
+
+
+    pub fn send_nb<T>(&self, data: T, dest: u32, id: u32) 
+        -> std::io::Result<NbDataHandle<std::io::Result<T>>>
+        where T: Serialize + std::marker::Send + 'static
+    {
+        let sender = self.byte_sender();
+        let serializer = Arc::clone(&self.serializer);
+        let seq = sender.next_seq(dest, id);
+        let (handle, completion) = NbDataHandle::new();
+        self.pool.execute(move || completion.complete((|| 
+            {
+                let msg = serialize_with(&*serializer, &data).inspect_err(|_|
+                    sender.skip_seq(dest, id, seq))?;
+                sender.send_seq(&msg, dest, id, seq)?;
+                Ok(data)
+            })()));
+
+        Ok(handle)
+    }
+
+
+    pub fn receive_nb<T>(&self, source: u32, id: u32) 
+        -> std::io::Result<NbDataHandle<std::io::Result<T>>>
+        where T: serde::de::DeserializeOwned + std::marker::Send + 'static,
+    {
+        let max_message_size = self.max_message_size;
+        let serializer = Arc::clone(&self.serializer);
+        let pool = Arc::clone(&self.pool);
+        let stats = self.stats.clone();
+
+        // Only decoding the message needs a worker, waiting for it does not.
+        // Nothing blocks here, so no receive wait time is counted.
+        let (handle, completion) = NbDataHandle::new();
+        let receive = move |msg: std::io::Result<Vec<u8>>|
+        {
+            if let Ok(m) = &msg
+            {
+                CommStats::record_receive(&stats, m.len(), Duration::ZERO);
+            }
+            pool.execute(move || completion.complete(msg.and_then(|m|
+                deserialize_with(&*serializer, &m, max_message_size))))
+        };
+
+        self.readers.0.lock().expect("Could not lock 'readers' Mutex")
+            .post((source, id), Box::new(receive));
+
+        Ok(handle)
+    }
+
+
+    // Persistent send of messages with id to dest, see PersistentSend
+    pub fn send_init<T>(&self, dest: u32, id: u32) -> std::io::Result<PersistentSend<'_, T>>
+        where T: Serialize + std::marker::Send + 'static,
+    {
+        self.open_stream(dest)?;
+        Ok(PersistentSend{client: self, dest, id, active: None})
+    }
+
+    // Persistent receive of messages with id from source, see PersistentReceive
+    pub fn recv_init<T>(&self, source: u32, id: u32) -> std::io::Result<PersistentReceive<'_, T>>
+        where T: serde::de::DeserializeOwned + std::marker::Send + 'static,
+    {
+        if source >= self.size
+        {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("Client {} is not part of this job", source)));
+        }
+        Ok(PersistentReceive{client: self, source, id, active: None})
+    }
+
+    // Opens the stream to dest that sends reuse, unless there is one already
+    fn open_stream(&self, dest: u32) -> std::io::Result<()>
+    {
+        let dest_addr = self.client_listeners.get(dest as usize).ok_or_else(||
+            std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("Client {} is not part of this job", dest)))?;
+        if dest == self.id
+            || self.send_streams.lock().expect("Error in locking 'send_streams' Mutex")
+                .contains_key(&dest)
+        {
+            return Ok(());
+        }
+
+        let stream = networking::connect_with(dest_addr, &self.socket_options())?;
+        self.send_streams.lock().expect("Error in locking 'send_streams' Mutex")
+            .entry(dest).or_insert(stream);
+        Ok(())
+    }
+
+    pub fn create_mutex<T>(&mut self, name: &str, start_data: T) 
+        -> std::io::Result<HeimdallrMutex<T>>
+        where T: Serialize
+    {
+        HeimdallrMutex::<T>::new(self, name, start_data)
+    }
+
+    pub fn create_rwlock<T>(&mut self, name: &str, start_data: T) 
+        -> std::io::Result<HeimdallrRwLock<T>>
+        where T: Serialize
+    {
+        HeimdallrRwLock::<T>::new(self, name, start_data)
+    }
+
+    pub fn create_semaphore(&mut self, name: &str, permits: u32)
+        -> std::io::Result<HeimdallrSemaphore>
+    {
+        HeimdallrSemaphore::new(self, name, permits)
+    }
+
+
+    pub fn barrier(&mut self) -> std::io::Result<()>
+    {
+        self.named_barrier(GLOBAL_BARRIER, self.size)
+    }
+
+    // Barrier that only the size clients using the same name take part in.
+    // Each named barrier completes and resets independently of the others.
+    pub fn named_barrier(&mut self, name: &str, size: u32) -> std::io::Result<()>
+    {
+        let pkt = BarrierPkt::new(name, self.id, size, &self.job);
+        pkt.send(&mut self.daemon_stream)?;
+        BarrierReplyPkt::receive(&mut self.daemon_stream).ok_or_else(||
+            std::io::Error::other("Could not receive BarrierReplyPkt"))?;
+        Ok(())
+    }
+
+    // Atomically adds delta to the integer called name on the daemon and returns its
+    // previous value. Integers are shared by all clients of the job and start at 0.
+    pub fn fetch_add(&mut self, name: &str, delta: i64) -> std::io::Result<i64>
+    {
+        let pkt = FetchAddPkt::new(name, self.id, delta, &self.job);
+        pkt.send(&mut self.daemon_stream)?;
+        let reply = AtomicReplyPkt::receive(&mut self.daemon_stream).ok_or_else(||
+            std::io::Error::other("Could not receive AtomicReplyPkt"))?;
+        Ok(reply.value)
+    }
+
+    // Atomically sets the integer called name on the daemon to new if it is expected.
+    // Returns the value it had before, so the swap happened if that equals expected.
+    // Works on the same integers as fetch_add.
+    pub fn compare_and_swap(&mut self, name: &str, expected: i64, new: i64)
+        -> std::io::Result<i64>
+    {
+        let pkt = CompareAndSwapPkt::new(name, self.id, expected, new, &self.job);
+        pkt.send(&mut self.daemon_stream)?;
+        let reply = AtomicReplyPkt::receive(&mut self.daemon_stream).ok_or_else(||
+            std::io::Error::other("Could not receive AtomicReplyPkt"))?;
+        Ok(reply.value)
+    }
+
+    // Non-blocking barrier, the returned handle completes once all clients reached it.
+    // Point-to-point communication can continue in the meantime, but other operations
+    // on the daemon (mutexes, barriers) have to wait until data() of the handle returned.
+    pub fn ibarrier(&mut self) -> std::io::Result<NbDataHandle<std::io::Result<()>>>
+    {
+        let pkt = BarrierPkt::new(GLOBAL_BARRIER, self.id, self.size, &self.job);
+        pkt.send(&mut self.daemon_stream)?;
+
+        let mut stream = self.daemon_stream.try_clone_stream()?;
+        let (handle, completion) = NbDataHandle::new();
+        self.pool.execute(move || completion.complete(
+            BarrierReplyPkt::receive(&mut stream).map(|_| ()).ok_or_else(||
+                std::io::Error::other("Could not receive BarrierReplyPkt"))));
+
+        Ok(handle)
+    }
+
+    // Stops the whole job after a fatal error on this client, like MPI_Abort.
+    // The daemon closes the connections of all clients of the job, so their blocking
+    // calls fail instead of waiting for this client. Exits the process with code.
+    pub fn abort(&mut self, code: i32) -> !
+    {
+        let abort_pkt = AbortPkt::new(self.id, code, &self.job);
+        if let Err(e) = abort_pkt.send(&mut self.daemon_stream)
+        {
+            eprintln!("Error: Could not send abort of job {} to the daemon: {}", self.job, e);
+        }
+        std::process::exit(code)
+    }
+
+    // Tells the daemon that this client is done, called when the client is dropped
+    fn finalize(&mut self) -> std::io::Result<()>
+    {
+        let finalize_pkt = FinalizePkt::new(self.id, self.size, &self.job);
+        finalize_pkt.send(&mut self.daemon_stream)?;
+        self.daemon_stream.flush()?;
+
+        match DaemonReplyPkt::receive(&mut self.daemon_stream)?
+        {
+            DaemonReplyPkt::FinalizeReply(_) => Ok(()),
+            DaemonReplyPkt::Error(e) => Err(std::io::Error::other(e.msg)),
+            _ => Err(std::io::Error::other("Unexpected reply to FinalizePkt")),
+        }
+    }
+}
+
+impl fmt::Display for HeimdallrClient
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "HeimdallClient:\n  Job: {}\n  Size: {}\n  Client id: {}",
+            self.job, self.size, self.id)
+    }
+}
+
+impl Drop for HeimdallrClient
+{
+    fn drop(&mut self)
+    {
+        // let mut stream = networking::connect(&self.daemon_addr)
+        //     .expect("Could not connect to daemin in finalization procedure of HeimdallrClient");
+
+        // Panicking in drop could abort the process, so a daemon that went away
+        // is only reported
+        if let Err(e) = self.finalize()
+        {
+            eprintln!("Warning: Could not finalize client {} of job {}: {}", self.id, self.job, e);
+        }
+    }
+}
+
+
+
+// Threads of a client running non-blocking operations. Operations are queued
+// until a thread is free, so posting many of them does not start as many threads.
+pub struct WorkerPool
+{
+    jobs: Mutex<mpsc::Sender<Box<dyn FnOnce() + std::marker::Send>>>,
+}
+
+// Threads of the pool of a client unless set with with_nb_workers
+pub const DEFAULT_NB_WORKERS: usize = 4;
+
+impl WorkerPool
+{
+    pub fn new(workers: usize) -> Self
+    {
+        let (jobs, queue) = mpsc::channel::<Box<dyn FnOnce() + std::marker::Send>>();
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..workers.max(1)
+        {
+            let queue = Arc::clone(&queue);
+            // Threads stop once the pool is dropped and the queue is empty
+            thread::spawn(move || loop
+            {
+                let job = queue.lock().expect("Could not lock WorkerPool queue").recv();
+                match job
+                {
+                    Ok(job) => job(),
+                    Err(_) => break,
+                }
+            });
+        }
+
+        Self {jobs: Mutex::new(jobs)}
+    }
+
+    pub fn execute<F>(&self, f: F)
+        where F: FnOnce() + std::marker::Send + 'static,
+    {
+        self.jobs.lock().expect("Could not lock WorkerPool jobs").send(Box::new(f))
+            .expect("Threads of WorkerPool stopped");
+    }
+}
+
+
+// Whether the operation of a NbDataHandle completed and the waker of the task awaiting it
+#[derive(Debug, Default)]
+struct NbState
+{
+    done: bool,
+    waker: Option<Waker>,
+}
 
-        let ip = self.listener.local_addr()?.ip();
-        let op_listener = networking::bind_listener(&format!("{}:0", ip))
-            .expect("Could not create listener for this send operation");
+// Result of an operation running in the background. It can be waited for with data
+// or awaited as a Future, the operation wakes the awaiting task once it is done.
+#[derive(Debug)]
+pub struct NbDataHandle<T>
+{
+    data: mpsc::Receiver<T>,
+    state: Arc<Mutex<NbState>>,
+}
 
-        let op_pkt = ClientOperationPkt::new(self.id, id, op_listener.local_addr()?);   
-        op_pkt.send(&mut stream)?;
+// Completes the NbDataHandle it was created with
+pub struct NbCompletion<T>
+{
+    data: mpsc::Sender<T>,
+    state: Arc<Mutex<NbState>>,
+}
 
-        let (mut stream2, _) = op_listener.accept()?;
-        let msg = bincode::serialize(data).expect("Error in serializing data");
-        stream2.write(msg.as_slice())?;
-        stream2.flush()?;
-        
-        Ok(())
+impl<T> NbCompletion<T>
+{
+    pub fn complete(self, data: T)
+    {
+        // The handle might have been dropped already, then nobody waits for data
+        let _ = self.data.send(data);
+        let mut state = self.state.lock().expect("Could not lock NbDataHandle state");
+        state.done = true;
+        if let Some(waker) = state.waker.take()
+        {
+            waker.wake();
+        }
     }
+}
 
-    pub fn send_slice<T>(&self, data: &[T], dest: u32, id: u32) -> std::io::Result<()>
-        where T: Serialize,
+impl<T> NbDataHandle<T>
+{
+    pub fn new() -> (NbDataHandle<T>, NbCompletion<T>)
     {
-        let mut stream = networking::connect(self.client_listeners.get(dest as usize).unwrap())?;
-
-        let ip = self.listener.local_addr()?.ip();
-        let op_listener = networking::bind_listener(&format!("{}:0", ip))?;
-        let op_pkt = ClientOperationPkt::new(self.id, id, op_listener.local_addr()?);   
-        op_pkt.send(&mut stream)?;
+        let (sender, receiver) = mpsc::channel();
+        let state = Arc::new(Mutex::new(NbState::default()));
+        (NbDataHandle::<T>{data: receiver, state: Arc::clone(&state)},
+            NbCompletion::<T>{data: sender, state})
+    }
 
-        let (mut stream2, _) = op_listener.accept()?;
-        let msg = bincode::serialize(data).expect("Could not serialize send_slice data");
-        stream2.write(msg.as_slice())?;
-        stream2.flush()?;
-        
-        Ok(())
+    pub fn data(self) -> T
+    {
+        self.data.recv().expect("Operation of NbDataHandle stopped without data")
     }
 
-    pub fn receive<T>(&self, source: u32, id: u32) -> std::io::Result<T>
-        where T: serde::de::DeserializeOwned,
+    // Checks without blocking whether the operation has completed
+    pub fn is_finished(&self) -> bool
     {
-        loop
-        {
-            let mut r = self.readers.lock().expect("Could not lock 'readers' Mutex");
-            let addr = r.remove(&(source,id));
-            match addr
-            {
-                Some(a) =>
-                {
-                    let stream = networking::connect(&a)?;
-                    let reader = BufReader::new(&stream);
-                    let data: T = bincode::deserialize_from(reader)
-                        .expect("Could not deserialize received data");
-                    return Ok(data);
-                },
-                None => continue,
-            }
-        }
+        self.state.lock().expect("Could not lock NbDataHandle state").done
     }
 
-    pub fn receive_any_source<T>(&self, id: u32) -> std::io::Result<T>
-        where T: serde::de::DeserializeOwned,
+    // Returns the data if the operation has completed, otherwise the handle
+    // is given back so it can be tested again later
+    pub fn try_data(self) -> Result<T, Self>
     {
-        loop
+        match self.is_finished()
         {
-            let mut r = self.readers.lock().expect("Could not lock 'readers' Mutex");
-            let mut key: Option<(u32,u32)> = None;
-            for k in r.keys()
-            {
-                if k.1 == id 
-                {
-                    key = Some(k.clone());
-                    break;
-                }
-            }
-
-            match key
-            {
-                Some(k) =>
-                {
-                    let addr = r.remove(&k);
-                    match addr
-                    {
-                        Some(a) =>
-                        {
-                            let stream = networking::connect(&a)?;
-                            let reader = BufReader::new(&stream);
-                            let data: T = bincode::deserialize_from(reader)
-                                .expect("Could not deserialize data in receive_any_source");
-                            return Ok(data);
-                        },
-                        None => continue,
-                    }
-                },
-                None => (),
-            }
+            true => Ok(self.data()),
+            false => Err(self),
         }
     }
+}
 
+impl<T> Future for NbDataHandle<T>
+{
+    type Output = T;
 
-    pub fn send_nb<T>(&self, data: T, dest: u32, id: u32) 
-        -> std::io::Result<NbDataHandle<std::io::Result<T>>>
-        where T: Serialize + std::marker::Send + 'static
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T>
     {
-        let dest_addr = self.client_listeners.get(dest as usize).unwrap().clone();
-        let ip = self.listener.local_addr()?.ip();
-        let self_id = self.id;
-        let t = thread::spawn(move || 
-            {
-                let mut stream = networking::connect(&dest_addr)?;
-                let op_listener = networking::bind_listener(&format!("{}:0", ip))?;
-                let op_pkt = ClientOperationPkt::new(self_id, id,
-                    op_listener.local_addr()?);   
-                op_pkt.send(&mut stream)?;
-
-                let (mut stream2, _) = op_listener.accept()?;
-                let msg = bincode::serialize(&data)
-                    .expect("Could not serialize data in send_nb");
-                stream2.write(msg.as_slice())?;
-                stream2.flush()?;
+        let mut state = self.state.lock().expect("Could not lock NbDataHandle state");
+        if !state.done
+        {
+            // Checked and stored under the lock, so the operation can not complete
+            // in between without seeing the waker
+            state.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        drop(state);
 
-                Ok(data)
-            });
-        
-        Ok(NbDataHandle::<std::io::Result<T>>::new(t))
+        // The data is sent before done is set
+        Poll::Ready(self.data.recv().expect("NbDataHandle polled after it was ready"))
     }
+}
 
+// Waits for all handles and returns their data in the order of handles
+pub fn wait_all<T>(handles: Vec<NbDataHandle<T>>) -> Vec<T>
+{
+    handles.into_iter().map(|h| h.data()).collect()
+}
 
-    pub fn receive_nb<T>(&self, source: u32, id: u32) 
-        -> std::io::Result<NbDataHandle<std::io::Result<T>>>
-        where T: serde::de::DeserializeOwned + std::marker::Send + 'static,
-    {
-        let readers = Arc::clone(&self.readers);
+// Waits until any of the handles has completed, removes it from handles and returns
+// its index together with its data. The remaining handles keep their order.
+// Panics if handles is empty.
+pub fn wait_any<T>(handles: &mut Vec<NbDataHandle<T>>) -> (usize, T)
+{
+    assert!(!handles.is_empty(), "wait_any called without handles");
 
-        let t = thread::spawn(move ||
+    // Every unfinished handle wakes this thread once its operation completed
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    loop
+    {
+        let mut finished = None;
+        for (pos, h) in handles.iter().enumerate()
+        {
+            let mut state = h.state.lock().expect("Could not lock NbDataHandle state");
+            if state.done
             {
-                loop
-                {
-                    let mut r = readers.lock().expect("Could not lock 'readers' Mutex");
-                    let addr = r.remove(&(source,id));
-                    match addr
-                    {
-                        Some(a) =>
-                        {
-                            let stream = networking::connect(&a)?;
-                            let reader = BufReader::new(&stream);
-                            let data: T = bincode::deserialize_from(reader)
-                                .expect("Could not deserialize received data in receive_nb");
-                            return Ok(data);
-                        },
-                        None => continue,
-                    }
-                }
-            });
+                finished = Some(pos);
+                break;
+            }
+            state.waker = Some(waker.clone());
+        }
 
-        Ok(NbDataHandle::<std::io::Result<T>>::new(t))
+        if let Some(pos) = finished
+        {
+            for h in handles.iter()
+            {
+                h.state.lock().expect("Could not lock NbDataHandle state").waker = None;
+            }
+            return (pos, handles.remove(pos).data());
+        }
+        // Returns right away if a handle completed since its waker was set
+        thread::park();
     }
+}
 
+// Wakes a thread parked in wait_any
+struct ThreadWaker(thread::Thread);
 
-    pub fn create_mutex<T>(&mut self, name: &str, start_data: T) 
-        -> std::io::Result<HeimdallrMutex<T>>
-        where T: Serialize
+impl std::task::Wake for ThreadWaker
+{
+    fn wake(self: Arc<Self>)
     {
-        Ok(HeimdallrMutex::<T>::new(self, name, start_data)?)
+        self.0.unpark();
     }
+}
 
 
-    pub fn barrier(&mut self) -> std::io::Result<()>
-    {
-        let pkt = BarrierPkt::new(self.id, self.size, &self.job);
-        pkt.send(&mut self.daemon_stream)?;
-        BarrierReplyPkt::receive(&self.daemon_stream).expect("Could not receive BarrierReplyPkt");
-        Ok(())
-    }
+// A send to the same client and id that can be started again and again, like
+// MPI_Send_init. The stream to dest is opened by send_init already.
+pub struct PersistentSend<'a, T>
+{
+    client: &'a HeimdallrClient,
+    dest: u32,
+    id: u32,
+    active: Option<NbDataHandle<std::io::Result<T>>>,
 }
 
-impl fmt::Display for HeimdallrClient
+impl<'a, T> PersistentSend<'a, T>
+    where T: Serialize + std::marker::Send + 'static,
 {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    // Sends data in the background, fails if the previous start was not waited for
+    pub fn start(&mut self, data: T) -> std::io::Result<()>
     {
-        write!(f, "HeimdallClient:\n  Job: {}\n  Size: {}\n  Client id: {}",
-            self.job, self.size, self.id)
+        if self.active.is_some()
+        {
+            return Err(std::io::Error::other("Persistent send was started before it completed"));
+        }
+        self.active = Some(self.client.send_nb(data, self.dest, self.id)?);
+        Ok(())
     }
-}
 
-impl Drop for HeimdallrClient
-{
-    fn drop(&mut self)
+    // Waits until the send of the last start is done and gives its data back,
+    // so the next start can reuse it
+    pub fn wait(&mut self) -> std::io::Result<T>
     {
-        // let mut stream = networking::connect(&self.daemon_addr)
-        //     .expect("Could not connect to daemin in finalization procedure of HeimdallrClient");
+        match self.active.take()
+        {
+            Some(handle) => handle.data(),
+            None => Err(std::io::Error::other("Persistent send was not started")),
+        }
+    }
 
-        let finalize_pkt = FinalizePkt::new(self.id, self.size, &self.job);
-        finalize_pkt.send(&mut self.daemon_stream).expect("Could not send FinalizePkt");
-        self.daemon_stream.flush().expect("Error in flushing stream");
-        FinalizeReplyPkt::receive(&self.daemon_stream).expect("Could not receive FinalizeReplyPkt");
+    pub fn is_active(&self) -> bool
+    {
+        self.active.is_some()
     }
 }
 
-
-#[derive(Debug)]
-pub struct NbDataHandle<T>
+// A receive from the same client and id that can be started again and again,
+// like MPI_Recv_init
+pub struct PersistentReceive<'a, T>
 {
-    t: thread::JoinHandle<T>
+    client: &'a HeimdallrClient,
+    source: u32,
+    id: u32,
+    active: Option<NbDataHandle<std::io::Result<T>>>,
 }
 
-impl<T> NbDataHandle<T>
+impl<'a, T> PersistentReceive<'a, T>
+    where T: serde::de::DeserializeOwned + std::marker::Send + 'static,
 {
-    pub fn new(t: thread::JoinHandle<T>) -> NbDataHandle<T>
+    // Posts the receive, fails if the previous start was not waited for
+    pub fn start(&mut self) -> std::io::Result<()>
     {
-        NbDataHandle::<T>{t}
+        if self.active.is_some()
+        {
+            return Err(std::io::Error::other("Persistent receive was started before it completed"));
+        }
+        self.active = Some(self.client.receive_nb(self.source, self.id)?);
+        Ok(())
     }
 
-    pub fn data(self) -> T
+    // Waits for the message of the last start
+    pub fn wait(&mut self) -> std::io::Result<T>
+    {
+        match self.active.take()
+        {
+            Some(handle) => handle.data(),
+            None => Err(std::io::Error::other("Persistent receive was not started")),
+        }
+    }
+
+    pub fn is_active(&self) -> bool
     {
-        let data = self.t.join().expect("Error in joining thread of NbDataHandle");
-        data
+        self.active.is_some()
     }
 }
 
@@ -415,8 +2320,9 @@ pub struct HeimdallrMutex<T>
 {
     name: String,
     job: String,
-    daemon_stream: TcpStream,
+    daemon_stream: Box<dyn Stream>,
     client_id: u32,
+    max_message_size: u64,
     data: T,
 }
 
@@ -425,14 +2331,20 @@ impl<'a, T> HeimdallrMutex<T>
 {
     pub fn new(client: &mut HeimdallrClient, name: &str,  start_value: T) 
         -> std::io::Result<HeimdallrMutex<T>>
+    {
+        Self::create(client, name, start_value, None)
+    }
+
+    fn create(client: &mut HeimdallrClient, name: &str, start_value: T, permits: Option<u32>)
+        -> std::io::Result<HeimdallrMutex<T>>
     {
         let ser_data = bincode::serialize(&start_value)
             .expect("Could not serialize Mutex's start value");
-        let pkt = MutexCreationPkt::new(name, client.id, ser_data, &client.job);
+        let pkt = MutexCreationPkt::new(name, client.id, ser_data, permits, &client.job);
         // let mut stream = networking::connect(&client.daemon_addr)?;
         pkt.send(&mut client.daemon_stream)?;
 
-        let reply = MutexCreationReplyPkt::receive(&client.daemon_stream)
+        let reply = MutexCreationReplyPkt::receive(&mut client.daemon_stream)
             .expect("Could not receive MutexCreationReplyPkt");
 
         if reply.name != name
@@ -441,37 +2353,118 @@ impl<'a, T> HeimdallrMutex<T>
         }
 
         Ok(HeimdallrMutex::<T>{name: name.to_string(), job: client.job.clone(),
-            daemon_stream: client.daemon_stream.try_clone().unwrap(), 
+            daemon_stream: client.daemon_stream.try_clone_stream().unwrap(),
             client_id: client.id,
+            max_message_size: client.max_message_size,
             data: start_value})
     }
 
     pub fn lock(&'a mut self) -> std::io::Result<HeimdallrMutexDataHandle::<'a,T>>
         where T: serde::de::DeserializeOwned,
     {
-        // TODO remove return socketaddr from packet
-        // let mut stream = networking::connect(&self.daemon_addr)?;
-        // let ip = self.client_addr.ip();
-        // let op_listener = networking::bind_listener(&format!("{}:0", ip))?;
-
         let lock_req_pkt = MutexLockReqPkt::new(&self.name, self.client_id,&self.job);
         lock_req_pkt.send(&mut self.daemon_stream)?;
 
-
-        // let (stream2, _) = op_listener.accept()?;
-        let reader = BufReader::new(&self.daemon_stream);
-        self.data = bincode::deserialize_from(reader)
-            .expect("Could not deserialize mutex data");
+        let reply = MutexLockReplyPkt::receive(&mut self.daemon_stream).ok_or_else(||
+            std::io::Error::other("Could not receive MutexLockReplyPkt"))?;
+        self.data = networking::deserialize_from(reply.data.as_slice(), self.max_message_size)?;
 
         Ok(HeimdallrMutexDataHandle::<T>::new(self))
     }
 
+    // Client id that currently holds the lock, None if nobody holds it exclusively.
+    // Only queries the daemon and never waits for the lock.
+    pub fn current_owner(&self) -> std::io::Result<Option<u32>>
+    {
+        let mut stream = self.daemon_stream.try_clone_stream()?;
+        MutexOwnerReqPkt::new(&self.name, self.client_id, &self.job).send(&mut stream)?;
+        let reply = MutexOwnerReplyPkt::receive(&mut stream)?;
+        Ok(reply.owner)
+    }
+
+    // Same as lock, but gives up and returns None if the daemon does not grant
+    // the lock within timeout
+    pub fn lock_timeout(&'a mut self, timeout: Duration)
+        -> std::io::Result<Option<HeimdallrMutexDataHandle::<'a,T>>>
+        where T: serde::de::DeserializeOwned,
+    {
+        let lock_req_pkt = MutexLockReqPkt::new(&self.name, self.client_id, &self.job);
+        lock_req_pkt.send(&mut self.daemon_stream)?;
+
+        // A zero read timeout is rejected by the OS
+        self.daemon_stream.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        let mut first = [0u8; 1];
+        let res = self.daemon_stream.read_exact(&mut first);
+        self.daemon_stream.set_read_timeout(None)?;
+
+        match res
+        {
+            Ok(()) =>
+            {
+                let reader = (&first[..]).chain(&mut self.daemon_stream);
+                let reply = MutexLockReplyPkt::receive(reader).ok_or_else(||
+                    std::io::Error::other("Could not receive MutexLockReplyPkt"))?;
+                self.data = networking::deserialize_from(reply.data.as_slice(),
+                    self.max_message_size)?;
+                Ok(Some(HeimdallrMutexDataHandle::<T>::new(self)))
+            },
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock
+                | std::io::ErrorKind::TimedOut) =>
+            {
+                let cancel_pkt = MutexCancelReqPkt::new(&self.name, self.client_id, &self.job);
+                cancel_pkt.send(&mut self.daemon_stream)?;
+
+                // The lock might have been granted before the daemon got the cancel request,
+                // the daemon then releases it again and the grant is skipped here.
+                // If the job was aborted meanwhile, the request failed instead.
+                let mut error = None;
+                loop
+                {
+                    match DaemonReplyPkt::receive(&mut self.daemon_stream)?
+                    {
+                        DaemonReplyPkt::MutexLockReply(_) => continue,
+                        DaemonReplyPkt::Error(e) => error = Some(e.msg),
+                        DaemonReplyPkt::MutexCancelReply(_) => return match error
+                        {
+                            Some(msg) => Err(std::io::Error::other(msg)),
+                            None => Ok(None),
+                        },
+                        _ => return Err(std::io::Error::other(
+                                "Unexpected reply to MutexCancelReqPkt")),
+                    }
+                }
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    // Same as lock, but returns None right away if another client holds the lock
+    pub fn try_lock(&'a mut self) -> std::io::Result<Option<HeimdallrMutexDataHandle::<'a,T>>>
+        where T: serde::de::DeserializeOwned,
+    {
+        let try_lock_pkt = MutexTryLockReqPkt::new(&self.name, self.client_id, &self.job);
+        try_lock_pkt.send(&mut self.daemon_stream)?;
+
+        let reply = MutexTryLockReplyPkt::receive(&mut self.daemon_stream).ok_or_else(||
+            std::io::Error::other("Could not receive MutexTryLockReplyPkt"))?;
+
+        match reply.data
+        {
+            Some(data) =>
+            {
+                self.data = networking::deserialize_from(data.as_slice(), self.max_message_size)?;
+                Ok(Some(HeimdallrMutexDataHandle::<T>::new(self)))
+            },
+            None => Ok(None),
+        }
+    }
+
     fn push_data(&mut self) -> std::io::Result<()> 
     {
         // let mut stream = networking::connect(&self.daemon_addr)?;
         let ser_data = bincode::serialize(&self.data)
             .expect("Could not serialize Mutex data");
-        let write_pkt = MutexWriteAndReleasePkt::new(&self.name, ser_data, &self.job);
+        let write_pkt = MutexWriteAndReleasePkt::new(&self.name, self.client_id, ser_data, &self.job);
         write_pkt.send(&mut self.daemon_stream)?;
         self.daemon_stream.flush()?;
         Ok(())
@@ -503,6 +2496,16 @@ impl<'a,T> HeimdallrMutexDataHandle<'a,T>
     {
         self.mutex.data = value;
     }
+
+    // Extends the lease on the lock if the daemon runs with --mutex-lease.
+    // Otherwise the daemon reclaims the lock once the lease expires and the
+    // data set in this handle is discarded.
+    pub fn renew_lease(&mut self) -> std::io::Result<()>
+    {
+        let pkt = MutexRenewLeasePkt::new(&self.mutex.name, self.mutex.client_id,
+            &self.mutex.job);
+        pkt.send(&mut self.mutex.daemon_stream)
+    }
 }
 
 impl<'a,T> Drop for HeimdallrMutexDataHandle<'a,T>
@@ -515,6 +2518,145 @@ impl<'a,T> Drop for HeimdallrMutexDataHandle<'a,T>
 }
 
 
+// Reader-writer lock kept by the daemon. Any number of clients can hold read
+// access at the same time, write access is exclusive like a HeimdallrMutex.
+// The daemon mutex behind it is created with the same packets as a HeimdallrMutex,
+// so names are shared between mutexes and rwlocks.
+pub struct HeimdallrRwLock<T>
+{
+    mutex: HeimdallrMutex<T>,
+}
+
+impl<'a, T> HeimdallrRwLock<T>
+    where T: Serialize,
+{
+    pub fn new(client: &mut HeimdallrClient, name: &str, start_value: T) 
+        -> std::io::Result<HeimdallrRwLock<T>>
+    {
+        let mutex = HeimdallrMutex::<T>::new(client, name, start_value)?;
+        Ok(HeimdallrRwLock::<T>{mutex})
+    }
+
+    pub fn read(&'a mut self) -> std::io::Result<HeimdallrRwLockReadHandle::<'a,T>>
+        where T: serde::de::DeserializeOwned,
+    {
+        let mutex = &mut self.mutex;
+        let read_req_pkt = MutexReadLockReqPkt::new(&mutex.name, mutex.client_id, &mutex.job);
+        read_req_pkt.send(&mut mutex.daemon_stream)?;
+
+        let reply = MutexLockReplyPkt::receive(&mut mutex.daemon_stream).ok_or_else(||
+            std::io::Error::other("Could not receive MutexLockReplyPkt"))?;
+        mutex.data = networking::deserialize_from(reply.data.as_slice(), mutex.max_message_size)?;
+
+        Ok(HeimdallrRwLockReadHandle::<T>{mutex})
+    }
+
+    // Exclusive access, the data set in the handle is pushed to the daemon on drop
+    pub fn write(&'a mut self) -> std::io::Result<HeimdallrMutexDataHandle::<'a,T>>
+        where T: serde::de::DeserializeOwned,
+    {
+        self.mutex.lock()
+    }
+}
+
+
+// Counting semaphore kept by the daemon, up to permits clients can hold it at the
+// same time. Like a HeimdallrRwLock it is a daemon mutex, so names are shared.
+pub struct HeimdallrSemaphore
+{
+    mutex: HeimdallrMutex<()>,
+    permits: u32,
+    acquired: bool,
+}
+
+impl HeimdallrSemaphore
+{
+    pub fn new(client: &mut HeimdallrClient, name: &str, permits: u32)
+        -> std::io::Result<HeimdallrSemaphore>
+    {
+        if permits == 0
+        {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("Semaphore {} needs at least one permit", name)));
+        }
+
+        let mutex = HeimdallrMutex::<()>::create(client, name, (), Some(permits))?;
+        Ok(HeimdallrSemaphore{mutex, permits, acquired: false})
+    }
+
+    pub fn permits(&self) -> u32
+    {
+        self.permits
+    }
+
+    // Blocks until the daemon grants one of the permits to this client
+    pub fn acquire(&mut self) -> std::io::Result<()>
+    {
+        if self.acquired
+        {
+            return Err(std::io::Error::other(format!("Semaphore {} is already acquired",
+                self.mutex.name)));
+        }
+
+        let mutex = &mut self.mutex;
+        let lock_req_pkt = MutexLockReqPkt::new(&mutex.name, mutex.client_id, &mutex.job);
+        lock_req_pkt.send(&mut mutex.daemon_stream)?;
+        MutexLockReplyPkt::receive(&mut mutex.daemon_stream).ok_or_else(||
+            std::io::Error::other("Could not receive MutexLockReplyPkt"))?;
+
+        self.acquired = true;
+        Ok(())
+    }
+
+    pub fn release(&mut self) -> std::io::Result<()>
+    {
+        if !self.acquired
+        {
+            return Err(std::io::Error::other(format!("Semaphore {} is not acquired",
+                self.mutex.name)));
+        }
+
+        self.acquired = false;
+        self.mutex.push_data()
+    }
+}
+
+impl Drop for HeimdallrSemaphore
+{
+    fn drop(&mut self)
+    {
+        if self.acquired
+        {
+            self.release().expect("Error in releasing Semaphore");
+        }
+    }
+}
+
+
+pub struct HeimdallrRwLockReadHandle<'a,T>
+{
+    mutex: &'a mut HeimdallrMutex<T>,
+}
+
+impl<'a,T> HeimdallrRwLockReadHandle<'a,T>
+{
+    pub fn get(&self) -> &T
+    {
+        &self.mutex.data
+    }
+}
+
+impl<'a,T> Drop for HeimdallrRwLockReadHandle<'a,T>
+{
+    fn drop(&mut self)
+    {
+        let pkt = MutexReadReleasePkt::new(&self.mutex.name, self.mutex.client_id,
+            &self.mutex.job);
+        pkt.send(&mut self.mutex.daemon_stream).expect("Error in releasing read access to daemon");
+    }
+}
+
+
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DaemonConfig
@@ -523,14 +2665,45 @@ pub struct DaemonConfig
     pub partition: String,
     pub client_addr: SocketAddr,
     pub daemon_addr: SocketAddr,
+    // Unix domain socket that clients on the same node use instead of client_addr
+    #[serde(default)]
+    pub client_socket: Option<PathBuf>,
 }
 
 impl DaemonConfig
 {
-    pub fn new(name: &str, partition: &str, client_addr: SocketAddr, daemon_addr: SocketAddr)
-        -> DaemonConfig
+    pub fn new(name: &str, partition: &str, client_addr: SocketAddr, daemon_addr: SocketAddr,
+        client_socket: Option<PathBuf>) -> DaemonConfig
     {
         DaemonConfig{name: name.to_string(), partition: partition.to_string(),
-            client_addr, daemon_addr}
+            client_addr, daemon_addr, client_socket}
+    }
+
+    // File the daemon name in partition writes its config to,
+    // in $XDG_CONFIG_HOME or ~/.config if that is not set
+    pub fn path(partition: &str, name: &str) -> std::io::Result<PathBuf>
+    {
+        let config_home = match env::var("XDG_CONFIG_HOME")
+        {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => match env::var("HOME")
+            {
+                Ok(home) => Path::new(&home).join(".config"),
+                Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::NotFound,
+                    "Neither XDG_CONFIG_HOME nor HOME is set")),
+            },
+        };
+
+        Ok(config_home.join("heimdallr").join(partition).join(name))
+    }
+
+    // Reads the config of a running daemon, e.g. for tools talking to it
+    pub fn load(partition: &str, name: &str) -> std::io::Result<DaemonConfig>
+    {
+        let path = DaemonConfig::path(partition, name)?;
+        let file = File::open(&path).map_err(|e| std::io::Error::new(e.kind(),
+            format!("Could not open daemon config {}: {}", path.display(), e)))?;
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
     }
 }
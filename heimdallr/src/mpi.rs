@@ -0,0 +1,70 @@
+// MPI like free function interface for porting existing MPI codes.
+// All functions forward to the corresponding HeimdallrClient methods,
+// tags are the message ids used by heimdallr.
+
+use std::env;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::{HeimdallrClient, HeimdallrError};
+
+// Wildcards for mpi_recv, the same as for receive_any
+pub use crate::{ANY_SOURCE, ANY_TAG};
+
+
+// Source and tag of a received message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MpiStatus
+{
+    pub source: u32,
+    pub tag: u32,
+}
+
+
+pub fn mpi_init() -> Result<HeimdallrClient, HeimdallrError>
+{
+    HeimdallrClient::init(env::args())
+}
+
+// Finalizes the job for this client, same as dropping the client
+pub fn mpi_finalize(comm: HeimdallrClient)
+{
+    drop(comm);
+}
+
+// Aborts the whole job and exits this process with code
+pub fn mpi_abort(comm: &mut HeimdallrClient, code: i32) -> !
+{
+    comm.abort(code)
+}
+
+pub fn mpi_comm_rank(comm: &HeimdallrClient) -> u32
+{
+    comm.id
+}
+
+pub fn mpi_comm_size(comm: &HeimdallrClient) -> u32
+{
+    comm.size
+}
+
+pub fn mpi_send<T>(comm: &HeimdallrClient, data: &T, dest: u32, tag: u32) -> std::io::Result<()>
+    where T: Serialize,
+{
+    comm.send(data, dest, tag)
+}
+
+// Receives a message from source with tag, both can be wildcards like for receive_any
+pub fn mpi_recv<T>(comm: &HeimdallrClient, source: u32, tag: u32)
+    -> std::io::Result<(T, MpiStatus)>
+    where T: DeserializeOwned,
+{
+    let (source, tag, data) = comm.receive_any(source, tag)?;
+    Ok((data, MpiStatus{source, tag}))
+}
+
+pub fn mpi_barrier(comm: &mut HeimdallrClient) -> std::io::Result<()>
+{
+    comm.barrier()
+}
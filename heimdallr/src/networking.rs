@@ -1,6 +1,21 @@
-use std::net::{SocketAddr, TcpStream, TcpListener, ToSocketAddrs};
-use std::io::{Write, BufReader};
+// Packet constructors return the enclosing DaemonPkt/DaemonReplyPkt so they
+// can be sent directly
+#![allow(clippy::new_ret_no_self)]
+
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr, TcpStream, TcpListener, ToSocketAddrs};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use std::os::unix::net::{UnixStream, UnixListener};
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use bincode::Options;
+use flate2::write::ZlibEncoder;
+use flate2::read::ZlibDecoder;
+
+use crate::collectives::{ReduceOp, ReduceValue};
 
 
 //
@@ -13,28 +28,68 @@ pub enum DaemonPktType
     ClientRegistration(ClientRegistrationPkt),
     MutexCreation(MutexCreationPkt),
     MutexLockReq(MutexLockReqPkt),
+    MutexTryLockReq(MutexTryLockReqPkt),
+    MutexCancelReq(MutexCancelReqPkt),
     MutexWriteAndRelease(MutexWriteAndReleasePkt),
+    MutexRenewLease(MutexRenewLeasePkt),
+    MutexReadLockReq(MutexReadLockReqPkt),
+    MutexReadRelease(MutexReadReleasePkt),
+    MutexOwnerReq(MutexOwnerReqPkt),
     Barrier(BarrierPkt),
     Finalize(FinalizePkt),
+    Abort(AbortPkt),
+    FetchAdd(FetchAddPkt),
+    CompareAndSwap(CompareAndSwapPkt),
+    Shutdown(ShutdownPkt),
+    StatusReq(StatusReqPkt),
+}
+
+impl DaemonPktType
+{
+    // Id of the client the packet claims to be sent by, None for packets
+    // that are not sent by a registered client
+    pub fn client_id(&self) -> Option<u32>
+    {
+        match self
+        {
+            DaemonPktType::MutexCreation(p) => Some(p.client_id),
+            DaemonPktType::MutexLockReq(p) => Some(p.id),
+            DaemonPktType::MutexTryLockReq(p) => Some(p.id),
+            DaemonPktType::MutexCancelReq(p) => Some(p.id),
+            DaemonPktType::MutexWriteAndRelease(p) => Some(p.id),
+            DaemonPktType::MutexRenewLease(p) => Some(p.id),
+            DaemonPktType::MutexReadLockReq(p) => Some(p.id),
+            DaemonPktType::MutexReadRelease(p) => Some(p.id),
+            DaemonPktType::MutexOwnerReq(p) => Some(p.id),
+            DaemonPktType::Barrier(p) => Some(p.id),
+            DaemonPktType::Finalize(p) => Some(p.id),
+            DaemonPktType::Abort(p) => Some(p.id),
+            DaemonPktType::FetchAdd(p) => Some(p.id),
+            DaemonPktType::CompareAndSwap(p) => Some(p.id),
+            DaemonPktType::ClientRegistration(_) | DaemonPktType::Shutdown(_)
+                | DaemonPktType::StatusReq(_) => None,
+        }
+    }
 }
 
 impl DaemonPkt
 {
-    pub fn send(self, stream: &mut TcpStream) -> std::io::Result<()>
+    pub fn send<W: Write>(self, stream: &mut W) -> std::io::Result<()>
     {
         let msg = bincode::serialize(&self).expect("Could not serialize DaemonPkt");
-        stream.write(msg.as_slice())?;
+        stream.write_all(msg.as_slice())?;
         stream.flush()?;
         Ok(())
     }
 
-    pub fn receive(stream: &TcpStream) -> DaemonPkt
+    // A closed stream is returned as UnexpectedEof error
+    pub fn receive<R: Read>(stream: R) -> std::io::Result<DaemonPkt>
     {
         // TODO see if Bufreader can be used here without loosing data when client
         // sends two packages successively with the daemon not already being at this
         // receive call
         // let reader = BufReader::new(stream);
-        bincode::deserialize_from(stream).expect("Could not deserialize DaemonPkt")
+        receive_pkt(stream)
     }
 }
 
@@ -53,12 +108,17 @@ pub struct ClientRegistrationPkt
     pub job: String,
     pub size: u32,
     pub listener_addr: SocketAddr,
+    pub hostname: String,
+    // Shared secret of the daemon, if it was started with one
+    pub token: Option<String>,
 }
 impl ClientRegistrationPkt
 {
-    pub fn new(job: &str, size: u32, listener_addr: SocketAddr) -> DaemonPkt
+    pub fn new(job: &str, size: u32, listener_addr: SocketAddr, hostname: &str,
+        token: Option<&str>) -> DaemonPkt
     {
-        let pkt = DaemonPktType::ClientRegistration(ClientRegistrationPkt{job: job.to_string(), size, listener_addr});
+        let pkt = DaemonPktType::ClientRegistration(ClientRegistrationPkt{job: job.to_string(), size, listener_addr,
+            hostname: hostname.to_string(), token: token.map(|t| t.to_string())});
 
         DaemonPkt {job: job.to_string(), pkt}
     }
@@ -71,13 +131,17 @@ pub struct MutexCreationPkt
     pub name: String,
     pub client_id: u32,
     pub start_data: Vec<u8>,
+    // Number of clients that can hold it at the same time for a semaphore, None for a mutex
+    pub permits: Option<u32>,
 }
 
 impl MutexCreationPkt
 {
-    pub fn new(name: &str, id: u32, serialized_data: Vec<u8>, job: &str) -> DaemonPkt
+    pub fn new(name: &str, id: u32, serialized_data: Vec<u8>, permits: Option<u32>, job: &str)
+        -> DaemonPkt
     {
-        let pkt = DaemonPktType::MutexCreation(MutexCreationPkt{name: name.to_string(), client_id: id, start_data: serialized_data});
+        let pkt = DaemonPktType::MutexCreation(MutexCreationPkt{name: name.to_string(), client_id: id,
+            start_data: serialized_data, permits});
         DaemonPkt{job: job.to_string(), pkt}
     }
 }
@@ -100,18 +164,126 @@ impl MutexLockReqPkt
 }
 
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MutexTryLockReqPkt
+{
+    pub name: String,
+    pub id: u32,
+}
+
+impl MutexTryLockReqPkt
+{
+    pub fn new(name: &str, client_id: u32, job: &str) -> DaemonPkt
+    {
+        let pkt = DaemonPktType::MutexTryLockReq(MutexTryLockReqPkt{name: name.to_string(), id: client_id});
+        DaemonPkt{job: job.to_string(), pkt}
+    }
+}
+
+
+// Withdraws a lock request that was not granted yet.
+// A lock that was granted in the meantime is released again.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MutexCancelReqPkt
+{
+    pub name: String,
+    pub id: u32,
+}
+
+impl MutexCancelReqPkt
+{
+    pub fn new(name: &str, client_id: u32, job: &str) -> DaemonPkt
+    {
+        let pkt = DaemonPktType::MutexCancelReq(MutexCancelReqPkt{name: name.to_string(), id: client_id});
+        DaemonPkt{job: job.to_string(), pkt}
+    }
+}
+
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MutexWriteAndReleasePkt
 {
     pub mutex_name: String,
+    pub id: u32,
     pub data: Vec<u8>,
 }
 
 impl MutexWriteAndReleasePkt
 {
-    pub fn new(mutex_name: &str, data: Vec<u8>, job: &str) -> DaemonPkt
+    pub fn new(mutex_name: &str, client_id: u32, data: Vec<u8>, job: &str) -> DaemonPkt
+    {
+        let pkt = DaemonPktType::MutexWriteAndRelease(MutexWriteAndReleasePkt{mutex_name: mutex_name.to_string(), id: client_id, data});
+        DaemonPkt{job: job.to_string(), pkt}
+    }
+}
+
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MutexRenewLeasePkt
+{
+    pub name: String,
+    pub id: u32,
+}
+
+impl MutexRenewLeasePkt
+{
+    pub fn new(name: &str, client_id: u32, job: &str) -> DaemonPkt
+    {
+        let pkt = DaemonPktType::MutexRenewLease(MutexRenewLeasePkt{name: name.to_string(), id: client_id});
+        DaemonPkt{job: job.to_string(), pkt}
+    }
+}
+
+
+// Asks which client holds the lock, does not change the state of the mutex
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MutexOwnerReqPkt
+{
+    pub name: String,
+    pub id: u32,
+}
+
+impl MutexOwnerReqPkt
+{
+    pub fn new(name: &str, client_id: u32, job: &str) -> DaemonPkt
+    {
+        let pkt = DaemonPktType::MutexOwnerReq(MutexOwnerReqPkt{name: name.to_string(), id: client_id});
+        DaemonPkt{job: job.to_string(), pkt}
+    }
+}
+
+
+// Requests shared access, granted with a MutexLockReplyPkt like MutexLockReqPkt
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MutexReadLockReqPkt
+{
+    pub name: String,
+    pub id: u32,
+}
+
+impl MutexReadLockReqPkt
+{
+    pub fn new(name: &str, client_id: u32, job: &str) -> DaemonPkt
     {
-        let pkt = DaemonPktType::MutexWriteAndRelease(MutexWriteAndReleasePkt{mutex_name: mutex_name.to_string(), data});
+        let pkt = DaemonPktType::MutexReadLockReq(MutexReadLockReqPkt{name: name.to_string(), id: client_id});
+        DaemonPkt{job: job.to_string(), pkt}
+    }
+}
+
+
+// Releases shared access without writing data back
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MutexReadReleasePkt
+{
+    pub name: String,
+    pub id: u32,
+}
+
+impl MutexReadReleasePkt
+{
+    pub fn new(name: &str, client_id: u32, job: &str) -> DaemonPkt
+    {
+        let pkt = DaemonPktType::MutexReadRelease(MutexReadReleasePkt{name: name.to_string(), id: client_id});
         DaemonPkt{job: job.to_string(), pkt}
     }
 }
@@ -119,19 +291,34 @@ impl MutexWriteAndReleasePkt
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BarrierPkt
 {
+    pub name: String,
     pub id: u32,
+    // Number of clients taking part in the barrier
     pub size: u32,
+    pub value: Option<(ReduceOp, ReduceValue)>,
 }
 
 impl BarrierPkt
 {
-    pub fn new(id: u32, size: u32, job: &str) -> DaemonPkt
+    pub fn new(name: &str, id: u32, size: u32, job: &str) -> DaemonPkt
+    {
+        let pkt = DaemonPktType::Barrier(BarrierPkt {name: name.to_string(), id, size,
+            value: None});
+        DaemonPkt{job: job.to_string(), pkt}
+    }
+
+    pub fn with_value(name: &str, id: u32, size: u32, job: &str, op: ReduceOp,
+        value: ReduceValue) -> DaemonPkt
     {
-        let pkt = DaemonPktType::Barrier(BarrierPkt {id, size});
+        let pkt = DaemonPktType::Barrier(BarrierPkt {name: name.to_string(), id, size,
+            value: Some((op, value))});
         DaemonPkt{job: job.to_string(), pkt}
     }
 }
 
+// Name of the barrier all clients of a job take part in
+pub const GLOBAL_BARRIER: &str = "__global__";
+
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct FinalizePkt
@@ -150,6 +337,174 @@ impl FinalizePkt
 }
 
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AbortPkt
+{
+    pub id: u32,
+    pub code: i32,
+}
+
+impl AbortPkt
+{
+    pub fn new(id: u32, code: i32, job: &str) -> DaemonPkt
+    {
+        let pkt = DaemonPktType::Abort(AbortPkt {id, code});
+        DaemonPkt {job: job.to_string(), pkt}
+    }
+}
+
+
+// Adds delta to the named integer of the job on the daemon, which is created with 0
+// on first use. Answered with the previous value in an AtomicReplyPkt.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FetchAddPkt
+{
+    pub name: String,
+    pub id: u32,
+    pub delta: i64,
+}
+
+impl FetchAddPkt
+{
+    pub fn new(name: &str, id: u32, delta: i64, job: &str) -> DaemonPkt
+    {
+        let pkt = DaemonPktType::FetchAdd(FetchAddPkt {name: name.to_string(), id, delta});
+        DaemonPkt {job: job.to_string(), pkt}
+    }
+}
+
+
+// Sets the named integer to new if it currently is expected, created with 0 on first use.
+// Answered with the value before the operation in an AtomicReplyPkt.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CompareAndSwapPkt
+{
+    pub name: String,
+    pub id: u32,
+    pub expected: i64,
+    pub new: i64,
+}
+
+impl CompareAndSwapPkt
+{
+    pub fn new(name: &str, id: u32, expected: i64, new: i64, job: &str) -> DaemonPkt
+    {
+        let pkt = DaemonPktType::CompareAndSwap(CompareAndSwapPkt {name: name.to_string(), id,
+            expected, new});
+        DaemonPkt {job: job.to_string(), pkt}
+    }
+}
+
+
+// Asks the daemon to stop once its running jobs finished. Sent on a new connection
+// instead of a ClientRegistrationPkt, the daemon closes it when it exits.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ShutdownPkt
+{
+    pub token: Option<String>,
+}
+
+impl ShutdownPkt
+{
+    pub fn new(token: Option<&str>) -> DaemonPkt
+    {
+        let pkt = DaemonPktType::Shutdown(ShutdownPkt {token: token.map(|t| t.to_string())});
+        DaemonPkt {job: String::new(), pkt}
+    }
+}
+
+
+// Asks the daemon for a StatusReplyPkt, sent on a new connection like ShutdownPkt
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatusReqPkt
+{
+    pub token: Option<String>,
+}
+
+impl StatusReqPkt
+{
+    pub fn new(token: Option<&str>) -> DaemonPkt
+    {
+        let pkt = DaemonPktType::StatusReq(StatusReqPkt {token: token.map(|t| t.to_string())});
+        DaemonPkt {job: String::new(), pkt}
+    }
+}
+
+
+// Environment variable with the token of the daemon, used if no --token is given
+pub const TOKEN_ENV: &str = "HEIMDALLR_TOKEN";
+
+// Environment variable with the network interface name or ip address,
+// used by clients and daemons if no --interface is given
+pub const INTERFACE_ENV: &str = "HEIMDALLR_INTERFACE";
+
+// An address of the network interface called name, one of the requested
+// family if it has any. None if no interface with an address has that name.
+pub fn interface_ip(name: &str, ipv6: bool) -> Option<IpAddr>
+{
+    pnet::datalink::interfaces().into_iter().find(|i| (i.name == name) & !i.ips.is_empty())
+        .map(|i| i.ips.iter().find(|n| n.is_ipv6() == ipv6).unwrap_or(&i.ips[0]).ip())
+}
+
+// Address to listen on. interface can be an ip address or the name of a network
+// interface, without one the ip of this node is used. ipv6 requests an address
+// family, the unspecified address of it is used if this node has no ip of it.
+// Fails if no interface with an address has the given name.
+pub fn resolve_ip(interface: Option<&str>, ipv6: Option<bool>) -> std::io::Result<IpAddr>
+{
+    let name = match interface.filter(|i| !i.is_empty())
+    {
+        Some(name) => name,
+        None =>
+        {
+            let local = local_ipaddress::get().and_then(|i| IpAddr::from_str(&i).ok());
+            return Ok(match (local, ipv6)
+            {
+                (Some(ip), None) => ip,
+                (Some(ip), Some(v6)) if ip.is_ipv6() == v6 => ip,
+                (_, Some(false)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                // Listens on all addresses of both families
+                _ => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            });
+        },
+    };
+
+    if let Ok(ip) = IpAddr::from_str(name)
+    {
+        return Ok(ip);
+    }
+
+    match interface_ip(name, ipv6.unwrap_or(false))
+    {
+        Some(ip) =>
+        {
+            println!("Using specified network interface {} with ip {}", name, ip);
+            Ok(ip)
+        },
+        None => Err(std::io::Error::new(std::io::ErrorKind::NotFound,
+            format!("No network interface {} with an address found", name))),
+    }
+}
+
+// Whether the token of a packet is the one the daemon was started with.
+// A daemon without token accepts every packet. Compares all bytes, so the time
+// it takes does not tell how much of a guessed token was right.
+pub fn token_matches(expected: Option<&str>, given: Option<&str>) -> bool
+{
+    match (expected, given)
+    {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(e), Some(g)) =>
+        {
+            let (e, g) = (e.as_bytes(), g.as_bytes());
+            let diff = e.iter().zip(g).fold(0, |acc, (a, b)| acc | (a ^ b));
+            (e.len() == g.len()) & (diff == 0)
+        },
+    }
+}
+
+
 //
 // Daemon to Client packets
 //
@@ -159,24 +514,32 @@ pub enum DaemonReplyPkt
 {
     ClientRegistrationReply(ClientRegistrationReplyPkt),
     MutexCreationReply(MutexCreationReplyPkt),
+    MutexLockReply(MutexLockReplyPkt),
+    MutexTryLockReply(MutexTryLockReplyPkt),
+    MutexCancelReply(MutexCancelReplyPkt),
+    MutexOwnerReply(MutexOwnerReplyPkt),
     BarrierReply(BarrierReplyPkt),
     FinalizeReply(FinalizeReplyPkt),
+    AtomicReply(AtomicReplyPkt),
+    StatusReply(StatusReplyPkt),
+    Error(ErrorReplyPkt),
 }
 
 impl DaemonReplyPkt
 {
-    pub fn send(self, stream: &mut TcpStream) -> std::io::Result<()>
+    pub fn send<W: Write>(self, stream: &mut W) -> std::io::Result<()>
     {
         let msg = bincode::serialize(&self).expect("Could not serialize DaemonReplyPkt");
-        stream.write(msg.as_slice())?;
+        stream.write_all(msg.as_slice())?;
         stream.flush()?;
         Ok(())
     }
 
-    pub fn receive(stream: &TcpStream) -> Self
+    // Reads directly from stream, a BufReader could read ahead into the next reply.
+    // A closed stream is returned as UnexpectedEof error.
+    pub fn receive<R: Read>(stream: R) -> std::io::Result<Self>
     {
-        let reader = BufReader::new(stream);
-        bincode::deserialize_from(reader).expect("Could not deserialize DaemonReplyPkt")
+        receive_pkt(stream)
     }
 }
 
@@ -186,21 +549,28 @@ pub struct ClientRegistrationReplyPkt
 {
     pub id: u32,
     pub client_listeners: Vec<SocketAddr>,
+    pub hostnames: Vec<String>,
 }
 
 impl ClientRegistrationReplyPkt
 {
-    pub fn new(id: u32, client_listeners: &Vec<SocketAddr>) -> DaemonReplyPkt
+    pub fn new(id: u32, client_listeners: &[SocketAddr], hostnames: &[String]) -> DaemonReplyPkt
     {
-        DaemonReplyPkt::ClientRegistrationReply(ClientRegistrationReplyPkt {id, client_listeners: client_listeners.to_vec()})
+        DaemonReplyPkt::ClientRegistrationReply(ClientRegistrationReplyPkt {id, client_listeners: client_listeners.to_vec(),
+            hostnames: hostnames.to_vec()})
     }
 
-    pub fn receive(stream: &TcpStream) -> Option<ClientRegistrationReplyPkt>
+    pub fn receive<R: Read>(stream: R) -> Option<ClientRegistrationReplyPkt>
     {
-        let de = DaemonReplyPkt::receive(stream);
+        let de = DaemonReplyPkt::receive(stream).ok()?;
         match de
         {
             DaemonReplyPkt::ClientRegistrationReply(r) => Some(r),
+            DaemonReplyPkt::Error(e) =>
+            {
+                eprintln!("Error reported by daemon: {}", e.msg);
+                None
+            },
             _ => None,
         }
     }
@@ -220,12 +590,121 @@ impl MutexCreationReplyPkt
         DaemonReplyPkt::MutexCreationReply(MutexCreationReplyPkt{name: name.to_string()})
     }
 
-    pub fn receive(stream: &TcpStream) -> Option<MutexCreationReplyPkt>
+    pub fn receive<R: Read>(stream: R) -> Option<MutexCreationReplyPkt>
     {
-        let de = DaemonReplyPkt::receive(stream);
+        let de = DaemonReplyPkt::receive(stream).ok()?;
         match de
         {
             DaemonReplyPkt::MutexCreationReply(r) => Some(r),
+            DaemonReplyPkt::Error(e) =>
+            {
+                eprintln!("Error reported by daemon: {}", e.msg);
+                None
+            },
+            _ => None,
+        }
+    }
+}
+
+
+// Grants the lock together with the current data of the mutex
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MutexLockReplyPkt
+{
+    pub data: Vec<u8>,
+}
+
+impl MutexLockReplyPkt
+{
+    pub fn new(data: Vec<u8>) -> DaemonReplyPkt
+    {
+        DaemonReplyPkt::MutexLockReply(MutexLockReplyPkt{data})
+    }
+
+    pub fn receive<R: Read>(stream: R) -> Option<MutexLockReplyPkt>
+    {
+        let de = DaemonReplyPkt::receive(stream).ok()?;
+        match de
+        {
+            DaemonReplyPkt::MutexLockReply(r) => Some(r),
+            DaemonReplyPkt::Error(e) =>
+            {
+                eprintln!("Error reported by daemon: {}", e.msg);
+                None
+            },
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MutexCancelReplyPkt
+{
+    pub name: String,
+}
+
+impl MutexCancelReplyPkt
+{
+    pub fn new(name: &str) -> DaemonReplyPkt
+    {
+        DaemonReplyPkt::MutexCancelReply(MutexCancelReplyPkt{name: name.to_string()})
+    }
+}
+
+
+// owner is None if the lock is free or only held for reading
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MutexOwnerReplyPkt
+{
+    pub name: String,
+    pub owner: Option<u32>,
+}
+
+impl MutexOwnerReplyPkt
+{
+    pub fn new(name: &str, owner: Option<u32>) -> DaemonReplyPkt
+    {
+        DaemonReplyPkt::MutexOwnerReply(MutexOwnerReplyPkt{name: name.to_string(), owner})
+    }
+
+    pub fn receive<R: Read>(stream: R) -> std::io::Result<MutexOwnerReplyPkt>
+    {
+        match DaemonReplyPkt::receive(stream)?
+        {
+            DaemonReplyPkt::MutexOwnerReply(r) => Ok(r),
+            DaemonReplyPkt::Error(e) => Err(std::io::Error::other(e.msg)),
+            _ => Err(std::io::Error::other("Unexpected reply to MutexOwnerReqPkt")),
+        }
+    }
+}
+
+
+// data of the mutex if the lock was granted, None if another client holds it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MutexTryLockReplyPkt
+{
+    pub data: Option<Vec<u8>>,
+}
+
+impl MutexTryLockReplyPkt
+{
+    pub fn new(data: Option<Vec<u8>>) -> DaemonReplyPkt
+    {
+        DaemonReplyPkt::MutexTryLockReply(MutexTryLockReplyPkt{data})
+    }
+
+    pub fn receive<R: Read>(stream: R) -> Option<MutexTryLockReplyPkt>
+    {
+        let de = DaemonReplyPkt::receive(stream).ok()?;
+        match de
+        {
+            DaemonReplyPkt::MutexTryLockReply(r) => Some(r),
+            DaemonReplyPkt::Error(e) =>
+            {
+                eprintln!("Error reported by daemon: {}", e.msg);
+                None
+            },
             _ => None,
         }
     }
@@ -236,21 +715,27 @@ impl MutexCreationReplyPkt
 pub struct BarrierReplyPkt
 {
     pub id: u32,
+    pub value: Option<ReduceValue>,
 }
 
 impl BarrierReplyPkt
 {
-    pub fn new(id: u32) -> DaemonReplyPkt
+    pub fn new(id: u32, value: Option<ReduceValue>) -> DaemonReplyPkt
     {
-        DaemonReplyPkt::BarrierReply(BarrierReplyPkt{id})
+        DaemonReplyPkt::BarrierReply(BarrierReplyPkt{id, value})
     }
 
-    pub fn receive(stream: &TcpStream) -> Option<BarrierReplyPkt>
+    pub fn receive<R: Read>(stream: R) -> Option<BarrierReplyPkt>
     {
-        let de = DaemonReplyPkt::receive(stream);
+        let de = DaemonReplyPkt::receive(stream).ok()?;
         match de
         {
             DaemonReplyPkt::BarrierReply(r) => Some(r),
+            DaemonReplyPkt::Error(e) =>
+            {
+                eprintln!("Error reported by daemon: {}", e.msg);
+                None
+            },
             _ => None,
         }
     }
@@ -270,49 +755,267 @@ impl FinalizeReplyPkt
         DaemonReplyPkt::FinalizeReply(FinalizeReplyPkt{id})
     }
 
-    pub fn receive(stream: &TcpStream) -> Option<FinalizeReplyPkt>
+    pub fn receive<R: Read>(stream: R) -> Option<FinalizeReplyPkt>
     {
-        let de = DaemonReplyPkt::receive(stream);
+        let de = DaemonReplyPkt::receive(stream).ok()?;
         match de 
         {
             DaemonReplyPkt::FinalizeReply(r) => Some(r),
+            DaemonReplyPkt::Error(e) =>
+            {
+                eprintln!("Error reported by daemon: {}", e.msg);
+                None
+            },
+            _ => None,
+        }
+    }
+}
+
+
+// Value of a named integer before an atomic operation changed it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AtomicReplyPkt
+{
+    pub value: i64,
+}
+
+impl AtomicReplyPkt
+{
+    pub fn new(value: i64) -> DaemonReplyPkt
+    {
+        DaemonReplyPkt::AtomicReply(AtomicReplyPkt{value})
+    }
+
+    pub fn receive<R: Read>(stream: R) -> Option<AtomicReplyPkt>
+    {
+        let de = DaemonReplyPkt::receive(stream).ok()?;
+        match de
+        {
+            DaemonReplyPkt::AtomicReply(r) => Some(r),
+            DaemonReplyPkt::Error(e) =>
+            {
+                eprintln!("Error reported by daemon: {}", e.msg);
+                None
+            },
             _ => None,
         }
     }
 }
 
 
+// What a daemon is currently doing, for operators
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusReplyPkt
+{
+    pub name: String,
+    pub jobs: Vec<JobStatus>,
+    // Jobs whose clients have not all registered yet
+    pub pending_jobs: Vec<PendingJobStatus>,
+    pub shutting_down: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobStatus
+{
+    pub name: String,
+    pub size: u32,
+    // Reason of the abort if the job was aborted
+    pub aborted: Option<String>,
+    // Clients that are waiting for all others to finalize
+    pub finalized: Vec<u32>,
+    pub disconnected: Vec<u32>,
+    pub barriers: Vec<BarrierStatus>,
+    pub mutexes: Vec<MutexStatus>,
+    pub atomics: Vec<(String, i64)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingJobStatus
+{
+    pub name: String,
+    pub size: u32,
+    pub registered: u32,
+}
+
+// A barrier that not all of its clients reached yet
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BarrierStatus
+{
+    pub name: String,
+    pub size: u32,
+    pub waiting: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MutexStatus
+{
+    pub name: String,
+    pub owner: Option<u32>,
+    pub readers: Vec<u32>,
+    // Clients waiting for access in the order of their requests
+    pub queued: Vec<u32>,
+}
+
+impl StatusReplyPkt
+{
+    pub fn new(status: StatusReplyPkt) -> DaemonReplyPkt
+    {
+        DaemonReplyPkt::StatusReply(status)
+    }
+
+    pub fn receive<R: Read>(stream: R) -> Option<StatusReplyPkt>
+    {
+        let de = DaemonReplyPkt::receive(stream).ok()?;
+        match de
+        {
+            DaemonReplyPkt::StatusReply(r) => Some(r),
+            DaemonReplyPkt::Error(e) =>
+            {
+                eprintln!("Error reported by daemon: {}", e.msg);
+                None
+            },
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for StatusReplyPkt
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        writeln!(f, "Daemon {}{}: {} running jobs, {} pending jobs", self.name,
+            if self.shutting_down { " (shutting down)" } else { "" },
+            self.jobs.len(), self.pending_jobs.len())?;
+
+        for job in &self.jobs
+        {
+            writeln!(f, "Job {} with {} clients", job.name, job.size)?;
+            if let Some(reason) = &job.aborted
+            {
+                writeln!(f, "  aborted: {}", reason)?;
+            }
+            if !job.finalized.is_empty()
+            {
+                writeln!(f, "  finalized clients: {:?}", job.finalized)?;
+            }
+            if !job.disconnected.is_empty()
+            {
+                writeln!(f, "  disconnected clients: {:?}", job.disconnected)?;
+            }
+            for b in &job.barriers
+            {
+                writeln!(f, "  barrier {}: {} of {} clients waiting {:?}", b.name,
+                    b.waiting.len(), b.size, b.waiting)?;
+            }
+            for m in &job.mutexes
+            {
+                let owner = m.owner.map_or(String::from("none"), |o| o.to_string());
+                writeln!(f, "  mutex {}: owner {}, readers {:?}, queued {:?}", m.name, owner,
+                    m.readers, m.queued)?;
+            }
+            for (name, value) in &job.atomics
+            {
+                writeln!(f, "  atomic {}: {}", name, value)?;
+            }
+        }
+
+        for job in &self.pending_jobs
+        {
+            writeln!(f, "Pending job {}: {} of {} clients registered", job.name,
+                job.registered, job.size)?;
+        }
+        Ok(())
+    }
+}
+
+
+// Sent instead of the expected reply if the daemon could not complete a request
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorReplyPkt
+{
+    pub msg: String,
+}
+
+impl ErrorReplyPkt
+{
+    pub fn new(msg: &str) -> DaemonReplyPkt
+    {
+        DaemonReplyPkt::Error(ErrorReplyPkt{msg: msg.to_string()})
+    }
+}
+
+
 //
 // Client to Client packets
 //
 
-#[derive(Serialize, Deserialize, Debug)]
+// Position of a fragment in a message that was striped over several streams.
+// The fragment at offset 0 is always sent over the regular stream to the client.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Stripe
+{
+    // Numbers the striped messages of a sender, same for all fragments of a message
+    pub seq: u64,
+    pub offset: u64,
+    // Length of the whole message
+    pub total: u64,
+}
+
+// Header of a message between two clients, followed by len bytes of payload.
+// All messages of one client to another are sent over the same stream.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct ClientOperationPkt
 {
     pub client_id: u32,
     pub op_id: u32,
-    pub addr: SocketAddr,
+    pub len: u64,
+    // The payload was compressed by the sender and has to be decompressed
+    pub compressed: bool,
+    // Set if the payload is only one fragment of a message striped over several streams
+    pub stripe: Option<Stripe>,
+    // Number of messages the sender sent before with the same op_id to this receiver
+    pub seq: u64,
+    // Set without payload if the sender failed to send the message with seq
+    pub lost: bool,
 }
 
 impl ClientOperationPkt
 {
-    pub fn new(client_id: u32, op_id: u32, addr: SocketAddr) -> Self
+    pub fn new(client_id: u32, op_id: u32, len: u64, compressed: bool) -> Self
     {
-        ClientOperationPkt {client_id, op_id, addr}
+        ClientOperationPkt {client_id, op_id, len, compressed, stripe: None, seq: 0, lost: false}
     }
 
-    pub fn send(self, stream: &mut TcpStream) -> std::io::Result<()>
+    pub fn with_seq(mut self, seq: u64) -> Self
+    {
+        self.seq = seq;
+        self
+    }
+
+    pub fn lost(mut self) -> Self
+    {
+        self.lost = true;
+        self
+    }
+
+    pub fn with_stripe(mut self, stripe: Stripe) -> Self
+    {
+        self.stripe = Some(stripe);
+        self
+    }
+
+    pub fn send<W: Write>(self, stream: &mut W) -> std::io::Result<()>
     {
         let msg = bincode::serialize(&self).expect("Could not serialize ClientOperationPkt");
-        stream.write(msg.as_slice())?;
+        stream.write_all(msg.as_slice())?;
         stream.flush()?;
         Ok(())
     }
 
-    pub fn receive(stream: &TcpStream) -> Self
+    // Must not read past the header, the payload follows directly on the stream
+    pub fn receive<R: Read>(stream: R) -> std::io::Result<Self>
     {
-        let reader = BufReader::new(stream);
-        bincode::deserialize_from(reader).expect("Could not deserialize ClientOperationPkt")
+        deserialize_from(stream, DEFAULT_MAX_MESSAGE_SIZE)
     }
 }
 
@@ -321,9 +1024,222 @@ impl ClientOperationPkt
 // General networking functions
 //
 
+// Default for the largest message a client accepts, also used for all packets
+pub const DEFAULT_MAX_MESSAGE_SIZE: u64 = 1 << 30;
+
+// bincode options used for decoding messages. The encoding is the same as the
+// one of bincode::serialize, but decoding fails as soon as a message turns out
+// to be larger than limit, instead of trying to allocate memory for a corrupt length.
+pub fn wire_options(limit: u64) -> impl Options
+{
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit)
+}
+
+// Wire format of the application data clients send to each other, e.g. to exchange
+// data with non-Rust tools over CBOR or MessagePack instead of bincode.
+// Packets between clients and daemon always use bincode.
+pub trait Serializer: Send + Sync
+{
+    fn serialize(&self, data: &dyn erased_serde::Serialize) -> std::io::Result<Vec<u8>>;
+
+    // Calls visit with a deserializer over data that fails beyond limit bytes
+    fn deserialize<'de>(&self, data: &'de [u8], limit: u64,
+        visit: &mut dyn FnMut(&mut dyn erased_serde::Deserializer<'de>)
+            -> Result<(), erased_serde::Error>) -> std::io::Result<()>;
+}
+
+// Default serializer with the encoding of bincode::serialize
+pub struct BincodeSerializer;
+
+impl Serializer for BincodeSerializer
+{
+    fn serialize(&self, data: &dyn erased_serde::Serialize) -> std::io::Result<Vec<u8>>
+    {
+        bincode::serialize(data)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    fn deserialize<'de>(&self, data: &'de [u8], limit: u64,
+        visit: &mut dyn FnMut(&mut dyn erased_serde::Deserializer<'de>)
+            -> Result<(), erased_serde::Error>) -> std::io::Result<()>
+    {
+        let mut de = bincode::Deserializer::from_slice(data, wire_options(limit));
+        visit(&mut <dyn erased_serde::Deserializer>::erase(&mut de))
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
+
+// Compression of large messages between clients
+// Payloads of at least threshold bytes are compressed with zlib at level 0 to 9
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Compression
+{
+    pub level: u32,
+    pub threshold: u64,
+}
+
+// Messages below this size are not worth compressing by default
+pub const DEFAULT_COMPRESSION_THRESHOLD: u64 = 64 * 1024;
+
+pub fn compress(data: &[u8], level: u32) -> std::io::Result<Vec<u8>>
+{
+    let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::new(level.min(9)));
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+pub fn decompress(data: &[u8]) -> std::io::Result<Vec<u8>>
+{
+    let mut msg = Vec::new();
+    ZlibDecoder::new(data).read_to_end(&mut msg)?;
+    Ok(msg)
+}
+
+
+// Decodes one packet between client and daemon. Errors of the stream keep their kind,
+// so a closed connection can be told apart from invalid data.
+fn receive_pkt<R, T>(stream: R) -> std::io::Result<T>
+    where R: Read, T: DeserializeOwned,
+{
+    wire_options(DEFAULT_MAX_MESSAGE_SIZE).deserialize_from(stream).map_err(|e| match *e
+    {
+        bincode::ErrorKind::Io(io) => io,
+        e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+    })
+}
+
+// Decodes one message of at most limit bytes from reader
+pub fn deserialize_from<R, T>(reader: R, limit: u64) -> std::io::Result<T>
+    where R: Read, T: DeserializeOwned,
+{
+    wire_options(limit).deserialize_from(reader)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+// Options of the TCP sockets between clients and to the daemon
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SocketOptions
+{
+    // Disables Nagle's algorithm, so small messages go out without waiting for more data
+    pub nodelay: bool,
+    // SO_SNDBUF and SO_RCVBUF in bytes, the defaults of the OS if None
+    pub send_buffer: Option<usize>,
+    pub recv_buffer: Option<usize>,
+    // How long refused connections are retried, zero fails on the first attempt
+    pub connect_timeout: Duration,
+}
+
+impl Default for SocketOptions
+{
+    fn default() -> Self
+    {
+        SocketOptions{nodelay: true, send_buffer: None, recv_buffer: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT}
+    }
+}
+
+impl SocketOptions
+{
+    pub fn apply(&self, stream: &TcpStream) -> std::io::Result<()>
+    {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(size) = self.send_buffer
+        {
+            set_buffer_size(stream, BufferKind::Send, size)?;
+        }
+        if let Some(size) = self.recv_buffer
+        {
+            set_buffer_size(stream, BufferKind::Recv, size)?;
+        }
+        Ok(())
+    }
+}
+
+enum BufferKind
+{
+    Send,
+    Recv,
+}
+
+#[cfg(unix)]
+fn set_buffer_size(stream: &TcpStream, kind: BufferKind, size: usize) -> std::io::Result<()>
+{
+    use std::convert::TryFrom;
+    use std::os::unix::io::AsRawFd;
+
+    let opt = match kind
+    {
+        BufferKind::Send => libc::SO_SNDBUF,
+        BufferKind::Recv => libc::SO_RCVBUF,
+    };
+    let value = libc::c_int::try_from(size).map_err(|_| std::io::Error::new(
+        std::io::ErrorKind::InvalidInput, format!("Socket buffer size {} is too large", size)))?;
+    let ret = unsafe
+    {
+        libc::setsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, opt,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if ret != 0
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_buffer_size(_stream: &TcpStream, _kind: BufferKind, _size: usize) -> std::io::Result<()>
+{
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported,
+        "Socket buffer sizes can only be set on unix"))
+}
+
+// Opens a stream with the default SocketOptions, i.e. with nodelay
 pub fn connect(addr: &SocketAddr) -> std::io::Result<TcpStream>
 {
-    TcpStream::connect(addr)
+    connect_with(addr, &SocketOptions::default())
+}
+
+pub fn connect_with(addr: &SocketAddr, options: &SocketOptions) -> std::io::Result<TcpStream>
+{
+    let stream = retry_connect(options.connect_timeout, || TcpStream::connect(addr))?;
+    options.apply(&stream)?;
+    Ok(stream)
+}
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_BACKOFF_START: Duration = Duration::from_millis(10);
+const CONNECT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+// Calls connect until it succeeds, with exponential backoff between the attempts.
+// Only errors of a peer that is not listening yet are retried, the last one is
+// returned once timeout is over.
+pub fn retry_connect<T, F>(timeout: Duration, mut connect: F) -> std::io::Result<T>
+    where F: FnMut() -> std::io::Result<T>,
+{
+    let deadline = Instant::now() + timeout;
+    let mut backoff = CONNECT_BACKOFF_START;
+    loop
+    {
+        let e = match connect()
+        {
+            Ok(c) => return Ok(c),
+            Err(e) => e,
+        };
+        let retry = matches!(e.kind(), std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::TimedOut | std::io::ErrorKind::Interrupted);
+        let now = Instant::now();
+        if !retry || now >= deadline
+        {
+            return Err(e);
+        }
+        std::thread::sleep(backoff.min(deadline - now));
+        backoff = (backoff * 2).min(CONNECT_BACKOFF_MAX);
+    }
 }
 
 
@@ -331,3 +1247,161 @@ pub fn bind_listener<A: ToSocketAddrs>(ip: &A) -> std::io::Result<TcpListener>
 {
     TcpListener::bind(ip)
 }
+
+
+// Connection between a client and the daemon, either over TCP or a Unix domain socket
+pub trait Stream: Read + Write + Send + Sync
+{
+    fn try_clone_stream(&self) -> std::io::Result<Box<dyn Stream>>;
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+    fn shutdown(&self) -> std::io::Result<()>;
+}
+
+// Listener of the daemon for client connections
+pub trait Listener: Send
+{
+    fn accept_stream(&self) -> std::io::Result<Box<dyn Stream>>;
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()>;
+}
+
+macro_rules! impl_stream
+{
+    ($stream:ty, $listener:ty) =>
+    {
+        impl Stream for $stream
+        {
+            fn try_clone_stream(&self) -> std::io::Result<Box<dyn Stream>>
+            {
+                Ok(Box::new(self.try_clone()?))
+            }
+
+            fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()>
+            {
+                <$stream>::set_nonblocking(self, nonblocking)
+            }
+
+            fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>
+            {
+                <$stream>::set_read_timeout(self, timeout)
+            }
+
+            fn shutdown(&self) -> std::io::Result<()>
+            {
+                <$stream>::shutdown(self, std::net::Shutdown::Both)
+            }
+        }
+
+        impl Listener for $listener
+        {
+            fn accept_stream(&self) -> std::io::Result<Box<dyn Stream>>
+            {
+                let (stream, _) = self.accept()?;
+                Ok(Box::new(stream))
+            }
+
+            fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()>
+            {
+                <$listener>::set_nonblocking(self, nonblocking)
+            }
+        }
+    };
+}
+
+impl_stream!(TcpStream, TcpListener);
+#[cfg(unix)]
+impl_stream!(UnixStream, UnixListener);
+
+
+// Connects to the daemon over its Unix domain socket if there is one and it can be
+// reached from this node, otherwise over TCP. Makes a single attempt, see retry_connect.
+pub fn connect_daemon(socket: Option<&Path>, addr: &SocketAddr) -> std::io::Result<Box<dyn Stream>>
+{
+    #[cfg(unix)]
+    if let Some(path) = socket
+    {
+        if let Ok(stream) = UnixStream::connect(path)
+        {
+            return Ok(Box::new(stream));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = socket;
+
+    let options = SocketOptions{connect_timeout: Duration::ZERO, ..SocketOptions::default()};
+    Ok(Box::new(connect_with(addr, &options)?))
+}
+
+
+// Binds a Unix domain socket at path, a stale socket file of an earlier daemon is replaced
+#[cfg(unix)]
+pub fn bind_unix_listener(path: &Path) -> std::io::Result<Box<dyn Listener>>
+{
+    if path.exists()
+    {
+        std::fs::remove_file(path)?;
+    }
+    Ok(Box::new(UnixListener::bind(path)?))
+}
+
+#[cfg(not(unix))]
+pub fn bind_unix_listener(_path: &Path) -> std::io::Result<Box<dyn Listener>>
+{
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform"))
+}
+
+
+// Writing to a socket whose peer closed the connection raises SIGPIPE, which
+// terminates the process by default. With SIGPIPE ignored the write fails with
+// an EPIPE error instead that is returned by the sending function.
+// TcpStream writes of the standard library already avoid the signal, this covers
+// all other sockets and pipes, also in processes that do not start from a Rust
+// main or restored the default handler.
+#[cfg(unix)]
+pub fn ignore_sigpipe()
+{
+    unsafe
+    {
+        libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn ignore_sigpipe()
+{
+}
+
+
+// Set by the handler of catch_termination_signals
+static TERMINATION_REQUESTED: std::sync::atomic::AtomicBool =
+    std::sync::atomic::AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn request_termination(_signal: libc::c_int)
+{
+    TERMINATION_REQUESTED.store(true, std::sync::atomic::Ordering::SeqCst);
+}
+
+// Replaces the default handling of SIGINT and SIGTERM, which terminates the process
+// right away, so a process can poll termination_requested and clean up before exiting
+#[cfg(unix)]
+pub fn catch_termination_signals()
+{
+    let handler = request_termination as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe
+    {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn catch_termination_signals()
+{
+}
+
+pub fn termination_requested() -> bool
+{
+    TERMINATION_REQUESTED.load(std::sync::atomic::Ordering::SeqCst)
+}
@@ -0,0 +1,200 @@
+// One-sided communication on memory exposed by every member of a communicator,
+// similar to an MPI_Win with fence synchronization. Puts and gets are served by
+// the reader threads of the target, so it does not have to post any receive.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::{HeimdallrClient, Communicator, deserialize_with, serialize_with};
+
+
+// Tags of the communicator of a window
+const PUT_TAG: u32 = 0;
+const PUT_ACK_TAG: u32 = 1;
+const GET_TAG: u32 = 2;
+const GET_REPLY_TAG: u32 = 3;
+
+
+// A put or get beyond the end of the memory of the target fails with this message
+type AccessResult<T> = Result<T, String>;
+
+pub struct Window<'a, T>
+{
+    comm: Communicator<'a>,
+    local: Arc<Mutex<Vec<T>>>,
+    // Puts of the current epoch whose acknowledgement fence still has to wait for
+    puts: AtomicU32,
+}
+
+impl<'a, T> Window<'a, T>
+    where T: Serialize + DeserializeOwned + Clone + Send + 'static,
+{
+    // Exposes local to the puts and gets of all other clients and starts the first epoch.
+    // Has to be called by all clients, the sizes of local may differ.
+    pub fn create(client: &'a HeimdallrClient, local: Vec<T>) -> std::io::Result<Window<'a, T>>
+    {
+        let comm = client.world().dup()?;
+        let local = Arc::new(Mutex::new(local));
+        let sender = Arc::new(client.byte_sender());
+        let serializer = Arc::clone(&client.serializer);
+        let limit = client.max_message_size;
+
+        let (memory, s, ser) = (Arc::clone(&local), Arc::clone(&sender), Arc::clone(&serializer));
+        let ack_id = comm.id(PUT_ACK_TAG)?;
+        client.set_handler(comm.id(PUT_TAG)?, Arc::new(move |src, msg|
+        {
+            let result = deserialize_with::<(usize, Vec<T>)>(&*ser, &msg, limit)
+                .map_err(|e| e.to_string())
+                .and_then(|(offset, data)| write_at(&mut lock(&memory), offset, data));
+            reply(&*ser, &s, &result, src, ack_id);
+        }));
+
+        let (memory, s, ser) = (Arc::clone(&local), sender, serializer);
+        let reply_id = comm.id(GET_REPLY_TAG)?;
+        client.set_handler(comm.id(GET_TAG)?, Arc::new(move |src, msg|
+        {
+            let result = deserialize_with::<(usize, usize)>(&*ser, &msg, limit)
+                .map_err(|e| e.to_string())
+                .and_then(|(offset, len)| read_at(&lock(&memory), offset, len));
+            reply(&*ser, &s, &result, src, reply_id);
+        }));
+
+        // No put may arrive before the target has its handlers
+        comm.barrier()?;
+        Ok(Window{comm, local, puts: AtomicU32::new(0)})
+    }
+
+    // Writes data into the memory of target from offset on. It is only guaranteed
+    // to be visible to all clients after the next fence, which also reports a put
+    // that did not fit into the memory of its target.
+    pub fn put(&self, data: &[T], target: u32, offset: usize) -> std::io::Result<()>
+    {
+        if target == self.comm.rank
+        {
+            return write_at(&mut self.local(), offset, data.to_vec())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e));
+        }
+
+        self.comm.send(&(offset, data), target, PUT_TAG)?;
+        self.puts.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Reads len elements from offset on out of the memory of target.
+    // Puts of this client to target in the same epoch are applied before.
+    pub fn get(&self, target: u32, offset: usize, len: usize) -> std::io::Result<Vec<T>>
+    {
+        let result = match target == self.comm.rank
+        {
+            true => read_at(&self.local(), offset, len),
+            false =>
+            {
+                self.comm.send(&(offset, len), target, GET_TAG)?;
+                self.comm.receive::<AccessResult<Vec<T>>>(target, GET_REPLY_TAG)?
+            },
+        };
+        result.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+    }
+
+    // Ends the current epoch: once it returns, the puts of all clients issued before
+    // their fence are applied. Has to be called by all clients.
+    pub fn fence(&self) -> std::io::Result<()>
+    {
+        let mut failed = None;
+        for _ in 0..self.puts.swap(0, Ordering::Relaxed)
+        {
+            if let Err(e) = self.comm.receive_any_source::<AccessResult<()>>(PUT_ACK_TAG)?
+            {
+                failed.get_or_insert(e);
+            }
+        }
+        self.comm.barrier()?;
+
+        match failed
+        {
+            Some(e) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)),
+            None => Ok(()),
+        }
+    }
+
+    // The memory of this client. Puts and gets of other clients wait while it is
+    // locked, so the guard must not be held across communication with them.
+    pub fn local(&self) -> MutexGuard<'_, Vec<T>>
+    {
+        lock(&self.local)
+    }
+
+    pub fn comm(&self) -> &Communicator<'a>
+    {
+        &self.comm
+    }
+
+    // Ends the last epoch with a fence and returns the memory of this client.
+    // Has to be called by all clients.
+    pub fn into_inner(self) -> std::io::Result<Vec<T>>
+    {
+        self.fence()?;
+        let local = std::mem::take(&mut *self.local());
+        Ok(local)
+    }
+}
+
+impl<'a, T> Drop for Window<'a, T>
+{
+    fn drop(&mut self)
+    {
+        let client = self.comm.client();
+        for tag in [PUT_TAG, GET_TAG].iter()
+        {
+            if let Ok(id) = self.comm.id(*tag)
+            {
+                client.remove_handler(id);
+            }
+        }
+    }
+}
+
+
+fn lock<T>(memory: &Mutex<Vec<T>>) -> MutexGuard<'_, Vec<T>>
+{
+    memory.lock().expect("Error in locking 'window' Mutex")
+}
+
+fn write_at<T>(memory: &mut [T], offset: usize, data: Vec<T>) -> AccessResult<()>
+{
+    let len = data.len();
+    match memory.get_mut(offset..offset.saturating_add(len))
+    {
+        Some(range) =>
+        {
+            for (slot, value) in range.iter_mut().zip(data)
+            {
+                *slot = value;
+            }
+            Ok(())
+        },
+        None => Err(format!("Put of {} elements at offset {} exceeds window of size {}",
+            len, offset, memory.len())),
+    }
+}
+
+fn read_at<T: Clone>(memory: &[T], offset: usize, len: usize) -> AccessResult<Vec<T>>
+{
+    memory.get(offset..offset.saturating_add(len)).map(|range| range.to_vec())
+        .ok_or_else(|| format!("Get of {} elements at offset {} exceeds window of size {}",
+            len, offset, memory.len()))
+}
+
+// Answers a put or get from a reader thread, which has nobody to report errors to
+fn reply<R: Serialize>(serializer: &dyn crate::Serializer, sender: &crate::ByteSender,
+    result: &R, dest: u32, id: u32)
+{
+    let sent = serialize_with(serializer, result).and_then(|msg| sender.send(&msg, dest, id));
+    if let Err(e) = sent
+    {
+        eprintln!("Error in answering window access of client {}: {}", dest, e);
+    }
+}
@@ -0,0 +1,84 @@
+// Prints what a running heimdallr daemon is doing: its jobs, barriers, mutexes
+// and registrations that are still incomplete.
+
+use std::net::SocketAddr;
+use std::{env, process};
+
+use heimdallr::DaemonConfig;
+use heimdallr::networking::*;
+
+
+fn usage()
+{
+    println!("Usage: heimdallr-status --partition <partition> --name <name> [--token <token>]");
+    println!("       heimdallr-status --daemon-addr <addr> [--token <token>]");
+}
+
+
+fn query_status(args: &[String]) -> Result<StatusReplyPkt, String>
+{
+    let mut partition = None;
+    let mut name = None;
+    let mut daemon_addr = None;
+    let mut token = env::var(TOKEN_ENV).ok();
+
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next()
+    {
+        match arg.as_str()
+        {
+            "-p" | "--partition" => partition = args.next(),
+            "-n" | "--name" => name = args.next(),
+            "--daemon-addr" =>
+            {
+                daemon_addr = match args.next().map(|a| a.parse::<SocketAddr>())
+                {
+                    Some(Ok(a)) => Some(a),
+                    _ => return Err(String::from("No valid daemon address given.")),
+                }
+            },
+            "--token" =>
+            {
+                token = match args.next()
+                {
+                    Some(t) if !t.is_empty() => Some(t.clone()),
+                    _ => return Err(String::from("No valid token given.")),
+                }
+            },
+            _ => return Err(format!("Unknown argument {}.", arg)),
+        }
+    }
+
+    let (socket, addr) = match (daemon_addr, partition, name)
+    {
+        (Some(addr), _, _) => (None, addr),
+        (None, Some(partition), Some(name)) =>
+        {
+            let config = DaemonConfig::load(partition, name).map_err(|e| e.to_string())?;
+            (config.client_socket, config.client_addr)
+        },
+        _ => return Err(String::from("Either --partition and --name or --daemon-addr is needed.")),
+    };
+
+    let mut stream = connect_daemon(socket.as_deref(), &addr)
+        .map_err(|e| format!("Could not connect to daemon at {}: {}", addr, e))?;
+    StatusReqPkt::new(token.as_deref()).send(&mut stream).map_err(|e| e.to_string())?;
+    StatusReplyPkt::receive(&mut stream)
+        .ok_or_else(|| String::from("Could not receive StatusReplyPkt"))
+}
+
+
+fn main()
+{
+    let args: Vec<String> = env::args().collect();
+    match query_status(&args)
+    {
+        Ok(status) => print!("{}", status),
+        Err(e) =>
+        {
+            eprintln!("Error: {}", e);
+            usage();
+            process::exit(1);
+        },
+    }
+}
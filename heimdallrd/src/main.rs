@@ -1,17 +1,17 @@
 use std::process;
-use std::collections::HashMap;
-use std::net::{TcpStream, TcpListener, SocketAddr, IpAddr};
-use std::io::Write;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::net::{TcpListener, SocketAddr};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use std::{env, fs, thread};
 use std::str::FromStr;
 use std::collections::VecDeque;
-use std::sync::{Mutex, Arc, Barrier};
+use std::io::Write;
+use std::sync::{Mutex, Condvar, Arc, Weak, mpsc};
 
-use local_ipaddress;
-use pnet::datalink;
 
 use heimdallr::DaemonConfig;
+use heimdallr::collectives::{ReduceOp, ReduceValue, ABORT_ID};
 use heimdallr::networking::*;
 
 
@@ -21,41 +21,45 @@ struct Daemon
     partition: String,
     client_listener_addr: SocketAddr,
     client_listener: TcpListener,
+    client_socket: Option<PathBuf>,
+    socket_listener: Option<Box<dyn Listener>>,
+    mutex_policy: MutexPolicy,
+    mutex_lease: Option<Duration>,
+    registration_timeout: Option<Duration>,
+    // Clients and tools have to present this token if set
+    token: Option<String>,
+    // Partition config file written on start, removed again when the daemon is dropped
+    config_file: PathBuf,
 }
 
 impl Daemon
 {
-    fn new(name: &str, partition: &str, interface: &str) -> std::io::Result<Daemon>
+    fn new(args: &DaemonArgs) -> std::io::Result<Daemon>
     {
-        // Get IP of this node
-        let mut ip = match local_ipaddress::get()
+        // The manually specified network interface or ip address, otherwise the ip of this node
+        let ip = heimdallr::networking::resolve_ip(Some(&args.interface), None)?;
+
+        let client_listener = heimdallr::networking::bind_listener(
+            &SocketAddr::new(ip, args.port))?;
+        // With port 0 the OS picks a free port, the config file needs the actual one
+        let client_listener_addr = client_listener.local_addr()?;
+
+        // Clients on this node can connect over a Unix domain socket instead
+        let socket_listener = match &args.unix_socket
         {
-            Some(i) => IpAddr::from_str(&i).unwrap(),
-            None => IpAddr::from_str("0.0.0.0").unwrap(),
+            Some(path) => Some(heimdallr::networking::bind_unix_listener(path)?),
+            None => None,
         };
 
-        // Use the manually specified network interface
-        if !interface.is_empty()
+        if env::var("XDG_CONFIG_HOME").is_err()
         {
-            let interfaces = datalink::interfaces();
-            for i in interfaces
-            {
-                if i.name == interface
-                {
-                    println!("Using specified network interface {} with ip {}",
-                        i.name, i.ips[0]);
-                    ip = i.ips[0].ip();
-
-                }
-            }
+            eprintln!("XDG_CONFIG_HOME is not set. Falling back to default path: ~/.config");
         }
-
-        let client_listener_addr = SocketAddr::new(ip, 4664);
-
-        let client_listener = heimdallr::networking::bind_listener(&client_listener_addr)?;
-
-        let daemon = Daemon{name: name.to_string(), partition: partition.to_string(),
-            client_listener_addr, client_listener};
+        let config_file = DaemonConfig::path(&args.partition, &args.name)?;
+        let daemon = Daemon{name: args.name.clone(), partition: args.partition.clone(),
+            client_listener_addr, client_listener, client_socket: args.unix_socket.clone(),
+            socket_listener, mutex_policy: args.mutex_policy, mutex_lease: args.mutex_lease,
+            registration_timeout: args.registration_timeout, token: args.token.clone(), config_file};
 
         daemon.create_partition_file().unwrap();
         
@@ -64,93 +68,357 @@ impl Daemon
 
     fn create_partition_file(&self) -> std::io::Result<()>
     {
-        let config_home = match env::var("XDG_CONFIG_HOME")
+        if let Some(path) = self.config_file.parent()
         {
-            Ok(path) => path,
-            Err(_) => 
+            if !path.exists()
             {
-                eprintln!("XDG_CONFIG_HOME is not set. Falling back to default path: ~/.config");
-                let home = env::var("HOME").expect("HOME environment variable is not set");
-                format!("{}/.config", home)
-            },
-        };
-
-        let path = format!("{}/heimdallr/{}", config_home, &self.partition);
-        if Path::new(&path).exists() == false
-        {
-            fs::create_dir_all(&path)?;
+                fs::create_dir_all(path)?;
+            }
         }
 
         let daemon_config = DaemonConfig::new(&self.name, &self.partition,
-                 self.client_listener_addr.clone(), self.client_listener_addr.clone());
+                 self.client_listener_addr, self.client_listener_addr, self.client_socket.clone());
 
-        let file_path = format!("{}/{}", path, self.name);
         let serialized = serde_json::to_string(&daemon_config)
             .expect("Could not serialize DaemonConfig");
-        fs::write(&file_path, serialized)?;
-        println!("Writing heimdallr daemon config to: {}", file_path);
+        fs::write(&self.config_file, serialized)?;
+        println!("Writing heimdallr daemon config to: {}", self.config_file.display());
 
         Ok(())
     }
+
+}
+
+// Clients could otherwise still find the address of this daemon after it stopped
+impl Drop for Daemon
+{
+    fn drop(&mut self)
+    {
+        match fs::remove_file(&self.config_file)
+        {
+            Ok(()) => println!("Removed heimdallr daemon config {}", self.config_file.display()),
+            Err(e) => eprintln!("Error: Could not remove daemon config {}: {}",
+                self.config_file.display(), e),
+        }
+    }
+}
+
+
+// An aborted job fails the daemon operations of its remaining clients
+// instead of waiting for clients that are gone
+#[derive(Debug, Clone, PartialEq)]
+enum JobState
+{
+    Running,
+    Aborted(String),
 }
 
 
 struct Job
 {
     size: u32,
-    barrier: Mutex<DaemonBarrier>,
+    mutex_policy: MutexPolicy,
+    mutex_lease: Option<Duration>,
+    client_listeners: Vec<SocketAddr>,
+    // Clones of the streams of all clients, closed when a client aborts the job
+    streams: Mutex<Vec<Box<dyn Stream>>>,
+    state: Mutex<JobState>,
+    // Synchronizes the handler threads of all clients for mutex creation and finalization
+    thread_barrier: JobBarrier,
+    barriers: Mutex<HashMap<String, DaemonBarrier>>,
     finalize: Mutex<JobFinalization>,
-    mutexes: Mutex<HashMap<String, HeimdallrDaemonMutex>>
+    mutexes: Mutex<HashMap<String, HeimdallrDaemonMutex>>,
+    // Named integers for atomic operations, created with 0 on first use
+    atomics: Mutex<HashMap<String, i64>>,
+    // Clients whose connection broke before they finalized, the job is degraded then
+    disconnected: Mutex<Vec<u32>>,
 }
 
 impl Job
 {
-    fn new(size: u32) -> std::io::Result<Job>
+    fn new(size: u32, mutex_policy: MutexPolicy, mutex_lease: Option<Duration>,
+        client_listeners: Vec<SocketAddr>) -> std::io::Result<Job>
     {
         // let clients = Vec::<TcpStream>::new();
         // let client_listeners = Vec::<SocketAddr>::new();
         let mutexes = Mutex::new(HashMap::<String, HeimdallrDaemonMutex>::new());
-        let barrier = Mutex::new(DaemonBarrier::new(size));
+        let barriers = Mutex::new(HashMap::<String, DaemonBarrier>::new());
         let finalize = Mutex::new(JobFinalization::new(size));
         // Ok(Job {name: name.to_string(), size, clients, client_listeners,
         //     mutexes, barrier, finalize})
-        Ok(Job{size, barrier, finalize, mutexes})
+        let state = Mutex::new(JobState::Running);
+        let thread_barrier = JobBarrier::new(size as usize);
+        let disconnected = Mutex::new(Vec::new());
+        Ok(Job{size, mutex_policy, mutex_lease, client_listeners, streams: Mutex::new(Vec::new()),
+            state, thread_barrier, barriers, finalize, mutexes, atomics: Mutex::new(HashMap::new()),
+            disconnected})
+    }
+
+    // Replaces the named integer by update of its value under the lock of the integers
+    // and replies with the previous value
+    fn update_atomic<F>(&self, name: &str, update: F) -> DaemonReplyPkt
+        where F: FnOnce(i64) -> i64,
+    {
+        if let Some(reason) = self.abort_reason()
+        {
+            return ErrorReplyPkt::new(&reason);
+        }
+
+        let mut atomics = self.atomics.lock().unwrap();
+        let value = atomics.entry(name.to_string()).or_insert(0);
+        let previous = *value;
+        *value = update(previous);
+        AtomicReplyPkt::new(previous)
+    }
+
+    // Snapshot of the state of the job for a StatusReplyPkt
+    fn status(&self, name: &str) -> JobStatus
+    {
+        let finalized = registered_ids(&self.finalize.lock().unwrap().streams);
+        let disconnected = self.disconnected.lock().unwrap().clone();
+
+        let mut barriers: Vec<BarrierStatus> = self.barriers.lock().unwrap().values()
+            .map(|b| BarrierStatus{name: b.name.clone(), size: b.size,
+                waiting: registered_ids(&b.streams)})
+            .collect();
+        barriers.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut mutexes: Vec<MutexStatus> = self.mutexes.lock().unwrap().values()
+            .map(|m| MutexStatus{name: m.name.clone(), owner: m.current_owner,
+                readers: m.readers.clone(),
+                queued: m.access_queue.iter().map(|&(id, _)| id).collect()})
+            .collect();
+        mutexes.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut atomics: Vec<(String, i64)> = self.atomics.lock().unwrap().iter()
+            .map(|(name, &value)| (name.clone(), value))
+            .collect();
+        atomics.sort();
+
+        JobStatus{name: name.to_string(), size: self.size, aborted: self.abort_reason(),
+            finalized, disconnected, barriers, mutexes, atomics}
+    }
+
+    fn abort_reason(&self) -> Option<String>
+    {
+        match &*self.state.lock().unwrap()
+        {
+            JobState::Running => None,
+            JobState::Aborted(reason) => Some(reason.clone()),
+        }
+    }
+
+    // Marks the job as aborted and sends an error to every client waiting in a barrier
+    // or for a mutex. The state is set before the barriers and mutexes are locked, so
+    // requests that get there afterwards see it and fail right away.
+    fn abort(&self, reason: &str)
+    {
+        let mut state = self.state.lock().unwrap();
+        if *state != JobState::Running
+        {
+            return
+        }
+        *state = JobState::Aborted(reason.to_string());
+        drop(state);
+        eprintln!("Error: {}", reason);
+
+        self.thread_barrier.abort();
+        for (_, mut barrier) in self.barriers.lock().unwrap().drain()
+        {
+            barrier.abort(reason);
+        }
+        for mutex in self.mutexes.lock().unwrap().values_mut()
+        {
+            mutex.abort(reason);
+        }
+    }
+
+    // Aborts the job on behalf of client source. The reason is also sent to the listener
+    // of every other client, so receives waiting for a message fail as well, then the
+    // streams to all clients are closed.
+    fn tear_down(&self, source: u32, reason: &str)
+    {
+        self.abort(reason);
+
+        for (id, addr) in self.client_listeners.iter().enumerate()
+        {
+            if id as u32 == source
+            {
+                continue;
+            }
+
+            let sent = connect(addr).and_then(|mut s|
+            {
+                ClientOperationPkt::new(source, ABORT_ID, reason.len() as u64, false).send(&mut s)?;
+                s.write_all(reason.as_bytes())?;
+                s.flush()
+            });
+            if let Err(e) = sent
+            {
+                eprintln!("Error: Could not send abort to client {}: {}", id, e);
+            }
+        }
+
+        for stream in self.streams.lock().unwrap().iter()
+        {
+            // Fails for clients that are gone already
+            let _ = stream.shutdown();
+        }
+    }
+}
+
+
+// Barrier over the handler threads of a job. Unlike std::sync::Barrier it can be
+// aborted, so the threads of the remaining clients do not wait forever for the
+// thread of a client that disconnected.
+struct JobBarrier
+{
+    size: usize,
+    state: Mutex<JobBarrierState>,
+    cvar: Condvar,
+}
+
+struct JobBarrierState
+{
+    count: usize,
+    generation: u64,
+    aborted: bool,
+}
+
+impl JobBarrier
+{
+    fn new(size: usize) -> Self
+    {
+        let state = Mutex::new(JobBarrierState{count: 0, generation: 0, aborted: false});
+        Self {size, state, cvar: Condvar::new()}
+    }
+
+    // Returns false if the barrier was aborted before all threads arrived
+    fn wait(&self) -> bool
+    {
+        let mut state = self.state.lock().unwrap();
+        if state.aborted
+        {
+            return false
+        }
+
+        let generation = state.generation;
+        state.count += 1;
+        if state.count == self.size
+        {
+            state.count = 0;
+            state.generation += 1;
+            self.cvar.notify_all();
+            return true
+        }
+
+        while (state.generation == generation) & (!state.aborted)
+        {
+            state = self.cvar.wait(state).unwrap();
+        }
+        state.generation != generation
+    }
+
+    fn abort(&self)
+    {
+        self.state.lock().unwrap().aborted = true;
+        self.cvar.notify_all();
+    }
+}
+
+
+// Policy used to pick the next owner of a mutex from its access queue
+// Fifo: grant in arrival order
+// Fair: grant round-robin to the first waiting rank after the last one granted,
+//       so a rank that re-requests right after releasing waits for all others
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MutexPolicy
+{
+    Fifo,
+    Fair,
+}
+
+// For parsing command line arguments
+impl FromStr for MutexPolicy
+{
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        match s
+        {
+            "fifo" => Ok(MutexPolicy::Fifo),
+            "fair" => Ok(MutexPolicy::Fair),
+            _ => Err("Mutex policy has to be 'fifo' or 'fair'."),
+        }
     }
 }
 
 
+// Exclusive access is used by mutexes and rwlock writers, shared access by rwlock readers
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AccessMode
+{
+    Exclusive,
+    Shared,
+}
+
+
 struct HeimdallrDaemonMutex
 {
     name: String,
-    streams: Vec<Option<TcpStream>>,
+    streams: Vec<Option<Box<dyn Stream>>>,
     constructed: bool,
     data: Vec<u8>,
-    access_queue: VecDeque<u32>,
+    access_queue: VecDeque<(u32, AccessMode)>,
     locked: bool,
     current_owner: Option<u32>,
+    readers: Vec<u32>,
+    policy: MutexPolicy,
+    last_grant: Option<u32>,
+    lease: Option<Duration>,
+    lease_deadline: Option<Instant>,
+    // Set for a semaphore, its holders are kept in readers
+    permits: Option<u32>,
 }
 
 impl HeimdallrDaemonMutex
 {
-    fn new(name: &str, size: u32, start_data: Vec<u8>) -> Self
+    fn new(name: &str, size: u32, start_data: Vec<u8>, policy: MutexPolicy,
+        lease: Option<Duration>, permits: Option<u32>) -> Self
     {
-        let mut streams = Vec::<Option<TcpStream>>::new();
+        let mut streams = Vec::<Option<Box<dyn Stream>>>::new();
         streams.resize_with(size as usize, || None);
-        let access_queue = VecDeque::<u32>::new();
+        let access_queue = VecDeque::<(u32, AccessMode)>::new();
 
         Self {name: name.to_string(), streams, constructed: false, 
-            data: start_data, access_queue, locked: false, current_owner: None}
+            data: start_data, access_queue, locked: false, current_owner: None,
+            readers: Vec::new(), policy, last_grant: None, lease, lease_deadline: None, permits}
     }
 
-    fn register_client(&mut self, id: u32, stream: TcpStream)
+    fn register_client(&mut self, id: u32, stream: Box<dyn Stream>, permits: Option<u32>)
+        -> Result<(), String>
     {
+        check_registration(&self.streams, id, &format!("mutex {}", self.name))?;
+        if permits != self.permits
+        {
+            return Err(format!("Client {} created mutex {} with {:?} permits, but it has {:?}",
+                id, self.name, permits, self.permits));
+        }
         self.streams[id as usize] = Some(stream);
         self.constructed = !self.streams.iter().any(|x| x.is_none());
+        Ok(())
     }
 
-    fn access_request(&mut self, client_id: u32)
+    fn access_request(&mut self, client_id: u32, mode: AccessMode)
     {
-        self.access_queue.push_back(client_id);
+        // Semaphore holders share it like readers, up to the number of permits
+        let mode = match self.permits
+        {
+            Some(_) => AccessMode::Shared,
+            None => mode,
+        };
+        self.access_queue.push_back((client_id, mode));
         self.grant_next_lock();
     }
 
@@ -160,6 +428,7 @@ impl HeimdallrDaemonMutex
         {
             self.locked = false;
             self.current_owner = None;
+            self.lease_deadline = None;
             self.grant_next_lock();
         }
         else
@@ -168,74 +437,300 @@ impl HeimdallrDaemonMutex
         }
     }
 
+    fn read_release_request(&mut self, client_id: u32)
+    {
+        match self.readers.iter().position(|id| *id == client_id)
+        {
+            Some(pos) =>
+            {
+                self.readers.swap_remove(pos);
+                self.grant_next_lock();
+            },
+            None => eprintln!("Error: Read release by client {} on mutex {} without read access",
+                client_id, self.name),
+        }
+    }
+
+    // Grants waiting requests until the next one has to wait. Readers are granted
+    // together, a waiting writer stops readers queued behind it from joining them.
     fn grant_next_lock(&mut self)
     {
-        if (!self.locked) & (!self.access_queue.is_empty())
+        while (!self.locked) & (!self.access_queue.is_empty())
+        {
+            let pos = match self.policy
+            {
+                MutexPolicy::Fifo => 0,
+                MutexPolicy::Fair =>
+                {
+                    // Distance in rank order from the rank after the last grant
+                    let size = self.streams.len() as u32;
+                    let after = self.last_grant.map_or(0, |l| (l + 1) % size);
+                    self.access_queue.iter().enumerate()
+                        .min_by_key(|(_, (id, _))| (id + size - after) % size)
+                        .map(|(pos, _)| pos)
+                        .unwrap()
+                },
+            };
+
+            let (id, mode) = self.access_queue[pos];
+            if (mode == AccessMode::Exclusive) & (!self.readers.is_empty())
+            {
+                break;
+            }
+            if self.permits.is_some_and(|p| self.readers.len() >= p as usize)
+            {
+                break;
+            }
+
+            self.access_queue.remove(pos);
+            match mode
+            {
+                AccessMode::Exclusive => self.grant(id),
+                AccessMode::Shared =>
+                {
+                    self.last_grant = Some(id);
+                    self.readers.push(id);
+                },
+            }
+            self.send_data(id);
+        }
+    }
+
+    fn grant(&mut self, client_id: u32)
+    {
+        self.current_owner = Some(client_id);
+        self.last_grant = Some(client_id);
+        self.locked = true;
+        self.lease_deadline = self.lease.map(|l| Instant::now() + l);
+    }
+
+    // Withdraws the lock request of client_id, if it already got the lock it is released again
+    fn cancel_request(&mut self, client_id: u32)
+    {
+        if self.current_owner == Some(client_id)
+        {
+            self.release_request();
+        }
+        else
+        {
+            self.access_queue.retain(|(id, _)| *id != client_id);
+        }
+    }
+
+    // Grants the lock to client_id only if nobody holds it, without queueing otherwise.
+    // Returns the data for the new owner.
+    fn try_access(&mut self, client_id: u32) -> Option<Vec<u8>>
+    {
+        if self.locked | (!self.readers.is_empty())
+        {
+            return None;
+        }
+
+        // Waiting clients are granted the lock right when it is released,
+        // so the queue is empty here
+        self.grant(client_id);
+        Some(self.data.clone())
+    }
+
+    fn renew_lease(&mut self, client_id: u32)
+    {
+        if self.current_owner == Some(client_id)
+        {
+            self.lease_deadline = self.lease.map(|l| Instant::now() + l);
+        }
+        else
+        {
+            eprintln!("Error: Client {} tried to renew lease on mutex {} without holding the lock",
+                client_id, self.name);
+        }
+    }
+
+    // Takes the lock away from an owner that did not release or renew it in time.
+    // The data of the last release is kept.
+    fn reclaim_expired_lease(&mut self)
+    {
+        if self.locked & self.lease_deadline.is_some_and(|d| Instant::now() >= d)
         {
-            self.current_owner = self.access_queue.pop_front();
-            self.locked = true;
-            self.send_data();
+            eprintln!("Lease of client {:?} on mutex {} expired, reclaiming lock",
+                self.current_owner, self.name);
+            self.release_request();
         }
     }
 
-    fn send_data(&mut self)
+    // Fails the requests of all waiting clients
+    fn abort(&mut self, reason: &str)
     {
-        match self.current_owner
+        while let Some((id, _)) = self.access_queue.pop_front()
         {
-            Some(id) =>
+            if let Some(s) = self.streams.get_mut(id as usize).and_then(|s| s.as_mut())
             {
-                let stream = self.streams.get_mut(id as usize).unwrap();
-                match stream
+                if let Err(e) = ErrorReplyPkt::new(reason).send(s)
                 {
-                    Some(s) =>
-                    {
-                        s.write(self.data.as_slice()).unwrap();
-                        s.flush().unwrap();
-                    },
-                    None => eprintln!("Error: No valid TcpStream found for client"),
+                    eprintln!("Error: Could not send abort of mutex {} to client {}: {}",
+                        self.name, id, e);
+                }
+            }
+        }
+    }
+
+    fn send_data(&mut self, id: u32)
+    {
+        let stream = self.streams.get_mut(id as usize).unwrap();
+        match stream
+        {
+            Some(s) =>
+            {
+                // A client that can not receive the data won't release the lock,
+                // so only report it and leave it to the lease to recover
+                if let Err(e) = MutexLockReplyPkt::new(self.data.clone()).send(s)
+                {
+                    eprintln!("Error: Could not send data of mutex {} to client {}: {}",
+                        self.name, id, e);
                 }
             },
-            None => eprintln!("Error: Mutex has no current owner to send data"),
+            None => eprintln!("Error: No valid stream found for client"),
         }
     }
 }
 
 
+// Checks that id is a valid rank for a job with one stream slot per rank
+// and that the rank has not registered already
+fn check_registration(streams: &[Option<Box<dyn Stream>>], id: u32, what: &str)
+    -> Result<(), String>
+{
+    match streams.get(id as usize)
+    {
+        None => Err(format!("Client id {} is out of range for {} of a job with size {}",
+                id, what, streams.len())),
+        Some(Some(_)) => Err(format!("Client id {} registered twice for {}", id, what)),
+        Some(None) => Ok(()),
+    }
+}
+
+// Returns the ranks that have not registered yet
+fn missing_ids(streams: &[Option<Box<dyn Stream>>]) -> Vec<u32>
+{
+    streams.iter().enumerate()
+        .filter(|(_, s)| s.is_none())
+        .map(|(id, _)| id as u32)
+        .collect()
+}
+
+// Returns the ranks that have registered
+fn registered_ids(streams: &[Option<Box<dyn Stream>>]) -> Vec<u32>
+{
+    streams.iter().enumerate()
+        .filter(|(_, s)| s.is_some())
+        .map(|(id, _)| id as u32)
+        .collect()
+}
+
+
+// Barrier over size clients of a job, which can be fewer than the job size.
+// Streams and values are indexed by client id.
 struct DaemonBarrier
 {
+    name: String,
     size: u32,
-    streams: Vec<Option<TcpStream>>,
+    streams: Vec<Option<Box<dyn Stream>>>,
+    values: Vec<Option<(ReduceOp, ReduceValue)>>,
     finished: bool,
 }
 
 impl DaemonBarrier
 {
-    fn new(size: u32) -> Self
+    fn new(name: &str, job_size: u32, size: u32) -> Self
     {
-        let mut streams = Vec::<Option<TcpStream>>::new();
-        streams.resize_with(size as usize, || None);
+        let mut streams = Vec::<Option<Box<dyn Stream>>>::new();
+        streams.resize_with(job_size as usize, || None);
+        let values = vec![None; job_size as usize];
 
-        Self {size, streams, finished: false}
+        Self {name: name.to_string(), size, streams, values, finished: false}
     }
 
-    fn register_client(&mut self, id: u32, stream: TcpStream)
+    fn register_client(&mut self, id: u32, size: u32, stream: Box<dyn Stream>,
+        value: Option<(ReduceOp, ReduceValue)>) -> Result<(), String>
     {
+        if (size == 0) | (size as usize > self.streams.len())
+        {
+            return Err(format!("Barrier {} can not have {} clients in a job of size {}",
+                self.name, size, self.streams.len()));
+        }
+        if size != self.size
+        {
+            return Err(format!("Client {} expected {} clients in barrier {}, others expected {}",
+                id, size, self.name, self.size));
+        }
+        check_registration(&self.streams, id, &format!("barrier {}", self.name))?;
         self.streams[id as usize] = Some(stream);
-        self.finished = !self.streams.iter().any(|x| x.is_none());
+        self.values[id as usize] = value;
+        self.finished = self.streams.iter().filter(|x| x.is_some()).count() == self.size as usize;
+        Ok(())
+    }
+
+    // Folds the values of all clients in rank order.
+    // Returns None for plain barriers or if the clients did not agree on the
+    // operation and value type.
+    fn reduced_value(&self) -> Option<ReduceValue>
+    {
+        let mut values = self.streams.iter().zip(self.values.iter())
+            .filter(|(s, _)| s.is_some())
+            .map(|(_, v)| v);
+        let (op, first) = (*values.next()?)?;
+        values.try_fold(first, |acc, v|
+        {
+            match v
+            {
+                Some((o, value)) if *o == op => acc.reduce(*value, op),
+                _ => None,
+            }
+        })
+    }
+
+    // Sends the reply to every client once the last one arrived
+    fn complete(&mut self, job_size: u32)
+    {
+        let value = self.reduced_value();
+        if self.values.iter().any(|v| v.is_some()) & value.is_none()
+        {
+            eprintln!("Error: Clients did not agree on the reduction of barrier {}", self.name);
+        }
+
+        for (id, stream) in self.streams.iter_mut().enumerate()
+        {
+            if let Some(s) = stream
+            {
+                if let Err(e) = BarrierReplyPkt::new(job_size, value).send(s)
+                {
+                    eprintln!("Error: Could not send BarrierReplyPkt of barrier {} to client {}: {}",
+                        self.name, id, e);
+                }
+            }
+        }
     }
 
-    fn reset(&mut self)
+    // Fails the barrier for every client that is already waiting in it
+    fn abort(&mut self, reason: &str)
     {
-        self.streams = Vec::<Option<TcpStream>>::new();
-        self.streams.resize_with(self.size as usize, || None);
-        self.finished = false;
+        for (id, stream) in self.streams.iter_mut().enumerate()
+        {
+            if let Some(s) = stream
+            {
+                if let Err(e) = ErrorReplyPkt::new(reason).send(s)
+                {
+                    eprintln!("Error: Could not send abort of barrier {} to client {}: {}",
+                        self.name, id, e);
+                }
+            }
+        }
     }
 }
 
 
 struct JobFinalization
 {
-    streams: Vec<Option<TcpStream>>,
+    streams: Vec<Option<Box<dyn Stream>>>,
     finished: bool,
 }
 
@@ -243,119 +738,466 @@ impl JobFinalization
 {
     fn new(size: u32) -> Self
     {
-        let mut streams = Vec::<Option<TcpStream>>::new();
+        let mut streams = Vec::<Option<Box<dyn Stream>>>::new();
         streams.resize_with(size as usize, || None);
 
         Self {streams, finished: false}
     }
 
-    fn register_client(&mut self, id: u32, stream: TcpStream)
+    fn register_client(&mut self, id: u32, stream: Box<dyn Stream>) -> Result<(), String>
     {
+        check_registration(&self.streams, id, "finalization")?;
         self.streams[id as usize] = Some(stream);
         self.finished = !self.streams.iter().any(|x| x.is_none());
+        Ok(())
     }
 }
 
 
-fn handle_client(mut stream: TcpStream, job: Arc<Job>, thread_barrier: Arc<Barrier>)
+fn report_error(reply: &DaemonReplyPkt)
 {
-    // println!("thread spawned for job: {}", job.name);
+    if let DaemonReplyPkt::Error(e) = reply
+    {
+        eprintln!("Error: {}", e.msg);
+    }
+}
 
-    loop
+
+// Sends the reason of the abort to a client whose request can not be served anymore
+fn reject_aborted(stream: &mut Box<dyn Stream>, reason: &str)
+{
+    if let Err(e) = ErrorReplyPkt::new(reason).send(stream)
     {
-        let pkt = DaemonPkt::receive(&stream);
-        // println!("Received DaemonPkt: {:?}", pkt);
+        eprintln!("Error: Could not send abort of job: {}", e);
+    }
+}
 
-        match pkt.pkt
+
+// Follows the wait-for graph of the mutexes from the waiting client id, a waiting
+// client depends on every client that currently holds the mutex it waits for.
+// Returns the clients of a cycle back to id together with the mutex each waits for.
+fn find_deadlock(mutexes: &HashMap<String, HeimdallrDaemonMutex>, id: u32)
+    -> Option<Vec<(u32, String)>>
+{
+    let waiting_for = |client: u32| mutexes.values()
+        .find(|m| m.access_queue.iter().any(|(c, _)| *c == client));
+
+    let mut path = Vec::<(u32, String)>::new();
+    let mut visited = HashSet::<u32>::new();
+    let mut stack = vec![(id, 0_usize)];
+    while let Some((client, depth)) = stack.pop()
+    {
+        path.truncate(depth);
+        if depth > 0 && client == id
         {
-            DaemonPktType::MutexCreation(mutex_pkt) =>
+            return Some(path);
+        }
+        if !visited.insert(client)
+        {
+            continue;
+        }
+        if let Some(mutex) = waiting_for(client)
+        {
+            path.push((client, mutex.name.clone()));
+            for holder in mutex.current_owner.iter().chain(mutex.readers.iter())
             {
-                let mut mutexes = job.mutexes.lock().unwrap();
-                let mutex = mutexes.entry(mutex_pkt.name.clone())
-                    .or_insert(HeimdallrDaemonMutex::new(&mutex_pkt.name, job.size,
-                            mutex_pkt.start_data));
+                stack.push((*holder, depth + 1));
+            }
+        }
+    }
+    None
+}
 
-                mutex.register_client(mutex_pkt.client_id, stream.try_clone().unwrap());
-                drop(mutexes);
 
-                thread_barrier.wait();
-                let mut mutexes = job.mutexes.lock().unwrap();
-                let mutex = mutexes.get_mut(&mutex_pkt.name).unwrap();
-                if mutex.constructed
-                {
-                    let reply = MutexCreationReplyPkt::new(&mutex.name);
-                    reply.send(&mut stream).expect("Could not send MutexCreationReplyPkt");
-                }
-                else
-                {
-                    eprintln!("Expected Mutex to be constructed at this point");
-                }
-            },
+// Fails the lock request that was just queued by client id on the named mutex
+// if it closes a cycle of waiting clients, as none of their locks could be granted
+fn reject_deadlock(mutexes: &mut HashMap<String, HeimdallrDaemonMutex>, name: &str, id: u32,
+    stream: &mut Box<dyn Stream>)
+{
+    let cycle = match find_deadlock(mutexes, id)
+    {
+        Some(cycle) => cycle,
+        None => return,
+    };
+
+    let waits = cycle.iter().map(|(client, mutex)| format!("client {} waits for {}", client, mutex))
+        .collect::<Vec<String>>().join(", ");
+    let msg = format!("Deadlock detected on lock of mutex {} by client {}: {}", name, id, waits);
+    eprintln!("Error: {}", msg);
+
+    mutexes.get_mut(name).unwrap().access_queue.retain(|(client, _)| *client != id);
+    if let Err(e) = ErrorReplyPkt::new(&msg).send(stream)
+    {
+        eprintln!("Error: Could not send deadlock to client {}: {}", id, e);
+    }
+}
+
+
+fn send_atomic_reply(stream: &mut Box<dyn Stream>, client_id: u32, reply: DaemonReplyPkt)
+{
+    if let Err(e) = reply.send(stream)
+    {
+        eprintln!("Error: Could not send AtomicReplyPkt to client {}: {}", client_id, e);
+    }
+}
+
+
+// Barriers, mutex creation and finalization can not complete without a client that
+// disconnected or can not be replied to anymore, so the others are told instead of
+// waiting. The mutexes and barriers of the job must not be locked by the caller.
+fn drop_client(job: &Job, client_id: u32)
+{
+    job.disconnected.lock().unwrap().push(client_id);
+    job.abort(&format!("Job aborted, client {} disconnected", client_id));
+}
+
+
+// Sends the reply to a request of client_id, logs if it could not be sent
+fn send_reply(stream: &mut Box<dyn Stream>, client_id: u32, reply: DaemonReplyPkt)
+    -> std::io::Result<()>
+{
+    reply.send(stream).inspect_err(|e|
+        eprintln!("Error: Could not send reply to client {}: {}", client_id, e))
+}
+
+
+// Error reply for a request on a mutex that was never created
+fn unknown_mutex(name: &str) -> DaemonReplyPkt
+{
+    let reply = ErrorReplyPkt::new(&format!("Mutex {} does not exist", name));
+    report_error(&reply);
+    reply
+}
+
+
+fn handle_client(mut stream: Box<dyn Stream>, client_id: u32, job: Arc<Job>)
+{
+    // println!("thread spawned for job: {}", job.name);
+
+    loop
+    {
+        let pkt = match DaemonPkt::receive(&mut stream)
+        {
+            Ok(pkt) => pkt,
+            // The streams of an aborted job are closed by the daemon itself
+            Err(_) if job.abort_reason().is_some() => return,
+            Err(e) =>
+            {
+                match e.kind()
+                {
+                    std::io::ErrorKind::UnexpectedEof =>
+                        eprintln!("Client {} disconnected without finalizing", client_id),
+                    _ => eprintln!("Error: Could not receive packet from client {}: {}", client_id, e),
+                }
+                drop_client(&job, client_id);
+                return
+            },
+        };
+        // println!("Received DaemonPkt: {:?}", pkt);
+
+        // All ids in the packets are used as indices into the streams of the job,
+        // so a client can only act as itself
+        match pkt.pkt.client_id()
+        {
+            Some(id) if id == client_id => (),
+            claimed =>
+            {
+                let msg = match claimed
+                {
+                    Some(id) => format!("Client {} sent a packet as client {}", client_id, id),
+                    None => format!("Client {} sent a packet that is only valid on a new connection",
+                        client_id),
+                };
+                eprintln!("Error: Rejecting packet: {}", msg);
+                ErrorReplyPkt::new(&msg).send(&mut stream).unwrap_or_else(|e|
+                    eprintln!("Could not report rejected packet to client {}: {}", client_id, e));
+                continue;
+            },
+        }
+
+        match pkt.pkt
+        {
+            DaemonPktType::MutexCreation(mutex_pkt) =>
+            {
+                let mut mutexes = job.mutexes.lock().unwrap();
+                let mutex = mutexes.entry(mutex_pkt.name.clone())
+                    .or_insert(HeimdallrDaemonMutex::new(&mutex_pkt.name, job.size,
+                            mutex_pkt.start_data, job.mutex_policy, job.mutex_lease,
+                            mutex_pkt.permits));
+
+                let registered = mutex.register_client(mutex_pkt.client_id,
+                    stream.try_clone_stream().unwrap(), mutex_pkt.permits);
+                drop(mutexes);
+
+                if !job.thread_barrier.wait()
+                {
+                    reject_aborted(&mut stream, &job.abort_reason().unwrap());
+                    continue;
+                }
+                let mut mutexes = job.mutexes.lock().unwrap();
+                let mutex = mutexes.get_mut(&mutex_pkt.name).unwrap();
+                let reply = match registered
+                {
+                    Ok(()) if mutex.constructed => MutexCreationReplyPkt::new(&mutex.name),
+                    Ok(()) => ErrorReplyPkt::new(&format!(
+                            "Mutex {} was not constructed, missing client ids: {:?}",
+                            mutex.name, missing_ids(&mutex.streams))),
+                    Err(e) => ErrorReplyPkt::new(&e),
+                };
+                report_error(&reply);
+                let sent = send_reply(&mut stream, client_id, reply);
+                drop(mutexes);
+                if sent.is_err()
+                {
+                    drop_client(&job, client_id);
+                    return
+                }
+            },
             DaemonPktType::MutexLockReq(mutex_pkt) =>
             {
                 let mut mutexes = job.mutexes.lock().unwrap();
-                let mutex = mutexes.get_mut(&mutex_pkt.name)
-                    .expect("Mutex for MutexLockReq does not exist");
-                mutex.access_request(mutex_pkt.id);
-            
+                let mutex = match mutexes.get_mut(&mutex_pkt.name)
+                {
+                    Some(mutex) => mutex,
+                    None =>
+                    {
+                        let sent = send_reply(&mut stream, client_id, unknown_mutex(&mutex_pkt.name));
+                        drop(mutexes);
+                        if sent.is_err()
+                        {
+                            drop_client(&job, client_id);
+                            return
+                        }
+                        continue;
+                    },
+                };
+                match job.abort_reason()
+                {
+                    Some(reason) => reject_aborted(&mut stream, &reason),
+                    None =>
+                    {
+                        mutex.access_request(mutex_pkt.id, AccessMode::Exclusive);
+                        reject_deadlock(&mut mutexes, &mutex_pkt.name, mutex_pkt.id, &mut stream);
+                    },
+                }
             },
-            DaemonPktType::MutexWriteAndRelease(mutex_pkt) =>
+            DaemonPktType::MutexReadLockReq(mutex_pkt) =>
             {
-                // TODO check for correct client id?
                 let mut mutexes = job.mutexes.lock().unwrap();
-                let mutex = mutexes.get_mut(&mutex_pkt.mutex_name)
-                    .expect("Mutex for MutexLockReq does not exist");
-                mutex.data = mutex_pkt.data;
-                mutex.release_request();
+                let mutex = match mutexes.get_mut(&mutex_pkt.name)
+                {
+                    Some(mutex) => mutex,
+                    None =>
+                    {
+                        let sent = send_reply(&mut stream, client_id, unknown_mutex(&mutex_pkt.name));
+                        drop(mutexes);
+                        if sent.is_err()
+                        {
+                            drop_client(&job, client_id);
+                            return
+                        }
+                        continue;
+                    },
+                };
+                match job.abort_reason()
+                {
+                    Some(reason) => reject_aborted(&mut stream, &reason),
+                    None =>
+                    {
+                        mutex.access_request(mutex_pkt.id, AccessMode::Shared);
+                        reject_deadlock(&mut mutexes, &mutex_pkt.name, mutex_pkt.id, &mut stream);
+                    },
+                }
             },
-            DaemonPktType::Barrier(barrier_pkt) =>
+            DaemonPktType::MutexReadRelease(mutex_pkt) =>
             {
-                let mut barrier = job.barrier.lock().unwrap();
-                barrier.register_client(barrier_pkt.id, stream.try_clone().unwrap());
-                drop(barrier);
-
-                thread_barrier.wait();
-                let barrier = job.barrier.lock().unwrap();
-                if barrier.finished
+                let mut mutexes = job.mutexes.lock().unwrap();
+                match mutexes.get_mut(&mutex_pkt.name)
+                {
+                    Some(mutex) => mutex.read_release_request(mutex_pkt.id),
+                    None => eprintln!("Error: Discarding read release of unknown mutex {} by client {}",
+                        mutex_pkt.name, mutex_pkt.id),
+                }
+            },
+            DaemonPktType::MutexTryLockReq(mutex_pkt) =>
+            {
+                let mut mutexes = job.mutexes.lock().unwrap();
+                let reply = match mutexes.get_mut(&mutex_pkt.name)
+                {
+                    Some(mutex) => MutexTryLockReplyPkt::new(mutex.try_access(mutex_pkt.id)),
+                    None => unknown_mutex(&mutex_pkt.name),
+                };
+                let sent = send_reply(&mut stream, client_id, reply);
+                drop(mutexes);
+                if sent.is_err()
+                {
+                    drop_client(&job, client_id);
+                    return
+                }
+            },
+            DaemonPktType::MutexCancelReq(mutex_pkt) =>
+            {
+                // Replied while holding the lock on the mutexes, so a lock granted right
+                // before is always sent to the client ahead of this reply
+                let mut mutexes = job.mutexes.lock().unwrap();
+                let reply = match mutexes.get_mut(&mutex_pkt.name)
+                {
+                    Some(mutex) =>
+                    {
+                        mutex.cancel_request(mutex_pkt.id);
+                        MutexCancelReplyPkt::new(&mutex_pkt.name)
+                    },
+                    None => unknown_mutex(&mutex_pkt.name),
+                };
+                let sent = send_reply(&mut stream, client_id, reply);
+                drop(mutexes);
+                if sent.is_err()
                 {
-                    let reply = BarrierReplyPkt::new(job.size);
-                    reply.send(&mut stream).expect("Could not send BarrierReplyPkt");
+                    drop_client(&job, client_id);
+                    return
+                }
+            },
+            DaemonPktType::MutexWriteAndRelease(mutex_pkt) =>
+            {
+                let mut mutexes = job.mutexes.lock().unwrap();
+                let mutex = match mutexes.get_mut(&mutex_pkt.mutex_name)
+                {
+                    Some(mutex) => mutex,
+                    None =>
+                    {
+                        eprintln!("Error: Discarding release of unknown mutex {} by client {}",
+                            mutex_pkt.mutex_name, mutex_pkt.id);
+                        continue;
+                    },
+                };
+                if mutex.permits.is_some()
+                {
+                    mutex.read_release_request(mutex_pkt.id);
+                }
+                else if mutex.current_owner == Some(mutex_pkt.id)
+                {
+                    mutex.data = mutex_pkt.data;
+                    mutex.release_request();
                 }
                 else
                 {
-                    eprintln!("Expected all client to have participated in barrier already")
+                    // The lease of this client expired and the lock was reclaimed
+                    eprintln!("Error: Discarding release of mutex {} by client {} which does not hold the lock",
+                        mutex.name, mutex_pkt.id);
+                }
+            },
+            DaemonPktType::MutexOwnerReq(mutex_pkt) =>
+            {
+                let mutexes = job.mutexes.lock().unwrap();
+                let reply = match mutexes.get(&mutex_pkt.name)
+                {
+                    Some(mutex) => MutexOwnerReplyPkt::new(&mutex.name, mutex.current_owner),
+                    None => unknown_mutex(&mutex_pkt.name),
+                };
+                let sent = send_reply(&mut stream, client_id, reply);
+                drop(mutexes);
+                if sent.is_err()
+                {
+                    drop_client(&job, client_id);
+                    return
                 }
-                drop(barrier);
+            },
+            DaemonPktType::MutexRenewLease(mutex_pkt) =>
+            {
+                let mut mutexes = job.mutexes.lock().unwrap();
+                match mutexes.get_mut(&mutex_pkt.name)
+                {
+                    Some(mutex) => mutex.renew_lease(mutex_pkt.id),
+                    None => eprintln!("Error: Discarding lease renewal of unknown mutex {} by client {}",
+                        mutex_pkt.name, mutex_pkt.id),
+                }
+            },
+            DaemonPktType::FetchAdd(add_pkt) =>
+            {
+                let reply = job.update_atomic(&add_pkt.name, |v| v.wrapping_add(add_pkt.delta));
+                send_atomic_reply(&mut stream, add_pkt.id, reply);
+            },
+            DaemonPktType::CompareAndSwap(cas_pkt) =>
+            {
+                let reply = job.update_atomic(&cas_pkt.name, |v| match v == cas_pkt.expected
+                {
+                    true => cas_pkt.new,
+                    false => v,
+                });
+                send_atomic_reply(&mut stream, cas_pkt.id, reply);
+            },
+            DaemonPktType::Barrier(barrier_pkt) =>
+            {
+                // Only a subset of the clients might take part, so instead of waiting
+                // on the thread barrier the last client to arrive replies to all of them
+                let mut barriers = job.barriers.lock().unwrap();
+                if let Some(reason) = job.abort_reason()
+                {
+                    drop(barriers);
+                    reject_aborted(&mut stream, &reason);
+                    continue;
+                }
+                let barrier = barriers.entry(barrier_pkt.name.clone())
+                    .or_insert_with(|| DaemonBarrier::new(&barrier_pkt.name, job.size,
+                            barrier_pkt.size));
+                let registered = barrier.register_client(barrier_pkt.id, barrier_pkt.size,
+                    stream.try_clone_stream().unwrap(), barrier_pkt.value);
 
-                let b_res = thread_barrier.wait();
-                if b_res.is_leader()
+                match registered
                 {
-                    let mut barrier = job.barrier.lock().unwrap();
-                    barrier.reset();
+                    Ok(()) if barrier.finished =>
+                    {
+                        barrier.complete(job.size);
+                        // The next use of the name starts a fresh barrier
+                        barriers.remove(&barrier_pkt.name);
+                    },
+                    Ok(()) => (),
+                    Err(e) =>
+                    {
+                        if barrier.streams.iter().all(|x| x.is_none())
+                        {
+                            barriers.remove(&barrier_pkt.name);
+                        }
+                        drop(barriers);
+                        let reply = ErrorReplyPkt::new(&e);
+                        report_error(&reply);
+                        reply.send(&mut stream).expect("Could not send ErrorReplyPkt");
+                    },
                 }
-                thread_barrier.wait();
             },
             //TODO Maybe use RwLock instead of mutex
             DaemonPktType::Finalize(finalize_pkt) =>
             {
                 // TODO Cleanup
                 let mut fini = job.finalize.lock().unwrap();
-                fini.register_client(finalize_pkt.id, stream.try_clone().unwrap());
+                let registered = fini.register_client(finalize_pkt.id,
+                    stream.try_clone_stream().unwrap());
                 drop(fini);
-                thread_barrier.wait();
-                let fini = job.finalize.lock().unwrap();
-                if fini.finished
+                if !job.thread_barrier.wait()
                 {
-                    let reply = FinalizeReplyPkt::new(job.size);
-                    reply.send(&mut stream).expect("Could not send FinalizeReplyPkt");
+                    reject_aborted(&mut stream, &job.abort_reason().unwrap());
+                    return
                 }
-                else
+                let fini = job.finalize.lock().unwrap();
+                let reply = match registered
                 {
-                    eprintln!("Expected to have already received all FinalizePkts")
-                }
+                    Ok(()) if fini.finished => FinalizeReplyPkt::new(job.size),
+                    Ok(()) => ErrorReplyPkt::new(&format!(
+                            "Finalization did not complete, missing client ids: {:?}",
+                            missing_ids(&fini.streams))),
+                    Err(e) => ErrorReplyPkt::new(&e),
+                };
+                report_error(&reply);
+                reply.send(&mut stream).expect("Could not send FinalizeReplyPkt");
                 drop(fini);
-                thread_barrier.wait();
-                return ()
+                job.thread_barrier.wait();
+                return
+            },
+            DaemonPktType::Abort(abort_pkt) =>
+            {
+                job.tear_down(abort_pkt.id, &format!("Job aborted by client {} with code {}",
+                        abort_pkt.id, abort_pkt.code));
+                return
             },
             _ => (),
         }
@@ -363,90 +1205,398 @@ fn handle_client(mut stream: TcpStream, job: Arc<Job>, thread_barrier: Arc<Barri
 }
 
 
+// Clients of a job that did not complete its registration yet
+struct PendingJob
+{
+    size: u32,
+    clients: Vec<Box<dyn Stream>>,
+    client_listeners: Vec<SocketAddr>,
+    hostnames: Vec<String>,
+    deadline: Option<Instant>,
+}
+
+
 fn run(daemon: Daemon) -> std::io::Result<()>
 {   
-    let mut job_name = "".to_string();
-    let mut job_size = 0;
-    let mut clients = Vec::<TcpStream>::new();
-    let mut client_listeners = Vec::<SocketAddr>::new();
+    let mut pending = HashMap::<String, PendingJob>::new();
+    // Jobs whose clients all registered by job name, removed once all clients finalized
+    let jobs = Arc::new(Mutex::new(HashMap::<String, Arc<Job>>::new()));
+
+    // Connections of shutdown requests, closed when the daemon exits
+    let mut shutdown_requests: Vec<Box<dyn Stream>> = Vec::new();
 
-    for stream in daemon.client_listener.incoming()
+    let mut listeners: Vec<&dyn Listener> = vec![&daemon.client_listener];
+    if let Some(l) = &daemon.socket_listener
     {
-        match stream
+        listeners.push(l.as_ref());
+    }
+
+    // Accept without blocking so the registration deadlines can be checked
+    // and several listeners can be served.
+    // The deadline of a job starts with its first registration.
+    for l in listeners.iter()
+    {
+        l.set_nonblocking(true)?;
+    }
+    let (first_pkts_sender, first_pkts) = mpsc::channel();
+
+    loop
+    {
+        // First listener that has a connection or an error other than WouldBlock
+        let accepted = listeners.iter().map(|l| l.accept_stream())
+            .find(|r| !matches!(r, Err(e) if e.kind() == std::io::ErrorKind::WouldBlock));
+
+        let received = match accepted
         {
-            Ok(stream) =>
+            Some(Ok(stream)) =>
+            {
+                let first_pkts = first_pkts_sender.clone();
+                thread::spawn(move || receive_first_packet(stream, first_pkts));
+                continue;
+            },
+            Some(Err(e)) =>
             {
-                let pkt = DaemonPkt::receive(&stream);
+                eprintln!("Error in daemon listening to incoming connections: {}", e);
+                continue;
+            },
+            None => first_pkts.try_recv().ok(),
+        };
 
+        match received
+        {
+            Some((mut stream, pkt)) =>
+            {
                 match pkt.pkt
                 {
                     DaemonPktType::ClientRegistration(client_reg) =>
                     {
                         // println!("Received ClientRegistrationPkt: {:?}", client_reg);
-                        
-                        if job_name.is_empty()
+
+                        if !token_matches(daemon.token.as_deref(), client_reg.token.as_deref())
+                        {
+                            reject_registration(stream, &format!(
+                                "Invalid token for job {}", client_reg.job));
+                            continue;
+                        }
+
+                        if !shutdown_requests.is_empty()
+                        {
+                            reject_registration(stream, &format!(
+                                "Daemon {} is shutting down", daemon.name));
+                            continue;
+                        }
+
+                        // A job without clients could never start
+                        if client_reg.size == 0
+                        {
+                            reject_registration(stream, &format!(
+                                "Job {} can not have size 0", client_reg.job));
+                            continue;
+                        }
+
+                        if jobs.lock().unwrap().contains_key(&client_reg.job)
+                        {
+                            reject_registration(stream, &format!(
+                                "Job {} is already running on this daemon", client_reg.job));
+                            continue;
+                        }
+
+                        let job = pending.entry(client_reg.job.clone()).or_insert_with(||
+                            PendingJob{size: client_reg.size, clients: Vec::new(),
+                                client_listeners: Vec::new(), hostnames: Vec::new(),
+                                deadline: daemon.registration_timeout.map(|t| Instant::now() + t)});
+
+                        if job.size != client_reg.size
+                        {
+                            reject_registration(stream, &format!(
+                                "Job {} has size {}, but client registered with size {}",
+                                client_reg.job, job.size, client_reg.size));
+                            continue;
+                        }
+
+                        job.clients.push(stream);
+                        job.client_listeners.push(client_reg.listener_addr);
+                        job.hostnames.push(client_reg.hostname);
+
+                        if job.clients.len() as u32 == job.size
+                        {
+                            let job = pending.remove(&client_reg.job).unwrap();
+                            start_job(&daemon, client_reg.job, job, &jobs)?;
+                        }
+                    },
+                    DaemonPktType::StatusReq(req) =>
+                    {
+                        if !token_matches(daemon.token.as_deref(), req.token.as_deref())
+                        {
+                            eprintln!("Error: Rejecting status request with invalid token");
+                            ErrorReplyPkt::new("Invalid token for status request").send(&mut stream)
+                                .unwrap_or_else(|e| eprintln!("Could not send ErrorReplyPkt: {}", e));
+                            continue;
+                        }
+
+                        let mut running: Vec<(String, Arc<Job>)> = jobs.lock().unwrap().iter()
+                            .map(|(name, job)| (name.clone(), Arc::clone(job)))
+                            .collect();
+                        running.sort_by(|a, b| a.0.cmp(&b.0));
+
+                        let mut pending_jobs: Vec<PendingJobStatus> = pending.iter()
+                            .map(|(name, job)| PendingJobStatus{name: name.clone(), size: job.size,
+                                registered: job.clients.len() as u32})
+                            .collect();
+                        pending_jobs.sort_by(|a, b| a.name.cmp(&b.name));
+
+                        let status = StatusReplyPkt{name: daemon.name.clone(),
+                            jobs: running.iter().map(|(name, job)| job.status(name)).collect(),
+                            pending_jobs, shutting_down: !shutdown_requests.is_empty()};
+                        StatusReplyPkt::new(status).send(&mut stream).unwrap_or_else(|e|
+                            eprintln!("Error: Could not send StatusReplyPkt: {}", e));
+                    },
+                    DaemonPktType::Shutdown(req) =>
+                    {
+                        if !token_matches(daemon.token.as_deref(), req.token.as_deref())
+                        {
+                            eprintln!("Error: Rejecting shutdown request with invalid token");
+                            ErrorReplyPkt::new("Invalid token for shutdown request").send(&mut stream)
+                                .unwrap_or_else(|e| eprintln!("Could not send ErrorReplyPkt: {}", e));
+                            continue;
+                        }
+
+                        println!("Shutdown requested, waiting for {} running jobs",
+                            jobs.lock().unwrap().len());
+                        // Jobs that are still incomplete would never start anymore
+                        for (name, job) in pending.drain()
                         {
-                            job_name = client_reg.job.clone();
-                            job_size = client_reg.size;
+                            abort_registration(&name, job.size, job.clients, &format!(
+                                "Registration for job {} aborted, daemon is shutting down", name));
                         }
-                        
-                        clients.push(stream);
-                        client_listeners.push(client_reg.listener_addr);
-                    }
+                        shutdown_requests.push(stream);
+                    },
                     _ => eprintln!("Unknown Packet type"),
                 }
             },
-            Err(e) =>
+            None =>
             {
-                eprintln!("Error in daemon listening to incoming connections: {}", e);
+                let now = Instant::now();
+                let expired: Vec<String> = pending.iter()
+                    .filter(|(_, job)| job.deadline.is_some_and(|d| now >= d))
+                    .map(|(name, _)| name.clone()).collect();
+                for name in expired
+                {
+                    let job = pending.remove(&name).unwrap();
+                    abort_registration(&name, job.size, job.clients,
+                        &format!("Registration for job {} timed out", name));
+                }
+
+                if !shutdown_requests.is_empty() && jobs.lock().unwrap().is_empty()
+                {
+                    // Removes the config file before the shutdown requests see their
+                    // connection close
+                    drop(listeners);
+                    drop(daemon);
+                    return Ok(());
+                }
+                if termination_requested()
+                {
+                    println!("Received termination signal, stopping {} running jobs",
+                        jobs.lock().unwrap().len());
+                    return Ok(());
+                }
+                thread::sleep(Duration::from_millis(10));
             },
         }
+    }
+}
 
-        if clients.len() as u32 == job_size
-        {
-            break;
-        }
+
+// Time a new connection has to send its first packet before it is closed
+const FIRST_PACKET_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Reads the packet a new connection starts with and passes both to the accept loop.
+// Runs on its own thread, so a connection that sends nothing does not keep the
+// daemon from accepting others.
+fn receive_first_packet(mut stream: Box<dyn Stream>,
+    first_pkts: mpsc::Sender<(Box<dyn Stream>, DaemonPkt)>)
+{
+    let pkt = stream.set_nonblocking(false)
+        .and_then(|_| stream.set_read_timeout(Some(FIRST_PACKET_TIMEOUT)))
+        .and_then(|_| DaemonPkt::receive(&mut stream))
+        .and_then(|pkt| stream.set_read_timeout(None).map(|_| pkt));
+
+    match pkt
+    {
+        // Only fails once the daemon stopped accepting
+        Ok(pkt) => first_pkts.send((stream, pkt)).unwrap_or(()),
+        Err(e) => eprintln!("Error: Could not receive packet from new connection: {}", e),
     }
+}
+
 
-    println!("All clients for job have connected");
+// Assigns the client ids of a job whose clients all registered and
+// starts a handler thread for each of its clients
+fn start_job(daemon: &Daemon, name: String, pending: PendingJob,
+    jobs: &Arc<Mutex<HashMap<String, Arc<Job>>>>) -> std::io::Result<()>
+{
+    println!("All clients for job {} have connected", name);
     let mut job_threads = Vec::<thread::JoinHandle<()>>::new();
-    let job_arc = Arc::new(Job::new(job_size).unwrap());
-    let thread_barrier = Arc::new(Barrier::new(job_size as usize));
-    
-    for id in 0..clients.len()
+    let job_arc = Arc::new(Job::new(pending.size, daemon.mutex_policy, daemon.mutex_lease,
+            pending.client_listeners.clone())?);
+
+    if daemon.mutex_lease.is_some()
+    {
+        let job = Arc::downgrade(&job_arc);
+        thread::spawn(move || watch_mutex_leases(job));
+    }
+
+    jobs.lock().unwrap().insert(name.clone(), Arc::clone(&job_arc));
+
+    for (id, mut stream) in pending.clients.into_iter().enumerate()
     {
-        let mut stream = clients.remove(0);
-        let reply = ClientRegistrationReplyPkt::new(id as u32, &client_listeners);
-        reply.send(&mut stream)?;
+        let reply = ClientRegistrationReplyPkt::new(id as u32, &pending.client_listeners,
+            &pending.hostnames);
+        reply.send(&mut stream).unwrap_or_else(|e|
+            eprintln!("Could not send ClientRegistrationReplyPkt to client {} of job {}: {}",
+                id, name, e));
 
         let job = Arc::clone(&job_arc);
-        let barrier = Arc::clone(&thread_barrier);
+        match stream.try_clone_stream()
+        {
+            Ok(s) => job.streams.lock().unwrap().push(s),
+            Err(e) => eprintln!("Could not clone stream of client {} of job {}: {}", id, name, e),
+        }
 
         let t = thread::spawn(move||
         {
-            handle_client(stream, job, barrier);
+            handle_client(stream, id as u32, job);
         });
 
         job_threads.push(t);
     }
 
-    for t in job_threads
+    // Forget the job once all of its clients are done, so its name can be used again
+    let jobs = Arc::clone(jobs);
+    thread::spawn(move ||
     {
-        t.join().unwrap();
-        println!("All job threads joined");
-        process::exit(0);
-    }
+        for t in job_threads
+        {
+            if t.join().is_err()
+            {
+                eprintln!("Error: Client thread of job {} panicked", name);
+            }
+        }
+        let job = jobs.lock().unwrap().remove(&name);
+        if let Some(job) = job
+        {
+            let disconnected = job.disconnected.lock().unwrap();
+            if !disconnected.is_empty()
+            {
+                eprintln!("Job {} was degraded, clients {:?} disconnected", name, disconnected);
+            }
+        }
+        println!("All job threads of job {} joined", name);
+    });
+
     Ok(())
 }
 
 
-fn parse_args(mut args: std::env::Args) -> Result<(String, String, String), &'static str>
+// Periodically reclaims mutex locks with an expired lease, so a crashed or hanging
+// owner does not block the other clients forever. Stops once the job is dropped.
+fn watch_mutex_leases(job: Weak<Job>)
+{
+    while let Some(job) = job.upgrade()
+    {
+        let mut mutexes = job.mutexes.lock().unwrap();
+        for mutex in mutexes.values_mut()
+        {
+            mutex.reclaim_expired_lease();
+        }
+        drop(mutexes);
+        drop(job);
+
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+
+// Report which clients of a job are missing and send an error to the ones that
+// already connected, so they do not wait for a registration reply forever
+fn reject_registration(mut stream: Box<dyn Stream>, msg: &str)
+{
+    eprintln!("Error: Rejecting client registration: {}", msg);
+    ErrorReplyPkt::new(msg).send(&mut stream).unwrap_or_else(|e|
+        eprintln!("Could not report rejected registration to client: {}", e));
+}
+
+
+fn abort_registration(job_name: &str, job_size: u32, clients: Vec<Box<dyn Stream>>,
+    reason: &str)
+{
+    let missing: Vec<u32> = (clients.len() as u32..job_size).collect();
+    let msg = format!("{}: {} of {} clients connected, missing client ids: {:?}",
+        reason, clients.len(), job_size, missing);
+    eprintln!("Error: {}", msg);
+
+    for mut stream in clients
+    {
+        ErrorReplyPkt::new(&msg).send(&mut stream).unwrap_or_else(|e|
+            eprintln!("Could not report aborted registration of job {} to client: {}",
+                job_name, e));
+    }
+}
+
+
+// Asks the daemon name in partition to shut down, returns once it exited
+fn request_shutdown(partition: &str, name: &str, token: Option<&str>) -> std::io::Result<()>
+{
+    let config = DaemonConfig::load(partition, name)?;
+    let mut stream = connect_daemon(config.client_socket.as_deref(), &config.client_addr)?;
+    ShutdownPkt::new(token).send(&mut stream)?;
+    println!("Requested shutdown of daemon {}, waiting for its running jobs", name);
+
+    // The daemon never replies, the stream is closed once it exits
+    match DaemonReplyPkt::receive(&mut stream)
+    {
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(()),
+        Err(e) => Err(e),
+        Ok(DaemonReplyPkt::Error(e)) => Err(std::io::Error::other(e.msg)),
+        Ok(_) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+            "Unexpected reply to shutdown request")),
+    }
+}
+
+
+// Command line arguments of the daemon
+struct DaemonArgs
+{
+    name: String,
+    partition: String,
+    interface: String,
+    port: u16,
+    mutex_policy: MutexPolicy,
+    mutex_lease: Option<Duration>,
+    registration_timeout: Option<Duration>,
+    unix_socket: Option<PathBuf>,
+    // Stop the running daemon of partition and name instead of starting one
+    shutdown: bool,
+    // Shared secret, defaults to the HEIMDALLR_TOKEN environment variable
+    token: Option<String>,
+}
+
+
+fn parse_args(mut args: std::env::Args) -> Result<DaemonArgs, &'static str>
 {
     args.next();
 
     let mut partition = String::new();
     let mut name = String::new();
-    let mut interface = String::new();
+    let mut interface = env::var(INTERFACE_ENV).unwrap_or_default();
+    let mut port = 4664;
+    let mut mutex_policy = MutexPolicy::Fifo;
+    let mut mutex_lease = None;
+    let mut registration_timeout = Some(Duration::from_secs(60));
+    let mut unix_socket = None;
+    let mut shutdown = false;
+    let mut token = env::var(TOKEN_ENV).ok();
 
     while let Some(arg) = args.next()
     {
@@ -476,28 +1626,102 @@ fn parse_args(mut args: std::env::Args) -> Result<(String, String, String), &'st
                     None => return Err("No valid network interface name given."),
                 }
             },
+            "-P" | "--port" =>
+            {
+                port = match args.next().map(|p| p.parse())
+                {
+                    Some(Ok(p)) => p,
+                    _ => return Err("No valid port given."),
+                }
+            },
+            "--mutex-policy" =>
+            {
+                mutex_policy = match args.next()
+                {
+                    Some(p) => p.parse()?,
+                    None => return Err("No valid mutex policy given."),
+                }
+            },
+            "--mutex-lease" =>
+            {
+                mutex_lease = match args.next().map(|t| t.parse::<u64>())
+                {
+                    Some(Ok(t)) if t > 0 => Some(Duration::from_secs(t)),
+                    _ => return Err("No valid mutex lease in seconds given."),
+                }
+            },
+            "--unix-socket" =>
+            {
+                unix_socket = match args.next()
+                {
+                    Some(p) => Some(PathBuf::from(p)),
+                    None => return Err("No valid unix socket path given."),
+                }
+            },
+            "--registration-timeout" =>
+            {
+                // A timeout of 0 seconds waits for clients indefinitely
+                registration_timeout = match args.next().map(|t| t.parse::<u64>())
+                {
+                    Some(Ok(0)) => None,
+                    Some(Ok(t)) => Some(Duration::from_secs(t)),
+                    _ => return Err("No valid registration timeout in seconds given."),
+                }
+            },
+            "--shutdown" => shutdown = true,
+            "--token" =>
+            {
+                token = match args.next()
+                {
+                    Some(t) if !t.is_empty() => Some(t),
+                    _ => return Err("No valid token given."),
+                }
+            },
             _ => return Err("Unknown argument error."),
         };
     }
-    Ok((name, partition, interface))
+    Ok(DaemonArgs{name, partition, interface, port, mutex_policy, mutex_lease, registration_timeout,
+        unix_socket, shutdown, token})
 }
 
 
 fn main() 
 {
-    let (name, partition, interface) = parse_args(env::args()).unwrap_or_else(|err|
+    heimdallr::networking::ignore_sigpipe();
+
+    let args = parse_args(env::args()).unwrap_or_else(|err|
     {
         eprintln!("Error: Problem parsing arguments: {}", err);
         process::exit(1);
     });
+
+    if args.shutdown
+    {
+        match request_shutdown(&args.partition, &args.name, args.token.as_deref())
+        {
+            Ok(()) => println!("Daemon {} shut down.", args.name),
+            Err(e) =>
+            {
+                eprintln!("Error: Could not shut down daemon {}: {}", args.name, e);
+                process::exit(1);
+            },
+        }
+        return;
+    }
             
-    let daemon = Daemon::new(&name, &partition, &interface).unwrap_or_else(|err|
+    // SIGINT and SIGTERM stop the daemon through run, so its config file is removed
+    heimdallr::networking::catch_termination_signals();
+    let daemon = Daemon::new(&args).unwrap_or_else(|err|
     {
         eprintln!("Error: Could not start daemon correctly: {} \n Shutting down.", err);
         process::exit(1);
     });
 
     println!("Daemon running under name: {} and address: {}", daemon.name, daemon.client_listener_addr);
+    if let Some(path) = &daemon.client_socket
+    {
+        println!("Clients on this node can connect over unix socket: {}", path.display());
+    }
 
     run(daemon).unwrap_or_else(|err|
     {
@@ -3,8 +3,13 @@ use std::ops::{Index,IndexMut};
 use std::process;
 use std::env;
 use std::vec;
+use std::fs::File;
+use std::io::{BufWriter, Write};
 
-use heimdallr::HeimdallrClient;
+use serde::Serialize;
+
+use heimdallr::{HeimdallrClient, ReduceOp};
+use heimdallr::communicator::dims_create;
 // The supported calculation Algorithms Gauss Seidel working on the same matrix
 // Jacobi using in and out matrices
 #[derive(Debug, PartialEq)]
@@ -60,6 +65,30 @@ impl std::str::FromStr for InferenceFunction
 // The supported termination conditions
 // TermPrec: terminate after set precision is reached
 // TermIter: terminate after set amount of iterations
+// How the matrix is distributed over the ranks: blocks of rows,
+// or a 2D grid of blocks with up to four neighbours each
+#[derive(Debug, PartialEq)]
+enum Decomposition
+{
+    Rows,
+    Blocks,
+}
+
+impl std::str::FromStr for Decomposition
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        match s
+        {
+            "1d" => Ok(Decomposition::Rows),
+            "2d" => Ok(Decomposition::Blocks),
+            _ => Err(format!("Unknown decomposition {}, use 1d or 2d", s)),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 enum TerminationCondition
 {
@@ -88,6 +117,7 @@ impl std::str::FromStr for TerminationCondition
 #[derive(Debug)]
 struct CalculationOptions
 {
+    #[allow(dead_code)]
     number: u64,                        // number of threads
     method: CalculationMethod,          // Gauss Seidel or Jacobi method of iteration
     interlines: usize,                  // matrix size = interline*8+9
@@ -95,6 +125,9 @@ struct CalculationOptions
     termination: TerminationCondition,  // termination condition
     term_iteration: u64,                // terminate if iteration number reached
     term_precision: f64,                // terminate if precision reached
+    report_json: Option<String>,        // path for writing a json report of the run
+    output: Option<String>,             // path for writing the solved matrix as pgm image
+    decomposition: Decomposition,       // distribution of the matrix over the ranks
 }
 
 impl CalculationOptions
@@ -103,7 +136,8 @@ impl CalculationOptions
         termination: TerminationCondition, term_iteration: u64, term_precision: f64)
         -> CalculationOptions
     {
-        CalculationOptions{number, method, interlines, inf_func, termination, term_iteration, term_precision}
+        CalculationOptions{number, method, interlines, inf_func, termination, term_iteration, term_precision,
+            report_json: None, output: None, decomposition: Decomposition::Rows}
     }
 }
 
@@ -186,7 +220,7 @@ impl PartdiffMatrix
 {
     fn new(rows: usize, cols: usize) -> PartdiffMatrix
     {
-        let matrix = vec![0.0; ((rows)*(cols)) as usize];
+        let matrix = vec![0.0; rows*cols];
         PartdiffMatrix{rows, cols, matrix}
     }
 }
@@ -199,26 +233,55 @@ impl PartdiffMatrix
 // 
 // Also supports switching between indexing with or without bounds checking
 // This can be set by building the crate with or without: --features "unsafe-indexing"
+// Without it out of bounds indices panic, with it they are undefined behaviour
+impl PartdiffMatrix
+{
+    // Position of idx in the 1D memory. A column beyond cols would silently
+    // address the next row, so debug builds check both coordinates.
+    fn linear_index(&self, idx: [usize; 2]) -> usize
+    {
+        debug_assert!(idx[0] < self.rows && idx[1] < self.cols,
+            "Index {:?} is out of bounds of the {}x{} matrix", idx, self.rows, self.cols);
+        idx[0] * self.cols + idx[1]
+    }
+}
+
 impl Index<[usize; 2]> for PartdiffMatrix
 {
     type Output = f64;
 
+    #[cfg(not(feature = "unsafe-indexing"))]
+    fn index(&self, idx: [usize; 2]) -> &Self::Output
+    {
+        &self.matrix[self.linear_index(idx)]
+    }
+
+    #[cfg(feature = "unsafe-indexing")]
     fn index(&self, idx: [usize; 2]) -> &Self::Output
     {       
         unsafe
         {
-            &self.matrix.get_unchecked(idx[0] * self.cols + idx[1])
+            self.matrix.get_unchecked(self.linear_index(idx))
         }
     }
 }
 
 impl IndexMut<[usize; 2]> for PartdiffMatrix
 {
+    #[cfg(not(feature = "unsafe-indexing"))]
+    fn index_mut(&mut self, idx: [usize; 2]) -> &mut Self::Output
+    {
+        let i = self.linear_index(idx);
+        &mut self.matrix[i]
+    }
+
+    #[cfg(feature = "unsafe-indexing")]
     fn index_mut(&mut self, idx: [usize; 2]) -> &mut Self::Output
     {
+        let i = self.linear_index(idx);
         unsafe
         {
-            self.matrix.get_unchecked_mut(idx[0] * self.cols + idx[1])
+            self.matrix.get_unchecked_mut(i)
         }
     }
 }
@@ -239,37 +302,26 @@ fn usage()
     println!("  -prec/iter:   depending on termination:");
     println!("                  precision: 1e-4 .. 1e-20");
     println!("                  iterations: 1 .. n");
+    println!("  --report-json <path>: optionally write a json report of the run to path");
+    println!("  --output <path>:      optionally write the solved matrix as pgm image to path");
+    println!("  --decomposition <1d|2d>: distribute rows (default) or 2D blocks, 2d needs MethJacobi");
 }
 
 
 // Helper function to parse command line arguments
-fn parse_arg<U>(arg: Option<&String>) -> U
+fn parse_arg<U>(arg: Option<&String>) -> Result<U, String>
 where U: std::str::FromStr,
       <U as std::str::FromStr>::Err: std::fmt::Display
 {
-    let ret: U = match arg
+    match arg
     {
-        Some(a) =>
-        {
-            a.parse().unwrap_or_else(|error|
-                {
-                    eprintln!("Error: {}", error);
-                    usage();
-                    process::exit(1);
-                })
-        },
-        None =>
-        {
-            eprintln!("Error: incomplete arguments.");
-            usage();
-            process::exit(1);
-        },
-    };
-    ret
+        Some(a) => a.parse().map_err(|error| format!("{}", error)),
+        None => Err("incomplete arguments.".to_string()),
+    }
 }
 
-// Parsing of command line arguments
-fn ask_params(cmd_args: &Vec::<String>) -> CalculationOptions
+// Parsing of command line arguments, main decides how to handle invalid ones
+fn ask_params(cmd_args: &[String]) -> Result<CalculationOptions, String>
 {
     // TODO keep authors of original c version?
     // println!("============================================================");
@@ -284,48 +336,62 @@ fn ask_params(cmd_args: &Vec::<String>) -> CalculationOptions
        
     let mut args = cmd_args.iter();
     
-    let number: u64 = parse_arg(args.next());
+    let number: u64 = parse_arg(args.next())?;
     if number < 1
     {
-        eprintln!("Error number argument must be a positive integer");
-        usage();
-        process::exit(1);
+        return Err("number argument must be a positive integer".to_string());
     }
 
-    let method: CalculationMethod = parse_arg(args.next());
+    let method: CalculationMethod = parse_arg(args.next())?;
 
-    let interlines: usize = parse_arg(args.next());
+    let interlines: usize = parse_arg(args.next())?;
 
-    let inf_func: InferenceFunction = parse_arg(args.next());
+    let inf_func: InferenceFunction = parse_arg(args.next())?;
 
-    let termination: TerminationCondition = parse_arg(args.next());
+    let termination: TerminationCondition = parse_arg(args.next())?;
 
     // Check for the meaning of the last argument
-    match termination
+    let mut options = match termination
     {
         TerminationCondition::TermPrec =>
         {
-            let prec: f64 = parse_arg(args.next());
-            if (prec < 1e-20) | (prec > 1e-4)
+            let prec: f64 = parse_arg(args.next())?;
+            if !(1e-20..=1e-4).contains(&prec)
             {
-                eprintln!("Error: termination precision must be between 1e-20 and 1e-4");
-                usage();
-                process::exit(1);
+                return Err("termination precision must be between 1e-20 and 1e-4".to_string());
             }
-            return CalculationOptions::new(number, method, interlines, inf_func, termination, std::u64::MAX, prec);
+            CalculationOptions::new(number, method, interlines, inf_func, termination, u64::MAX, prec)
         },
         TerminationCondition::TermIter =>
         {
-            let iterations = parse_arg(args.next());
+            let iterations = parse_arg(args.next())?;
             if iterations < 1
             {
-                eprintln!("Error: termination iterations must be > 0");
-                usage();
-                process::exit(1);
+                return Err("termination iterations must be > 0".to_string());
             }
-            return CalculationOptions::new(number, method, interlines, inf_func, termination, iterations, 0.0);
+            CalculationOptions::new(number, method, interlines, inf_func, termination, iterations, 0.0)
         },
+    };
+
+    // Optional arguments
+    while let Some(arg) = args.next()
+    {
+        match arg.as_str()
+        {
+            "--report-json" => options.report_json = Some(parse_arg(args.next())?),
+            "--output" => options.output = Some(parse_arg(args.next())?),
+            "--decomposition" => options.decomposition = parse_arg(args.next())?,
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
     }
+
+    // The wavefront of Gauss-Seidel relies on the order of the row blocks
+    if (options.decomposition == Decomposition::Blocks) & (options.method != CalculationMethod::MethJacobi)
+    {
+        return Err("the 2d decomposition is only supported for MethJacobi".to_string());
+    }
+
+    Ok(options)
 }
 
 
@@ -338,33 +404,107 @@ fn init_variables(client: &HeimdallrClient, options: &CalculationOptions) -> (Ca
         CalculationMethod::MethGaussSeidel => 1,
         CalculationMethod::MethJacobi => 2,
     };
-    let h: f64 = 1.0 as f64 / n as f64;
+    let h: f64 = 1.0_f64 / n as f64;
 
 
     // Calculate data distribution
-    let div = (n as u64 -1 ) / client.size as u64;
-    let rest = (n as u64 -1 ) % client.size as u64;
+    let (from, to) = split_range(n as u64 - 1, client.size as u64, client.id as u64);
+    let chunk_size = to - from + 3;
+
+    let arguments = CalculationArguments::new(n, chunk_size as usize, n+1, num_matrices, h);
+    let results = CalculationResults::new(0,0,0.0);
+    let process_data = ProcessData::new(chunk_size,from,to);
+
+    (arguments, results, process_data)
+}
 
-    let (chunk_size, from, to): (u64, u64, u64);
 
-    if client.id < rest as u32
+// Splits the len inner lines 1..=len into parts ranges, the first ones get one line
+// more if it does not divide evenly. Returns the first and last line of part index.
+fn split_range(len: u64, parts: u64, index: u64) -> (u64, u64)
+{
+    let div = len / parts;
+    let rest = len % parts;
+
+    if index < rest
     {
-        chunk_size = div + 3;
-        from = client.id as u64 * (div+1) + 1;
-        to = from + div;
+        let from = index * (div+1) + 1;
+        (from, from + div)
     }
     else
     {
-        chunk_size = div+2;
-        from = client.id as u64 * (div) + rest + 1;
-        to = from + div - 1;
+        let from = index * div + rest + 1;
+        (from, from + div - 1)
     }
+}
 
-    let arguments = CalculationArguments::new(n, chunk_size as usize, n+1, num_matrices, h);
+
+// Inner lines of the block of a rank in the 2D decomposition, and the ranks of the
+// blocks above, below, left and right of it. The local matrices additionally hold
+// one line on every side, the halo from the neighbour or the matrix border.
+#[derive(Debug)]
+struct BlockData
+{
+    row_from: usize,
+    row_to: usize,
+    col_from: usize,
+    col_to: usize,
+    up: Option<u32>,
+    down: Option<u32>,
+    left: Option<u32>,
+    right: Option<u32>,
+}
+
+impl BlockData
+{
+    // Global lines this rank contributes to the result, including the matrix border
+    // at the edges of the grid
+    fn owned_rows(&self, n: usize) -> (usize, usize)
+    {
+        (if self.up.is_none() {0} else {self.row_from}, if self.down.is_none() {n} else {self.row_to})
+    }
+
+    fn owned_cols(&self, n: usize) -> (usize, usize)
+    {
+        (if self.left.is_none() {0} else {self.col_from}, if self.right.is_none() {n} else {self.col_to})
+    }
+
+    // Position of the global element [row, col] in the local matrices
+    fn local(&self, row: usize, col: usize) -> [usize; 2]
+    {
+        [row + 1 - self.row_from, col + 1 - self.col_from]
+    }
+
+    fn contains(&self, row: usize, col: usize) -> bool
+    {
+        (self.row_from-1..=self.row_to+1).contains(&row) & (self.col_from-1..=self.col_to+1).contains(&col)
+    }
+}
+
+// Same as init_variables for the 2D decomposition, the ranks form a grid of
+// blocks that is as square as possible
+fn init_variables_2d(client: &HeimdallrClient, options: &CalculationOptions) -> (CalculationArguments, CalculationResults, BlockData)
+{
+    let n: usize = (options.interlines * 8) + 9 - 1;
+    let h: f64 = 1.0_f64 / n as f64;
+
+    let dims = dims_create(client.size, 2);
+    let grid = client.world().cart(&dims, &[false, false])
+        .expect("Could not create 2D grid of all ranks");
+    let coords = grid.coords(grid.rank());
+
+    let (row_from, row_to) = split_range(n as u64 - 1, dims[0] as u64, coords[0] as u64);
+    let (col_from, col_to) = split_range(n as u64 - 1, dims[1] as u64, coords[1] as u64);
+    let (up, down) = grid.shift(0, 1);
+    let (left, right) = grid.shift(1, 1);
+
+    let block = BlockData{row_from: row_from as usize, row_to: row_to as usize,
+        col_from: col_from as usize, col_to: col_to as usize, up, down, left, right};
+    let arguments = CalculationArguments::new(n, block.row_to - block.row_from + 3,
+        block.col_to - block.col_from + 3, 2, h);
     let results = CalculationResults::new(0,0,0.0);
-    let process_data = ProcessData::new(chunk_size,from,to);
 
-    (arguments, results, process_data)
+    (arguments, results, block)
 }
 
 
@@ -376,7 +516,7 @@ fn init_matrices(arguments: &mut CalculationArguments, options: &CalculationOpti
         let n = arguments.n;
         let h = arguments.h;
 
-        for g in 0 .. arguments.num_matrices as usize
+        for g in 0 .. arguments.num_matrices
         {
             let m = match g
             {
@@ -403,7 +543,7 @@ fn init_matrices_heimdallr(client: &HeimdallrClient, arguments: &mut Calculation
         let n = arguments.n;
         let h = arguments.h;
 
-        for g in 0 .. arguments.num_matrices as usize
+        for g in 0 .. arguments.num_matrices
         {
             let m = match g
             {
@@ -442,10 +582,38 @@ fn init_matrices_heimdallr(client: &HeimdallrClient, arguments: &mut Calculation
 }
 
 
+// Initialize the parts of the matrix borders that lie in the local matrices of block,
+// in the same order as init_matrices so the corners get the same values
+fn init_matrices_2d(arguments: &mut CalculationArguments, options: &CalculationOptions, block: &BlockData)
+{
+    if options.inf_func == InferenceFunction::FuncF0
+    {
+        let n = arguments.n;
+        let h = arguments.h;
+
+        for m in [&mut arguments.m1, &mut arguments.m2].iter_mut()
+        {
+            for i in 0..(n+1)
+            {
+                let border = [(i, 0, 1.0 - (h * i as f64)), (i, n, h * i as f64),
+                    (0, i, 1.0 - (h * i as f64)), (n, i, h * i as f64)];
+                for &(row, col, value) in border.iter()
+                {
+                    if block.contains(row, col)
+                    {
+                        m[block.local(row, col)] = value;
+                    }
+                }
+            }
+        }
+    }
+}
+
+
 // Main calculation
 fn calculate(arguments: &mut CalculationArguments, results: &mut CalculationResults, options: &CalculationOptions)
 {
-    const PI: f64 = 3.141592653589793;
+    const PI: f64 = std::f64::consts::PI;
     const TWO_PI_SQUARE: f64 = 2.0 * PI * PI;
 
     let n = arguments.n;
@@ -514,7 +682,12 @@ fn calculate(arguments: &mut CalculationArguments, results: &mut CalculationResu
                     };
                 }
 
-                m_out[[i,j]] = star;
+                // Gauss-Seidel only has one matrix and updates it in place
+                match options.method
+                {
+                    CalculationMethod::MethGaussSeidel => m_in[[i,j]] = star,
+                    CalculationMethod::MethJacobi => m_out[[i,j]] = star,
+                }
             }
         }
 
@@ -525,7 +698,7 @@ fn calculate(arguments: &mut CalculationArguments, results: &mut CalculationResu
         {
             in_matrix = 0;
         }
-        else
+        else if options.method == CalculationMethod::MethJacobi
         {
             in_matrix = 1;
         }
@@ -548,19 +721,28 @@ fn calculate(arguments: &mut CalculationArguments, results: &mut CalculationResu
 
 
 // Main calculation
+// Ranks that hold the rows before and after the rows of this rank,
+// None at the first and last rank of the 1D decomposition
+fn neighbours(client: &HeimdallrClient) -> (Option<u32>, Option<u32>)
+{
+    client.world().cart(&[client.size], &[false])
+        .expect("Could not create 1D grid of all ranks")
+        .shift(0, 1)
+}
+
 fn calculate_jacobi_heimdallr(client: &mut HeimdallrClient, mut arguments: CalculationArguments,
     results: &mut CalculationResults, options: &CalculationOptions,
     process_data: &ProcessData) 
     -> CalculationArguments
 {
-    const PI: f64 = 3.141592653589793;
+    const PI: f64 = std::f64::consts::PI;
     const TWO_PI_SQUARE: f64 = 2.0 * PI * PI;
 
     let n = arguments.n;
     let h = arguments.h;
 let mut star: f64;
     let mut residuum: f64;
-    let mut maxresiduum: f64;
+    let mut maxresiduum: f64 = 0.0;
 
     let mut pih: f64 = 0.0;
     let mut fpisin: f64 = 0.0;
@@ -581,18 +763,12 @@ let mut star: f64;
         fpisin = 0.25 * TWO_PI_SQUARE * h * h;
     }
 
-    let rank = client.id;
-    let size = client.size;
-    
-    let proc_next = rank as i32 +1;
-
-    let proc_before = rank as i32 -1;
+    let (proc_before, proc_next) = neighbours(client);
 
     let from = process_data.from;
     let chunk_size = process_data.chunk_size;
 
-    let mut global_maxresiduum = client.create_mutex::<f64>("maxresiduum", 0.0)
-        .unwrap();
+    let mut done = false;
 
 
     while term_iteration > 0
@@ -600,36 +776,24 @@ let mut star: f64;
         println!("Iteration: {}", results.stat_iteration);
         maxresiduum = 0.0;
 
-        if options.termination == TerminationCondition::TermPrec
-        {
-            client.barrier().unwrap();
-            if client.id == 0
-            {
-                let mut mr = global_maxresiduum.lock().unwrap();
-                mr.set(0.0);
-            }
-        }
-
-
         let (mut m_in, mut m_out) = match in_matrix
         {
             1 => (arguments.m2, arguments.m1),
             _ => (arguments.m1, arguments.m2),
         };
         
-        if rank < size-1
+        if let Some(next) = proc_next
         {
-            client.send_slice(
+            let halo = client.sendrecv::<_, Vec<f64>>(
                 &m_in.matrix[((m_in.rows-2)*m_in.cols)..((m_in.rows-1)*m_in.cols)],
-                proc_next as u32, 2).unwrap();
-            m_in.matrix.splice(((m_in.rows-1)*m_in.cols)..((m_in.rows)*m_in.cols),
-                client.receive::<Vec<f64>>(proc_next as u32, 1).unwrap());
+                next, 2, next, 1).unwrap();
+            m_in.matrix.splice(((m_in.rows-1)*m_in.cols)..((m_in.rows)*m_in.cols), halo);
         }
-        if rank > 0
+        if let Some(before) = proc_before
         {
-            m_in.matrix.splice(0..(m_in.cols),
-                client.receive::<Vec<f64>>(proc_before as u32, 2).unwrap());
-            client.send_slice(&m_in.matrix[m_in.cols..(2*m_in.cols)], proc_before as u32, 1).unwrap();
+            let halo = client.sendrecv::<_, Vec<f64>>(&m_in.matrix[m_in.cols..(2*m_in.cols)],
+                before, 1, before, 2).unwrap();
+            m_in.matrix.splice(0..(m_in.cols), halo);
         }
 
 
@@ -642,7 +806,7 @@ let mut star: f64;
                 fpisin_i = fpisin * (pih * (i + from as usize - 1)as f64).sin();
             }
 
-            for j in 1..n as usize 
+            for j in 1..n 
             {
                 star = 0.25 * (m_in[[i-1,j]] + m_in[[i+1,j]] + m_in[[i,j-1]] + m_in[[i,j+1]]);
 
@@ -668,19 +832,161 @@ let mut star: f64;
 
         results.stat_iteration += 1;
 
-        if (options.termination == TerminationCondition::TermPrec) | (term_iteration == 1)
+        if options.termination == TerminationCondition::TermPrec
+        {
+            done = client.all_done(maxresiduum < options.term_precision).unwrap();
+        }
+        
+        if in_matrix == 1
+        {
+            arguments.m1 = m_out;
+            arguments.m2 = m_in;
+            in_matrix = 0;
+        }
+        else
+        {
+            arguments.m1 = m_in;
+            arguments.m2 = m_out;
+            in_matrix = 1;
+        }
+
+
+        match options.termination
         {
+            TerminationCondition::TermPrec =>
             {
-                let mut mr = global_maxresiduum.lock().unwrap();
-                match *mr.get()
+                if done
                 {
-                    r if r < maxresiduum => mr.set(maxresiduum),
-                    _ => (),
+                    term_iteration = 0;
                 }
-            }
-            client.barrier().unwrap();
+            },
+            TerminationCondition::TermIter => term_iteration -= 1,
         }
         
+    }
+
+    // maxresiduum is only valid for the last iteration, which is all we need here
+    results.stat_precision = client.allreduce(maxresiduum, ReduceOp::Max).unwrap();
+    results.m = in_matrix;
+    arguments
+}
+
+
+// Main calculation for Jacobi on the 2D decomposition. Before every iteration the
+// outermost inner lines are swapped with the up to four neighbour blocks.
+fn calculate_jacobi_2d_heimdallr(client: &mut HeimdallrClient, mut arguments: CalculationArguments,
+    results: &mut CalculationResults, options: &CalculationOptions, block: &BlockData)
+    -> CalculationArguments
+{
+    const PI: f64 = std::f64::consts::PI;
+    const TWO_PI_SQUARE: f64 = 2.0 * PI * PI;
+
+    let h = arguments.h;
+    let mut star: f64;
+    let mut residuum: f64;
+    let mut maxresiduum: f64 = 0.0;
+
+    let mut pih: f64 = 0.0;
+    let mut fpisin: f64 = 0.0;
+
+    let mut term_iteration = options.term_iteration;
+
+    // for distinguishing between old and new state of the matrix
+    let mut in_matrix: usize = 1;
+
+    if options.inf_func == InferenceFunction::FuncFPiSin
+    {
+        pih = PI * h;
+        fpisin = 0.25 * TWO_PI_SQUARE * h * h;
+    }
+
+    let rows = arguments.m1.rows;
+    let cols = arguments.m1.cols;
+
+    let mut done = false;
+
+    while term_iteration > 0
+    {
+        maxresiduum = 0.0;
+
+        let (mut m_in, mut m_out) = match in_matrix
+        {
+            1 => (arguments.m2, arguments.m1),
+            _ => (arguments.m1, arguments.m2),
+        };
+
+        // Rows are contiguous, columns are copied out and back element by element
+        if let Some(down) = block.down
+        {
+            let halo = client.sendrecv::<_, Vec<f64>>(&m_in.matrix[((rows-2)*cols)..((rows-1)*cols)],
+                down, 2, down, 1).unwrap();
+            m_in.matrix[((rows-1)*cols)..(rows*cols)].copy_from_slice(&halo);
+        }
+        if let Some(up) = block.up
+        {
+            let halo = client.sendrecv::<_, Vec<f64>>(&m_in.matrix[cols..(2*cols)],
+                up, 1, up, 2).unwrap();
+            m_in.matrix[0..cols].copy_from_slice(&halo);
+        }
+        if let Some(right) = block.right
+        {
+            let column: Vec<f64> = (1..rows-1).map(|i| m_in[[i, cols-2]]).collect();
+            let halo = client.sendrecv::<_, Vec<f64>>(&column, right, 4, right, 3).unwrap();
+            for (i, value) in (1..rows-1).zip(halo)
+            {
+                m_in[[i, cols-1]] = value;
+            }
+        }
+        if let Some(left) = block.left
+        {
+            let column: Vec<f64> = (1..rows-1).map(|i| m_in[[i, 1]]).collect();
+            let halo = client.sendrecv::<_, Vec<f64>>(&column, left, 3, left, 4).unwrap();
+            for (i, value) in (1..rows-1).zip(halo)
+            {
+                m_in[[i, 0]] = value;
+            }
+        }
+
+        for i in 1..rows-1
+        {
+            let mut fpisin_i = 0.0;
+
+            if options.inf_func == InferenceFunction::FuncFPiSin
+            {
+                fpisin_i = fpisin * (pih * (i + block.row_from - 1) as f64).sin();
+            }
+
+            for j in 1..cols-1
+            {
+                star = 0.25 * (m_in[[i-1,j]] + m_in[[i+1,j]] + m_in[[i,j-1]] + m_in[[i,j+1]]);
+
+                if options.inf_func == InferenceFunction::FuncFPiSin
+                {
+                    star += fpisin_i * (pih * (j + block.col_from - 1) as f64).sin();
+                }
+
+                if (options.termination == TerminationCondition::TermPrec) | (term_iteration == 1)
+                {
+                    residuum = (m_in[[i,j]] - star).abs();
+
+                    maxresiduum = match residuum
+                    {
+                        r if r < maxresiduum => maxresiduum,
+                        _ => residuum,
+                    };
+                }
+
+                m_out[[i,j]] = star;
+            }
+        }
+
+        results.stat_iteration += 1;
+
+        if options.termination == TerminationCondition::TermPrec
+        {
+            done = client.all_done(maxresiduum < options.term_precision).unwrap();
+        }
+
         if in_matrix == 1
         {
             arguments.m1 = m_out;
@@ -694,31 +1000,149 @@ let mut star: f64;
             in_matrix = 1;
         }
 
-
         match options.termination
         {
             TerminationCondition::TermPrec =>
             {
+                if done
                 {
-                    let mr = global_maxresiduum.lock().unwrap();
-                    if *mr.get() < options.term_precision
-                    {
-                        term_iteration = 0;
-                    }
+                    term_iteration = 0;
                 }
             },
             TerminationCondition::TermIter => term_iteration -= 1,
         }
-        
     }
 
-    let mr = global_maxresiduum.lock().unwrap();
-    results.stat_precision = *mr.get();
+    // maxresiduum is only valid for the last iteration, which is all we need here
+    results.stat_precision = client.allreduce(maxresiduum, ReduceOp::Max).unwrap();
     results.m = in_matrix;
     arguments
 }
 
 
+// Main calculation for Gauss-Seidel as a wavefront over the row blocks.
+// A rank starts its sweep once it got the updated last row of the rank before,
+// rank r can work on iteration k while rank r+1 is still on iteration k-1.
+// The first row goes back up to the rank before for its next iteration, so all
+// values are the same as in the single process calculation.
+// Termination by precision checks the residuum of every iteration with all_done
+// like the Jacobi method, which waits for the last rank, so the iterations of the
+// wavefront only overlap with termination by iterations.
+fn calculate_gauss_seidel_heimdallr(client: &mut HeimdallrClient, arguments: &mut CalculationArguments,
+    results: &mut CalculationResults, options: &CalculationOptions,
+    process_data: &ProcessData)
+{
+    const PI: f64 = std::f64::consts::PI;
+    const TWO_PI_SQUARE: f64 = 2.0 * PI * PI;
+
+    let n = arguments.n;
+    let h = arguments.h;
+    let mut star: f64;
+    let mut residuum: f64;
+    let mut maxresiduum: f64 = 0.0;
+
+    let mut pih: f64 = 0.0;
+    let mut fpisin: f64 = 0.0;
+
+    let mut term_iteration = options.term_iteration;
+
+    if options.inf_func == InferenceFunction::FuncFPiSin
+    {
+        pih = PI * h;
+        fpisin = 0.25 * TWO_PI_SQUARE * h * h;
+    }
+
+    let (proc_before, proc_next) = neighbours(client);
+
+    let from = process_data.from;
+    let chunk_size = process_data.chunk_size as usize;
+
+    let m = &mut arguments.m1;
+    let cols = m.cols;
+
+    while term_iteration > 0
+    {
+        maxresiduum = 0.0;
+
+        // Last row of the rank before from this iteration
+        if let Some(before) = proc_before
+        {
+            client.receive_slice_into(before, 1, &mut m.matrix[0..cols]).unwrap();
+        }
+        // First row of the rank after from the previous iteration,
+        // the first iteration uses the initial values
+        if let (Some(next), true) = (proc_next, results.stat_iteration > 0)
+        {
+            client.receive_slice_into(next, 2, &mut m.matrix[((chunk_size-1)*cols)..(chunk_size*cols)])
+                .unwrap();
+        }
+
+        for i in 1..chunk_size-1
+        {
+            let mut fpisin_i = 0.0;
+
+            if options.inf_func == InferenceFunction::FuncFPiSin
+            {
+                fpisin_i = fpisin * (pih * (i + from as usize - 1)as f64).sin();
+            }
+
+            for j in 1..n
+            {
+                star = 0.25 * (m[[i-1,j]] + m[[i+1,j]] + m[[i,j-1]] + m[[i,j+1]]);
+
+                if options.inf_func == InferenceFunction::FuncFPiSin
+                {
+                    star += fpisin_i * (pih * j as f64).sin();
+                }
+
+                if (options.termination == TerminationCondition::TermPrec) | (term_iteration == 1)
+                {
+                    residuum = (m[[i,j]] - star).abs();
+
+                    maxresiduum = match residuum
+                    {
+                        r if r < maxresiduum => maxresiduum,
+                        _ => residuum,
+                    };
+                }
+
+                m[[i,j]] = star;
+            }
+        }
+
+        // The first row of the last iteration is never received, whether there is
+        // another iteration is only known after the precision check
+        if let Some(before) = proc_before
+        {
+            client.send_slice(&m.matrix[cols..(2*cols)], before, 2).unwrap();
+        }
+        if let Some(next) = proc_next
+        {
+            client.send_slice(&m.matrix[((chunk_size-2)*cols)..((chunk_size-1)*cols)],
+                next, 1).unwrap();
+        }
+
+        results.stat_iteration += 1;
+
+        match options.termination
+        {
+            TerminationCondition::TermPrec =>
+            {
+                if client.all_done(maxresiduum < options.term_precision).unwrap()
+                {
+                    term_iteration = 0;
+                }
+            },
+            TerminationCondition::TermIter => term_iteration -= 1,
+        }
+    }
+
+    // maxresiduum is only valid for the last iteration, which is all we need here
+    results.stat_precision = client.allreduce(maxresiduum, ReduceOp::Max).unwrap();
+    results.m = 0;
+}
+
+
 // Display important information about the calculation
 fn display_statistics(arguments: &CalculationArguments, results: &CalculationResults, options: &CalculationOptions, duration: Duration)
 {
@@ -731,20 +1155,106 @@ fn display_statistics(arguments: &CalculationArguments, results: &CalculationRes
     print!("Stoerfunktion:      ");
     match options.inf_func
     {
-        InferenceFunction::FuncF0 => print!("f(x,y) = 0\n"),
-        InferenceFunction::FuncFPiSin => print!("f(x,y) = 2pi^2*sin(pi*x)sin(pi*y)\n"),
+        InferenceFunction::FuncF0 => println!("f(x,y) = 0"),
+        InferenceFunction::FuncFPiSin => println!("f(x,y) = 2pi^2*sin(pi*x)sin(pi*y)"),
     }
     print!("Terminierung:       ");
     match options.termination
     {
-        TerminationCondition::TermPrec => print!("Hinreichende Genauigkeit\n"),
-        TerminationCondition::TermIter => print!("Anzahl der Iterationen\n"),
+        TerminationCondition::TermPrec => println!("Hinreichende Genauigkeit"),
+        TerminationCondition::TermIter => println!("Anzahl der Iterationen"),
     }
     println!("Anzahl Iterationen: {}", results.stat_iteration);
     println!("Norm des Fehlers:   {:.6e}", results.stat_precision);
 }
 
 
+// Machine readable summary of a run for --report-json
+#[derive(Serialize, Debug)]
+struct Report
+{
+    ranks: u32,
+    interlines: usize,
+    method: String,
+    inf_func: String,
+    termination: String,
+    iterations: u64,
+    precision: f64,
+    rank_times: Vec<f64>,   // calculation time of each rank in seconds
+    total_time: f64,        // time of the slowest rank
+}
+
+fn write_report(path: &str, results: &CalculationResults, options: &CalculationOptions,
+    rank_times: Vec<f64>)
+{
+    let total_time = rank_times.iter().cloned().fold(0.0, f64::max);
+    let report = Report{ranks: rank_times.len() as u32, interlines: options.interlines,
+        method: format!("{:?}", options.method), inf_func: format!("{:?}", options.inf_func),
+        termination: format!("{:?}", options.termination), iterations: results.stat_iteration,
+        precision: results.stat_precision, rank_times, total_time};
+
+    let file = File::create(path).unwrap_or_else(|e|
+    {
+        eprintln!("Error: Could not create report file {}: {}", path, e);
+        process::exit(1);
+    });
+    serde_json::to_writer_pretty(file, &report).expect("Could not write json report");
+}
+
+
+// Writes the matrix as binary grayscale pgm image, scaled from its min (black)
+// to its max (white) value
+fn write_pgm(path: &str, matrix: &[f64], rows: usize, cols: usize)
+{
+    let min = matrix.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = matrix.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let range = match max - min
+    {
+        r if r > 0.0 => r,
+        _ => 1.0,
+    };
+
+    let file = File::create(path).unwrap_or_else(|e|
+    {
+        eprintln!("Error: Could not create output file {}: {}", path, e);
+        process::exit(1);
+    });
+    let mut writer = BufWriter::new(file);
+
+    let pixels: Vec<u8> = matrix.iter().map(|v| ((v - min) / range * 255.0).round() as u8).collect();
+    write!(writer, "P5\n{} {}\n255\n", cols, rows)
+        .and_then(|_| writer.write_all(&pixels))
+        .and_then(|_| writer.flush())
+        .expect("Could not write pgm image");
+}
+
+// Collects the full matrix on rank 0, every rank contributes the rows it calculated.
+// Rank 0 and the last rank additionally own the top and bottom border rows.
+// Has to be called by all ranks, returns None on all ranks but 0.
+fn gather_matrix_heimdallr(client: &HeimdallrClient, arguments: &CalculationArguments,
+    results: &CalculationResults, process_data: &ProcessData) -> Option<Vec<f64>>
+{
+    let matrix = match results.m
+    {
+        1 => &arguments.m2,
+        _ => &arguments.m1,
+    };
+
+    let first = match client.id
+    {
+        0 => 0,
+        _ => 1,
+    };
+    let last = match client.id
+    {
+        x if x == client.size-1 => process_data.chunk_size as usize,
+        _ => process_data.chunk_size as usize - 1,
+    };
+
+    client.gatherv(&matrix.matrix[(first*matrix.cols)..(last*matrix.cols)], 0).unwrap()
+}
+
+
 // Beschreibung der Funktion displayMatrix:                              
 //                                                                       
 // Die Funktion displayMatrix gibt eine Matrix                           
@@ -763,13 +1273,13 @@ fn display_matrix(arguments: &mut CalculationArguments, results: &CalculationRes
     let interlines = options.interlines;
 
     println!("Matrix:");
-    for y in 0..9 as usize
+    for y in 0..9_usize
     {
-        for x in 0..9 as usize
+        for x in 0..9_usize
         {
             print!(" {:.4}", matrix[[y * (interlines+1),x * (interlines+1)]]);
         }
-        print!("\n");
+        println!();
     }
 }
 
@@ -818,11 +1328,11 @@ fn display_matrix_heimdallr(client: &HeimdallrClient, arguments: &mut Calculatio
                 if (line >= from as usize) & (line <= to as usize)
                 {
                     let mut send = Vec::<f64>::new();
-                    for x in 0..9 as usize
+                    for x in 0..9_usize
                     {
                         send.push(matrix[[line - from as usize +1, x * (options.interlines+1)]]);
                     }
-                    print!("\n");
+                    println!();
                     client.send(&send, 0,42+y as u32).unwrap();
                 }
             },
@@ -837,31 +1347,127 @@ fn display_matrix_heimdallr(client: &HeimdallrClient, arguments: &mut Calculatio
                     let col = x * (options.interlines+1);
                     print!(" {:.4}", matrix[[line, col]]);
                 }   
-                print!("\n");
+                println!();
             }
             else
             {
-                for x in 0..9
+                for value in recv.iter().take(9)
                 {
-                    print!(" {:.4}", recv[x]);
+                    print!(" {:.4}", value);
                 }
-                print!("\n");
+                println!();
             }
         }
     }
 }
 
 
+// Collects the full matrix on rank 0 for the 2D decomposition, every rank contributes
+// its inner lines and the parts of the matrix border next to them.
+// Has to be called by all ranks, returns None on all ranks but 0.
+fn gather_matrix_2d_heimdallr(client: &HeimdallrClient, arguments: &CalculationArguments,
+    results: &CalculationResults, block: &BlockData) -> Option<Vec<f64>>
+{
+    let matrix = match results.m
+    {
+        1 => &arguments.m2,
+        _ => &arguments.m1,
+    };
+    let n = arguments.n;
+
+    let (row_lo, row_hi) = block.owned_rows(n);
+    let (col_lo, col_hi) = block.owned_cols(n);
+    let mut values = Vec::with_capacity((row_hi - row_lo + 1) * (col_hi - col_lo + 1));
+    for row in row_lo..=row_hi
+    {
+        for col in col_lo..=col_hi
+        {
+            values.push(matrix[block.local(row, col)]);
+        }
+    }
+
+    let blocks = client.gather(&(row_lo, col_lo, col_hi - col_lo + 1, values), 0).unwrap()?;
+    let mut full = vec![0.0; (n+1) * (n+1)];
+    for (row_lo, col_lo, width, values) in blocks
+    {
+        for (k, row) in values.chunks(width).enumerate()
+        {
+            let start = (row_lo + k) * (n+1) + col_lo;
+            full[start..(start + width)].copy_from_slice(row);
+        }
+    }
+    Some(full)
+}
+
+// Same output as display_matrix, the shown elements are collected from the ranks
+// whose blocks hold them
+fn display_matrix_2d_heimdallr(client: &HeimdallrClient, arguments: &CalculationArguments,
+    results: &CalculationResults, options: &CalculationOptions, block: &BlockData)
+{
+    let matrix = match results.m
+    {
+        1 => &arguments.m2,
+        _ => &arguments.m1,
+    };
+    let n = arguments.n;
+    let (row_lo, row_hi) = block.owned_rows(n);
+    let (col_lo, col_hi) = block.owned_cols(n);
+
+    let mut shown = Vec::new();
+    for y in 0..9_usize
+    {
+        for x in 0..9_usize
+        {
+            let (row, col) = (y * (options.interlines+1), x * (options.interlines+1));
+            if (row_lo..=row_hi).contains(&row) & (col_lo..=col_hi).contains(&col)
+            {
+                shown.push((y, x, matrix[block.local(row, col)]));
+            }
+        }
+    }
+
+    if let Some(all) = client.gather(&shown, 0).unwrap()
+    {
+        let mut values = [[0.0; 9]; 9];
+        for (y, x, value) in all.into_iter().flatten()
+        {
+            values[y][x] = value;
+        }
+
+        println!("Matrix:");
+        for row in values.iter()
+        {
+            for value in row.iter()
+            {
+                print!(" {:.4}", value);
+            }
+            println!();
+        }
+    }
+}
+
 
 fn main()
 {
-    let mut client = HeimdallrClient::init(env::args()).unwrap();
+    let mut client = match HeimdallrClient::init(env::args())
+    {
+        Ok(c) => c,
+        Err(e) =>
+        {
+            eprintln!("Error in initializing heimdallr: {}", e);
+            process::exit(1);
+        },
+    };
 
-    let options = ask_params(&client.cmd_args);
+    let options = ask_params(&client.cmd_args).unwrap_or_else(|e|
+    {
+        eprintln!("Error: {}", e);
+        usage();
+        process::exit(1);
+    });
     let (mut arguments, mut results, process_data) = init_variables(&client, &options);
 
-    if (client.size == 1) | (client.size >= arguments.n as u32 -1) | 
-        (options.method == CalculationMethod::MethGaussSeidel)
+    if (client.size == 1) | (client.size >= arguments.n as u32 -1)
     {
         println!("Executing with only 1 process.");
         if client.id == 0
@@ -872,6 +1478,55 @@ fn main()
             let duration = now.elapsed();
             display_statistics(&arguments, &results, &options, duration);
             display_matrix(&mut arguments, &results, &options);
+
+            if let Some(path) = &options.output
+            {
+                let matrix = match results.m
+                {
+                    1 => &arguments.m2,
+                    _ => &arguments.m1,
+                };
+                write_pgm(path, &matrix.matrix, matrix.rows, matrix.cols);
+            }
+
+            if let Some(path) = &options.report_json
+            {
+                write_report(path, &results, &options, vec![duration.as_secs_f64()]);
+            }
+        }
+    }
+    else if options.decomposition == Decomposition::Blocks
+    {
+        let (mut arguments, mut results, block) = init_variables_2d(&client, &options);
+        println!("Executing with {} clients on 2D blocks", client.size);
+        init_matrices_2d(&mut arguments, &options, &block);
+        let now = Instant::now();
+        arguments = calculate_jacobi_2d_heimdallr(&mut client, arguments, &mut results, &options,
+            &block);
+        let duration = now.elapsed();
+
+        if client.id == 0
+        {
+            display_statistics(&arguments, &results, &options, duration);
+        }
+
+        display_matrix_2d_heimdallr(&client, &arguments, &results, &options, &block);
+
+        if let Some(path) = &options.output
+        {
+            if let Some(matrix) = gather_matrix_2d_heimdallr(&client, &arguments, &results, &block)
+            {
+                let n = arguments.n;
+                write_pgm(path, &matrix, n+1, n+1);
+            }
+        }
+
+        if let Some(path) = &options.report_json
+        {
+            if let Some(rank_times) = client.gather(&duration.as_secs_f64(), 0).unwrap()
+            {
+                write_report(path, &results, &options, rank_times);
+            }
         }
     }
     else
@@ -879,8 +1534,13 @@ fn main()
         println!("Executing with {} clients", client.size);
         init_matrices_heimdallr(&client, &mut arguments, &options, &process_data);
         let now = Instant::now();
-        arguments = calculate_jacobi_heimdallr(&mut client, arguments, &mut results, &options,
-            &process_data);
+        match options.method
+        {
+            CalculationMethod::MethGaussSeidel => calculate_gauss_seidel_heimdallr(&mut client,
+                &mut arguments, &mut results, &options, &process_data),
+            CalculationMethod::MethJacobi => arguments = calculate_jacobi_heimdallr(&mut client,
+                arguments, &mut results, &options, &process_data),
+        }
         let duration = now.elapsed();
 
         if client.id == 0
@@ -889,6 +1549,23 @@ fn main()
         }
 
         display_matrix_heimdallr(&client, &mut arguments, &results, &options, &process_data);
+
+        if let Some(path) = &options.output
+        {
+            if let Some(matrix) = gather_matrix_heimdallr(&client, &arguments, &results, &process_data)
+            {
+                let n = arguments.n;
+                write_pgm(path, &matrix, n+1, n+1);
+            }
+        }
+
+        if let Some(path) = &options.report_json
+        {
+            if let Some(rank_times) = client.gather(&duration.as_secs_f64(), 0).unwrap()
+            {
+                write_report(path, &results, &options, rank_times);
+            }
+        }
     }
 
 